@@ -1,38 +1,255 @@
-use crate::app_config::{AppConfig, LineNumbers};
-use crate::file::*;
+use crate::app_config::{AppConfig, Autosave, Backup, WordWrap, WrappedRows};
+use crate::cli::LaunchOptions;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::collab::CollabSession;
+use crate::document::Document;
+use crate::encoding::{Encoding, LineEnding};
+use crate::file::{self, FileStatus};
 use crate::find::FindTools;
+use crate::fonts;
+use crate::highlight::{self, Language};
+use crate::history;
+use crate::keymap::{Command, KeyCombo, Keymap};
+use crate::palette::Palette;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::paste::{Expiry, PendingRequest, Reply};
+use crate::preview::{self, FileCheck, Preview, PreviewKind};
+use crate::session::Session;
+use crate::share::{self, Snippet};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::swap;
+use crate::text_buf::{EditorView, TextBuf};
+use crate::text_pos::TextPos;
+use crate::theme::{Theme, ThemeChoice};
 use eframe::egui;
 use eframe::egui::Color32;
 use eframe::epi;
+use std::path::{Path, PathBuf};
+
+/// The editor lays out whole blocks of this many lines, the ones in view and
+/// one either side, so scrolling a little doesn't change what is laid out and
+/// highlighted, and a comment or string starting a little way up is colored right.
+const EDITOR_BLOCK_LINES: usize = 128;
+
+/// Space egui's `TextEdit` leaves on either side of its text.
+const TEXT_EDIT_MARGIN: f32 = 4.0;
+
+/// Lines left showing above the target of Go to Line.
+const GO_TO_LINE_CONTEXT_ROWS: usize = 5;
+
+/// Seconds between checks for autosaves and files changed on disk.
+#[cfg(not(target_arch = "wasm32"))]
+const TICK_SECS: u64 = 2;
 
 #[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
 #[cfg_attr(feature = "persistence", serde(default))]
 pub struct CodeShare {
+    /// Loaded from `AppConfig::path()`, see `app_config`.
+    #[cfg_attr(all(feature = "persistence", not(target_arch = "wasm32")), serde(skip))]
     config: AppConfig,
+    /// Colors loaded from `config.theme`.
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    theme: Theme,
+    /// Keys bound to commands, from `Keymap::path()`.
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    keymap: Keymap,
 
+    //  Tabs are saved as `session` instead
     #[cfg_attr(feature = "persistence", serde(skip))]
-    file_status: FileStatus,
-    text_buf: String,
-    line_nums: Option<LineNumbers>,
-    finder: FindTools,
+    docs: Vec<Document>,
+    active_doc: usize,
+    closing_doc: Option<usize>,
     active_popup: Popup,
     err_msg: Option<String>,
     status_msg: Option<String>,
     switch_to_editor: bool,
+    #[cfg(not(target_arch = "wasm32"))]
+    paste_expiry: Expiry,
+    session: Session,
+    /// Command palette, keeping the commands last run from it.
+    palette: Palette,
+    /// The word wrap last used other than `Off`, which Toggle Word Wrap turns back on.
+    word_wrap_on: WordWrap,
+
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    share_base_url: String,
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    share_fragment: Option<String>,
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    share_link: String,
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    paste_id_buf: String,
+    /// Publish or fetch waiting for the paste server to answer.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    paste_request: Option<PendingRequest>,
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    session_name_buf: String,
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    go_to_line_buf: String,
+    /// Command waiting for a key press to bind in the Keyboard Shortcuts popup.
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    rebinding: Option<Command>,
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    launch_options: Option<LaunchOptions>,
+    /// Launches forwarded from other processes, see `instance`.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    launch_rx: Option<std::sync::mpsc::Receiver<LaunchOptions>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    instance: Option<crate::instance::Listener>,
+    /// Editors of tabs whose file changed on disk, waiting for the user to pick a version.
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    changed_on_disk: Vec<egui::Id>,
+    /// Swap files left by an editor that didn't close properly.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    swap_orphans: Vec<swap::Orphan>,
+    /// Open files whose swap file has been looked for.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    swap_checked: std::collections::HashSet<PathBuf>,
+    /// Binary and very large files waiting for the user to choose how to open them.
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    pending_opens: Vec<(PathBuf, FileCheck)>,
+    /// Diff shown in the changed on disk and swap recovery popups.
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    disk_diff: Option<String>,
+    /// Whether the pointer was over the window last frame, for `Autosave::FocusLost`.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    had_pointer: bool,
+    /// Input time files were last checked for changes on disk.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    last_watch: f64,
+    /// Settings as last read from or written to the settings file.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    saved_settings: Option<AppConfig>,
+    /// Modification time of the settings file when it was last read or written.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    settings_modified: Option<std::time::SystemTime>,
 }
 
 impl Default for CodeShare {
     fn default() -> Self {
         Self {
             config: AppConfig::default(),
-            file_status: FileStatus::default(),
-            text_buf: String::new(),
-            line_nums: None,
-            finder: FindTools::default(),
+            theme: Theme::default(),
+            keymap: Keymap::default(),
+            docs: vec![Document::default()],
+            active_doc: 0,
+            closing_doc: None,
             active_popup: Popup::None,
             err_msg: None,
             status_msg: Some("code_share loaded".to_string()),
             switch_to_editor: false,
+            #[cfg(not(target_arch = "wasm32"))]
+            paste_expiry: Expiry::default(),
+            session: Session::default(),
+            palette: Palette::default(),
+            word_wrap_on: WordWrap::Window,
+            share_base_url: share::DEFAULT_BASE_URL.to_string(),
+            share_fragment: None,
+            share_link: String::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            paste_id_buf: String::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            paste_request: None,
+            session_name_buf: String::new(),
+            go_to_line_buf: String::new(),
+            rebinding: None,
+            launch_options: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            launch_rx: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            instance: None,
+            changed_on_disk: Vec::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            swap_orphans: Vec::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            swap_checked: std::collections::HashSet::new(),
+            pending_opens: Vec::new(),
+            disk_diff: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            had_pointer: false,
+            #[cfg(not(target_arch = "wasm32"))]
+            last_watch: 0.0,
+            #[cfg(not(target_arch = "wasm32"))]
+            saved_settings: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            settings_modified: None,
+        }
+    }
+}
+
+impl CodeShare {
+    /// Open the snippet in `href`'s fragment once the app starts, and share
+    /// new snippets relative to the same page.
+    pub fn open_share_link(&mut self, href: &str) {
+        let (base_url, fragment) = match href.find('#') {
+            Some(index) => href.split_at(index),
+            None => (href, ""),
+        };
+        self.share_base_url = base_url.to_string();
+        if Snippet::is_share_fragment(fragment) {
+            self.share_fragment = Some(fragment.to_string());
+        }
+    }
+
+    /// Open the files and apply the flags from the command line once the app starts.
+    pub fn open_launch_options(&mut self, options: LaunchOptions) {
+        self.launch_options = Some(options);
+    }
+
+    fn apply_launch_options(&mut self, ctx: &egui::CtxRef, options: LaunchOptions) {
+        if let Some(size) = options.font_size {
+            self.config.set_font_size(size);
+            CodeShare::apply_fonts(
+                ctx,
+                &mut self.config,
+                &mut self.err_msg,
+                &mut self.active_popup,
+            );
+        }
+        for file in options.files {
+            //  Binary and very large files are asked about first
+            if let Ok(check @ (FileCheck::Binary | FileCheck::Large { .. })) =
+                FileCheck::of(&file.path)
+            {
+                self.pending_opens.push((file.path, check));
+                continue;
+            }
+            let mut doc = Document::default();
+            match doc.file_status.open_path(&file.path) {
+                Ok(contents) => doc.load_text(contents),
+                Err(e) => {
+                    self.err_msg = Some(format!("{}: {}", file.path.display(), e));
+                    self.active_popup = Popup::Error;
+                    continue;
+                }
+            }
+            doc.read_only = options.read_only;
+            if let Some(line) = file.line {
+                let column = file.column.unwrap_or(1);
+                let pos = doc
+                    .text_buf
+                    .pos_at_line_col(line.saturating_sub(1), column.saturating_sub(1));
+                CodeShare::move_cursor(ctx, doc.editor_id(), pos, None, &mut self.switch_to_editor);
+            }
+            CodeShare::add_doc(&mut self.docs, &mut self.active_doc, doc);
+        }
+        if let Some(text) = options.stdin_text {
+            let mut doc = Document::default();
+            doc.load_text(text);
+            doc.shared_name = Some("stdin".to_string());
+            doc.file_status.set_unsaved(true);
+            doc.read_only = options.read_only;
+            CodeShare::add_doc(&mut self.docs, &mut self.active_doc, doc);
         }
     }
 }
@@ -49,222 +266,1407 @@ impl epi::App for CodeShare {
         _frame: &mut epi::Frame<'_>,
         _storage: Option<&dyn epi::Storage>,
     ) {
+        //  Keep the share link and arguments the app was started with
+        let share_base_url = std::mem::take(&mut self.share_base_url);
+        let share_fragment = self.share_fragment.take();
+        let launch_options = self.launch_options.take();
         // Load previous app state (if any).
         // Note that you must enable the `persistence` feature for this to work.
         #[cfg(feature = "persistence")]
         if let Some(storage) = _storage {
             *self = epi::get_value(storage, epi::APP_KEY).unwrap_or_default()
         }
-        //  Set font to saved size
-        CodeShare::change_app_font_size(ctx, self.config.get_font_size());
+        self.share_base_url = share_base_url;
         //  Startup Message
         self.status_msg = Some("code_share loaded".to_string());
         //  Reset Things that are saved even though they're allegedly excluded
-        self.text_buf.clear();
+        self.closing_doc = None;
         self.err_msg = None;
         self.active_popup = Popup::None;
-        self.file_status.set_unsaved(false);
-        self.finder.full_reset();
+        #[cfg(not(target_arch = "wasm32"))]
+        self.load_settings();
+
+        //  Bring back the tabs from the last run
+        let restored = std::mem::take(&mut self.session).restore();
+        self.docs = restored.docs;
+        self.active_doc = restored.active_doc;
+        for doc in self.docs.iter() {
+            let pos = doc.text_buf.pos_at_char(doc.cursor);
+            CodeShare::move_cursor(ctx, doc.editor_id(), pos, None, &mut self.switch_to_editor);
+        }
+        for index in restored.changed_on_disk {
+            self.changed_on_disk.push(self.docs[index].editor_id());
+        }
+        if !restored.errors.is_empty() {
+            self.err_msg = Some(format!("Could not reopen {}", restored.errors.join("\n")));
+            self.active_popup = Popup::Error;
+        }
+
+        //  Open the shared snippet read-only
+        if let Some(fragment) = share_fragment {
+            match Snippet::decode(&fragment) {
+                Ok(snippet) => {
+                    let doc = Document::from_snippet(snippet);
+                    CodeShare::add_doc(&mut self.docs, &mut self.active_doc, doc);
+                    self.status_msg = Some("Shared snippet opened".to_string());
+                }
+                Err(e) => {
+                    self.err_msg = Some(e.to_string());
+                    self.active_popup = Popup::Error;
+                }
+            }
+        }
 
-        //  Setup Line numbers
-        match self.config.line_nums {
-            true => self.line_nums = Some(LineNumbers::default()),
-            false => self.line_nums = None,
+        if let Some(options) = launch_options {
+            self.apply_launch_options(ctx, options);
+        }
+        //  Take files from later launches, unless told to open a new window
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let (launch_tx, launch_rx) = std::sync::mpsc::channel();
+            let repaint_signal = _frame.repaint_signal();
+            let listening = crate::instance::listen(move |options| {
+                let _ = launch_tx.send(options);
+                repaint_signal.request_repaint();
+            });
+            if let Ok(listener) = listening {
+                self.launch_rx = Some(launch_rx);
+                self.instance = Some(listener);
+            }
+        }
+        //  Offer back what an editor that crashed left in swap files, and wake
+        //  up now and then so autosaves and changes on disk are seen while idle
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.swap_orphans = swap::untitled_orphans();
+            let repaint_signal = _frame.repaint_signal();
+            std::thread::spawn(move || loop {
+                std::thread::sleep(std::time::Duration::from_secs(TICK_SECS));
+                repaint_signal.request_repaint();
+            });
         }
 
         //  Disable text wrapping
         let mut style = (*ctx.style()).clone();
         style.wrap = Some(false);
         ctx.set_style(style);
+
+        match self.config.theme.load() {
+            Ok(theme) => self.theme = theme,
+            Err(e) => {
+                self.config.theme = ThemeChoice::default();
+                self.err_msg = Some(format!("Could not load theme {}", e));
+                self.active_popup = Popup::Error;
+            }
+        }
+        self.theme.apply(ctx);
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(path) = Keymap::path().filter(|path| path.exists()) {
+            match Keymap::from_file(&path) {
+                Ok(keymap) => self.keymap = keymap,
+                Err(e) => {
+                    self.err_msg = Some(format!("Could not load keymap {}", e));
+                    self.active_popup = Popup::Error;
+                }
+            }
+        }
+        if !self.keymap.conflicts().is_empty() {
+            self.status_msg =
+                Some("Some shortcuts conflict, see Tools > Keyboard Shortcuts".to_string());
+        }
+        //  Set the saved fonts and size
+        CodeShare::apply_fonts(
+            ctx,
+            &mut self.config,
+            &mut self.err_msg,
+            &mut self.active_popup,
+        );
     }
 
     /// Called by the frame work to save state before shutdown.
     /// Note that you must enable the `persistence` feature for this to work.
     #[cfg(feature = "persistence")]
     fn save(&mut self, storage: &mut dyn epi::Storage) {
+        self.session = Session::capture(&self.docs, self.active_doc);
         epi::set_value(storage, epi::APP_KEY, self);
     }
 
+    /// Called once on shutdown. Swap files are only for recovering from a crash,
+    /// unsaved buffers live on in the session.
+    fn on_exit(&mut self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            for doc in self.docs.iter_mut() {
+                doc.swap.remove();
+            }
+            if let Some(listener) = &self.instance {
+                listener.close();
+            }
+        }
+    }
+
     /// Called each time the UI needs repainting, which may be many times per second.
     /// Put your widgets into a `SidePanel`, `TopPanel`, `CentralPanel`, `Window` or `Area`.
     fn update(&mut self, ctx: &egui::CtxRef, frame: &mut epi::Frame<'_>) {
+        //  Files opened from the command line while the app was running
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let forwarded: Vec<LaunchOptions> = match &self.launch_rx {
+                Some(launch_rx) => launch_rx.try_iter().collect(),
+                None => Vec::new(),
+            };
+            for options in forwarded {
+                self.apply_launch_options(ctx, options);
+            }
+            self.save_settings();
+            self.find_swap_orphans();
+            self.autosave(ctx);
+            self.watch_files(ctx);
+        }
+        if self.config.word_wrap != WordWrap::Off {
+            self.word_wrap_on = self.config.word_wrap;
+        }
+
         let Self {
             config,
-            file_status,
-            text_buf,
-            line_nums,
-            finder,
+            theme,
+            keymap,
+            docs,
+            active_doc,
+            closing_doc,
             active_popup,
             err_msg,
             status_msg,
             switch_to_editor,
+            #[cfg(not(target_arch = "wasm32"))]
+            paste_expiry,
+            share_base_url,
+            share_link,
+            #[cfg(not(target_arch = "wasm32"))]
+            paste_id_buf,
+            #[cfg(not(target_arch = "wasm32"))]
+            paste_request,
+            session_name_buf,
+            go_to_line_buf,
+            rebinding,
+            palette,
+            word_wrap_on,
+            changed_on_disk,
+            pending_opens,
+            #[cfg(not(target_arch = "wasm32"))]
+            swap_orphans,
+            disk_diff,
+            ..
         } = self;
 
+        //  Finish closing a tab once its unsaved prompt has been dealt with
+        if let Some(index) = *closing_doc {
+            if *active_popup == Popup::None {
+                if index < docs.len() && !docs[index].file_status.is_unsaved() {
+                    CodeShare::close_doc(docs, active_doc, index);
+                }
+                *closing_doc = None;
+            }
+        }
+
+        //  Exchange edits with collaborators in live sessions
+        #[cfg(not(target_arch = "wasm32"))]
+        for doc in docs.iter_mut() {
+            CodeShare::sync_collab(ctx, doc, status_msg);
+        }
+
+        //  Finish a publish or fetch once the paste server has answered
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(reply) = paste_request.as_ref().and_then(PendingRequest::try_reply) {
+            *paste_request = None;
+            match reply {
+                Ok(Reply::Published(url)) => {
+                    ctx.output().copied_text = url.clone();
+                    *share_link = url;
+                    *status_msg = Some("Paste link copied".to_string());
+                    *active_popup = Popup::ShareLink;
+                }
+                Ok(Reply::Fetched(snippet)) => {
+                    docs.push(Document::from_snippet(snippet));
+                    *active_doc = docs.len() - 1;
+                    paste_id_buf.clear();
+                    *active_popup = Popup::None;
+                    *status_msg = Some("Paste opened".to_string());
+                }
+                Err(e) => {
+                    *err_msg = Some(e);
+                    *active_popup = Popup::Error;
+                }
+            }
+        }
+
+        let mut undo_requested = false;
+        let mut redo_requested = false;
+        //  Run after the menus, from their buttons and from shortcuts
+        let mut commands = match rebinding {
+            Some(_) => Vec::new(),
+            None => keymap.pressed(ctx.input()),
+        };
+
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
                 egui::menu::menu_button(ui, "File", |ui| {
-                    if ui.button("New").clicked() {
-                        match file_status.is_unsaved() {
-                            false => {
-                                text_buf.clear();
-                                file_status.reset();
-                                if let Some(ln) = line_nums {
-                                    ln.reset();
-                                }
-                                *status_msg = Some("New File Opened".to_string());
-                            }
-                            true => {
-                                *active_popup = Popup::FileNotSavedNew;
-                            }
+                    for command in [
+                        Command::NewFile,
+                        Command::OpenFile,
+                        Command::Save,
+                        Command::SaveAs,
+                        Command::Share,
+                        #[cfg(not(target_arch = "wasm32"))]
+                        Command::PublishPaste,
+                        #[cfg(not(target_arch = "wasm32"))]
+                        Command::OpenPaste,
+                        #[cfg(all(feature = "persistence", not(target_arch = "wasm32")))]
+                        Command::SaveSession,
+                        #[cfg(all(feature = "persistence", not(target_arch = "wasm32")))]
+                        Command::OpenSession,
+                        Command::CloseTab,
+                    ] {
+                        if CodeShare::command_button(ui, keymap, command, true) {
+                            commands.push(command);
                         }
                     }
-                    if ui.button("Open").clicked() {
-                        match file_status.is_unsaved() {
-                            false => {
-                                *active_popup = Popup::OpenFile;
-                            }
-                            true => {
-                                *active_popup = Popup::FileNotSavedOpen;
-                            }
+                    ui.separator();
+                    for command in [Command::Preferences, Command::Quit] {
+                        if CodeShare::command_button(ui, keymap, command, true) {
+                            commands.push(command);
                         }
                     }
-                    if ui.button("Save").clicked() {
-                        *active_popup = Popup::SaveFile;
-                    }
-                    if ui.button("Save As").clicked() {
-                        *active_popup = Popup::SaveAs
-                    }
-                    if ui.button("Quit").clicked() {
-                        frame.quit();
-                    }
                 });
                 egui::menu::menu_button(ui, "Edit", |ui| {
-                    ui.label("to be built");
+                    let history = &docs[*active_doc].history;
+                    if CodeShare::command_button(ui, keymap, Command::Undo, history.can_undo()) {
+                        commands.push(Command::Undo);
+                    }
+                    if CodeShare::command_button(ui, keymap, Command::Redo, history.can_redo()) {
+                        commands.push(Command::Redo);
+                    }
                 });
                 egui::menu::menu_button(ui, "View", |ui| {
                     ui.checkbox(&mut config.line_nums, "Line Numbers");
+                    ui.horizontal(|ui| {
+                        ui.label("Word Wrap:");
+                        CodeShare::wrap_editor(ui, &mut config.word_wrap);
+                    });
                     ui.horizontal(|ui| {
                         if ui.button(" - ").clicked() {
-                            config.dec_font_size();
-                            CodeShare::change_app_font_size(ctx, config.get_font_size());
+                            commands.push(Command::FontSmaller);
                         }
                         let font_size_str = format!("Font Size: {}", config.get_font_size());
                         ui.label(font_size_str);
                         if ui.button(" + ").clicked() {
-                            config.inc_font_size();
-                            CodeShare::change_app_font_size(ctx, config.get_font_size());
+                            commands.push(Command::FontBigger);
                         }
                     });
+                    for (label, editor) in [("Editor Font:", true), ("UI Font:", false)] {
+                        ui.horizontal(|ui| {
+                            ui.label(label);
+                            CodeShare::font_picker(ui, config, editor, &mut commands);
+                        });
+                    }
+                    ui.separator();
+                    ui.label("Theme");
+                    CodeShare::theme_picker(ui, keymap, config, &mut commands);
+                    ui.separator();
+                    let doc = &docs[*active_doc];
+                    ui.label("Encoding");
+                    for (encoding, command) in Encoding::ALL.iter().zip([
+                        Command::EncodingUtf8,
+                        Command::EncodingUtf8Bom,
+                        Command::EncodingUtf16Le,
+                        Command::EncodingUtf16Be,
+                        Command::EncodingLatin1,
+                    ]) {
+                        let current = doc.file_status.encoding == *encoding;
+                        if ui.radio(current, encoding.name()).clicked() {
+                            commands.push(command);
+                        }
+                    }
+                    ui.label("Line Endings");
+                    for (line_ending, command) in LineEnding::ALL.iter().zip([
+                        Command::LineEndingsLf,
+                        Command::LineEndingsCrLf,
+                        Command::LineEndingsCr,
+                    ]) {
+                        let current = doc.file_status.line_ending == *line_ending;
+                        if ui.radio(current, line_ending.name()).clicked() {
+                            commands.push(command);
+                        }
+                    }
+                    ui.separator();
+                    let detected = Language::from_path(doc.file_status.get_path());
+                    ui.label("Syntax");
+                    let auto = format!("Auto ({})", detected.name());
+                    if ui.radio(doc.language_override.is_none(), auto).clicked() {
+                        commands.push(Command::SyntaxAuto);
+                    }
+                    for (language, command) in Language::ALL.iter().zip([
+                        Command::SyntaxPlainText,
+                        Command::SyntaxRust,
+                        Command::SyntaxToml,
+                        Command::SyntaxJson,
+                        Command::SyntaxMarkdown,
+                        Command::SyntaxPython,
+                        Command::SyntaxShell,
+                    ]) {
+                        let current = doc.language_override == Some(*language);
+                        if ui.radio(current, language.name()).clicked() {
+                            commands.push(command);
+                        }
+                    }
                 });
                 egui::menu::menu_button(ui, "Tools", |ui| {
-                    if ui.button("Find").clicked() {
-                        *active_popup = Popup::Find;
-                    }
-                    if ui.button("Find and Replace").clicked() {
-                        *active_popup = Popup::Find;
-                        finder.replace_mode = true;
+                    for command in [
+                        Command::Find,
+                        Command::FindAndReplace,
+                        Command::GoToLine,
+                        #[cfg(not(target_arch = "wasm32"))]
+                        Command::Collaborate,
+                        Command::KeyboardShortcuts,
+                        Command::Palette,
+                    ] {
+                        if CodeShare::command_button(ui, keymap, command, true) {
+                            commands.push(command);
+                        }
                     }
                 });
             });
         });
 
-        //  Save File "popup"
-        if *active_popup == Popup::SaveFile {
-            match file_status.is_new() {
-                true => *active_popup = Popup::SaveAs,
-                false => {
-                    match file_status.save_file(text_buf) {
-                        Ok(_) => {
-                            *active_popup = Popup::None;
-                            *status_msg = Some("Save Successful".to_string());
+        //  Tab strip
+        egui::TopBottomPanel::top("tab_bar").show(ctx, |ui| {
+            ui.horizontal_wrapped(|ui| {
+                let mut close_index = None;
+                for (index, doc) in docs.iter().enumerate() {
+                    if ui
+                        .selectable_label(index == *active_doc, doc.title())
+                        .clicked()
+                    {
+                        *active_doc = index;
+                    }
+                    if ui.small_button("x").clicked() {
+                        close_index = Some(index);
+                    }
+                    ui.separator();
+                }
+                if let Some(index) = close_index {
+                    CodeShare::request_close(docs, active_doc, closing_doc, active_popup, index);
+                }
+            });
+        });
+
+        //  Preferences popup
+        if *active_popup == Popup::Preferences {
+            let mut fonts_changed = false;
+            egui::Window::new("Preferences")
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    egui::Grid::new("preferences")
+                        .num_columns(2)
+                        .show(ui, |ui| {
+                            ui.label("Font size");
+                            let mut size = config.get_font_size() as u32;
+                            if ui
+                                .add(egui::DragValue::new(&mut size).clamp_range(1..=200))
+                                .changed()
+                            {
+                                config.set_font_size(size);
+                                fonts_changed = true;
+                            }
+                            ui.end_row();
+                            for (label, editor) in [("Editor font", true), ("UI font", false)] {
+                                ui.label(label);
+                                CodeShare::font_picker(ui, config, editor, &mut commands);
+                                ui.end_row();
+                            }
+                            ui.label("Theme");
+                            ui.horizontal(|ui| {
+                                CodeShare::theme_picker(ui, keymap, config, &mut commands);
+                            });
+                            ui.end_row();
+                            ui.label("Line numbers");
+                            ui.checkbox(&mut config.line_nums, "");
+                            ui.end_row();
+                            ui.label("Tab key");
+                            ui.horizontal(|ui| {
+                                ui.radio_value(&mut config.insert_spaces, false, "Inserts a tab");
+                                ui.radio_value(&mut config.insert_spaces, true, "Inserts");
+                                ui.add(
+                                    egui::DragValue::new(&mut config.tab_width)
+                                        .clamp_range(1..=16)
+                                        .suffix(" spaces"),
+                                );
+                            });
+                            ui.end_row();
+                            ui.label("Word wrap");
+                            ui.horizontal(|ui| CodeShare::wrap_editor(ui, &mut config.word_wrap));
+                            ui.end_row();
+                            #[cfg(not(target_arch = "wasm32"))]
+                            {
+                                ui.label("Autosave");
+                                ui.horizontal(|ui| {
+                                    if ui.radio(config.autosave == Autosave::Off, "Off").clicked() {
+                                        commands.push(Command::AutosaveOff);
+                                    }
+                                    let every = matches!(config.autosave, Autosave::Every(_));
+                                    if ui.radio(every, "Every").clicked() {
+                                        commands.push(Command::AutosaveTimed);
+                                    }
+                                    if let Autosave::Every(secs) = &mut config.autosave {
+                                        ui.add(
+                                            egui::DragValue::new(secs)
+                                                .clamp_range(5..=3600)
+                                                .suffix(" s"),
+                                        );
+                                    }
+                                    let focus_lost = config.autosave == Autosave::FocusLost;
+                                    if ui.radio(focus_lost, "When leaving the window").clicked() {
+                                        commands.push(Command::AutosaveFocusLost);
+                                    }
+                                });
+                                ui.end_row();
+                                ui.label("Keep a .bak of the old file");
+                                ui.horizontal(|ui| {
+                                    for (backup, label, command) in [
+                                        (Backup::Off, "Never", Command::BackupOff),
+                                        (
+                                            Backup::FirstSave,
+                                            "On first save",
+                                            Command::BackupFirstSave,
+                                        ),
+                                        (
+                                            Backup::EverySave,
+                                            "On every save",
+                                            Command::BackupEverySave,
+                                        ),
+                                    ] {
+                                        if ui.radio(config.backup == backup, label).clicked() {
+                                            commands.push(command);
+                                        }
+                                    }
+                                });
+                                ui.end_row();
+                            }
+                            #[cfg(not(target_arch = "wasm32"))]
+                            {
+                                ui.label("Paste server");
+                                ui.text_edit_singleline(&mut config.paste_server);
+                                ui.end_row();
+                            }
+                            ui.label("Relay");
+                            ui.text_edit_singleline(&mut config.relay_url);
+                            ui.end_row();
+                            ui.label("User name");
+                            ui.text_edit_singleline(&mut config.user_name);
+                            ui.end_row();
+                        });
+                    if ui.button("Close").clicked() {
+                        *active_popup = Popup::None;
+                    }
+                    #[cfg(not(target_arch = "wasm32"))]
+                    if let Some(path) = AppConfig::path() {
+                        ui.label(format!("Saved in {}", path.display()));
+                    }
+                });
+            if fonts_changed {
+                CodeShare::apply_fonts(ctx, config, err_msg, active_popup);
+            }
+        }
+
+        //  Command palette popup
+        if *active_popup == Popup::CommandPalette {
+            let matches = palette.matches();
+            let step = match ctx.input() {
+                input if input.key_pressed(egui::Key::ArrowDown) => 1,
+                input if input.key_pressed(egui::Key::ArrowUp) => -1,
+                _ => 0,
+            };
+            palette.move_selection(step, matches.len());
+            let mut chosen = None;
+            egui::Window::new("Command Palette")
+                .title_bar(false)
+                .collapsible(false)
+                .anchor(egui::Align2::CENTER_TOP, [0.0, 40.0])
+                .show(ctx, |ui| {
+                    let query = ui.add(
+                        egui::widgets::TextEdit::singleline(&mut palette.query)
+                            .hint_text("Type a command"),
+                    );
+                    query.request_focus();
+                    if query.changed() {
+                        palette.selected = 0;
+                    }
+                    egui::ScrollArea::vertical()
+                        .max_height(300.0)
+                        .show(ui, |ui| {
+                            for (index, command) in matches.iter().enumerate() {
+                                ui.horizontal(|ui| {
+                                    let row = ui.selectable_label(
+                                        index == palette.selected,
+                                        command.name(),
+                                    );
+                                    if index == palette.selected && step != 0 {
+                                        row.scroll_to_me(egui::Align::Center);
+                                    }
+                                    if row.clicked() {
+                                        chosen = Some(*command);
+                                    }
+                                    if let Some(shortcut) = keymap.shortcut(*command) {
+                                        ui.add(egui::Label::new(shortcut).weak());
+                                    }
+                                });
+                            }
+                        });
+                });
+            if ctx.input().key_pressed(egui::Key::Enter) {
+                chosen = matches.get(palette.selected).copied();
+            }
+            if ctx.input().key_pressed(egui::Key::Escape) || chosen.is_some() {
+                *active_popup = Popup::None;
+            }
+            if let Some(command) = chosen {
+                palette.used(command);
+                commands.push(command);
+            }
+        }
+
+        for command in commands {
+            match command {
+                Command::NewFile => {
+                    docs.push(Document::default());
+                    *active_doc = docs.len() - 1;
+                    *status_msg = Some("New File Opened".to_string());
+                }
+                Command::OpenFile => *active_popup = Popup::OpenFile,
+                Command::Save => *active_popup = Popup::SaveFile,
+                Command::SaveAs => *active_popup = Popup::SaveAs,
+                Command::Share => {
+                    *share_link = docs[*active_doc].to_snippet().to_url(share_base_url);
+                    ctx.output().copied_text = share_link.clone();
+                    *status_msg = match share_link.len() > share::LONG_LINK_LEN {
+                        true => Some("Long link copied, may get cut off".to_string()),
+                        false => Some("Share link copied".to_string()),
+                    };
+                    *active_popup = Popup::ShareLink;
+                }
+                #[cfg(not(target_arch = "wasm32"))]
+                Command::PublishPaste => *active_popup = Popup::PublishPaste,
+                #[cfg(not(target_arch = "wasm32"))]
+                Command::OpenPaste => *active_popup = Popup::OpenPaste,
+                Command::CloseTab => {
+                    CodeShare::request_close(
+                        docs,
+                        active_doc,
+                        closing_doc,
+                        active_popup,
+                        *active_doc,
+                    );
+                }
+                Command::Preferences => *active_popup = Popup::Preferences,
+                Command::Quit => frame.quit(),
+                Command::Undo => undo_requested = true,
+                Command::Redo => redo_requested = true,
+                Command::Find => *active_popup = Popup::Find,
+                Command::FindAndReplace => {
+                    *active_popup = Popup::Find;
+                    docs[*active_doc].finder.replace_mode = true;
+                }
+                Command::FindNext | Command::FindPrevious => {
+                    let doc = &mut docs[*active_doc];
+                    let editor_id = doc.editor_id();
+                    match doc.finder.query_buf.is_empty() {
+                        true => *active_popup = Popup::Find,
+                        false => CodeShare::step_find(
+                            ctx,
+                            editor_id,
+                            &doc.text_buf,
+                            &mut doc.finder,
+                            command == Command::FindNext,
+                            switch_to_editor,
+                        ),
+                    }
+                }
+                Command::GoToLine => *active_popup = Popup::GoToLine,
+                Command::FontBigger => {
+                    config.inc_font_size();
+                    CodeShare::apply_fonts(ctx, config, err_msg, active_popup);
+                }
+                Command::FontSmaller => {
+                    config.dec_font_size();
+                    CodeShare::apply_fonts(ctx, config, err_msg, active_popup);
+                }
+                Command::ToggleLineNumbers => config.line_nums = !config.line_nums,
+                Command::ToggleWordWrap => {
+                    config.word_wrap = match config.word_wrap {
+                        WordWrap::Off => *word_wrap_on,
+                        _ => WordWrap::Off,
+                    }
+                }
+                #[cfg(all(feature = "persistence", not(target_arch = "wasm32")))]
+                Command::SaveSession => {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("Session", &["toml"])
+                        .save_file()
+                    {
+                        match Session::capture(docs, *active_doc).save(&path) {
+                            Ok(()) => *status_msg = Some("Session saved".to_string()),
+                            Err(e) => {
+                                *err_msg = Some(e);
+                                *active_popup = Popup::Error;
+                            }
                         }
-                        Err(e) => {
-                            let error = format!("Save failed: {}", e);
-                            *err_msg = Some(error);
-                            *active_popup = Popup::Error;
-                            *status_msg = Some("Save Failed".to_string())
+                    }
+                }
+                #[cfg(all(feature = "persistence", not(target_arch = "wasm32")))]
+                Command::OpenSession => {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("Session", &["toml"])
+                        .pick_file()
+                    {
+                        match Session::from_file(&path) {
+                            //  Opened next to the tabs already open
+                            Ok(session) => {
+                                let restored = session.restore();
+                                let first = docs.len();
+                                for (index, doc) in restored.docs.into_iter().enumerate() {
+                                    let pos = doc.text_buf.pos_at_char(doc.cursor);
+                                    let editor_id = doc.editor_id();
+                                    CodeShare::move_cursor(
+                                        ctx,
+                                        editor_id,
+                                        pos,
+                                        None,
+                                        switch_to_editor,
+                                    );
+                                    if restored.changed_on_disk.contains(&index) {
+                                        changed_on_disk.push(editor_id);
+                                    }
+                                    docs.push(doc);
+                                }
+                                *active_doc = first + restored.active_doc;
+                                if !restored.errors.is_empty() {
+                                    *err_msg = Some(format!(
+                                        "Could not reopen {}",
+                                        restored.errors.join("\n")
+                                    ));
+                                    *active_popup = Popup::Error;
+                                }
+                            }
+                            Err(e) => {
+                                *err_msg = Some(e);
+                                *active_popup = Popup::Error;
+                            }
                         }
+                    }
+                }
+                Command::DefaultEditorFont | Command::DefaultUiFont => {
+                    match command {
+                        Command::DefaultEditorFont => config.editor_font = None,
+                        _ => config.ui_font = None,
+                    }
+                    CodeShare::apply_fonts(ctx, config, err_msg, active_popup);
+                }
+                Command::SyntaxAuto
+                | Command::SyntaxPlainText
+                | Command::SyntaxRust
+                | Command::SyntaxToml
+                | Command::SyntaxJson
+                | Command::SyntaxMarkdown
+                | Command::SyntaxPython
+                | Command::SyntaxShell => {
+                    docs[*active_doc].language_override = match command {
+                        Command::SyntaxPlainText => Some(Language::PlainText),
+                        Command::SyntaxRust => Some(Language::Rust),
+                        Command::SyntaxToml => Some(Language::Toml),
+                        Command::SyntaxJson => Some(Language::Json),
+                        Command::SyntaxMarkdown => Some(Language::Markdown),
+                        Command::SyntaxPython => Some(Language::Python),
+                        Command::SyntaxShell => Some(Language::Shell),
+                        _ => None,
                     };
                 }
-            };
-        }
-        //  Open file popup
-        if *active_popup == Popup::OpenFile {
-            match file_status.open_file() {
-                Ok(Some(contents)) => {
-                    *text_buf = contents;
-                    *active_popup = Popup::None;
-                    *status_msg = Some("Open Successful".to_string());
+                Command::EncodingUtf8
+                | Command::EncodingUtf8Bom
+                | Command::EncodingUtf16Le
+                | Command::EncodingUtf16Be
+                | Command::EncodingLatin1 => {
+                    let encoding = match command {
+                        Command::EncodingUtf8 => Encoding::Utf8,
+                        Command::EncodingUtf8Bom => Encoding::Utf8Bom,
+                        Command::EncodingUtf16Le => Encoding::Utf16Le,
+                        Command::EncodingUtf16Be => Encoding::Utf16Be,
+                        _ => Encoding::Latin1,
+                    };
+                    //  Files are converted when next saved
+                    let file_status = &mut docs[*active_doc].file_status;
+                    if file_status.encoding != encoding {
+                        file_status.encoding = encoding;
+                        file_status.set_unsaved(true);
+                    }
                 }
-                Ok(None) => {
-                    *active_popup = Popup::None;
-                    *status_msg = Some("Open Cancelled".to_string());
+                Command::LineEndingsLf | Command::LineEndingsCrLf | Command::LineEndingsCr => {
+                    let line_ending = match command {
+                        Command::LineEndingsLf => LineEnding::Lf,
+                        Command::LineEndingsCrLf => LineEnding::CrLf,
+                        _ => LineEnding::Cr,
+                    };
+                    let file_status = &mut docs[*active_doc].file_status;
+                    if file_status.line_ending != line_ending {
+                        file_status.line_ending = line_ending;
+                        file_status.set_unsaved(true);
+                    }
                 }
-                Err(e) => {
-                    *err_msg = Some(e.to_string());
-                    *active_popup = Popup::Error;
+                #[cfg(not(target_arch = "wasm32"))]
+                Command::AutosaveOff | Command::AutosaveTimed | Command::AutosaveFocusLost => {
+                    config.autosave = match (command, config.autosave) {
+                        (Command::AutosaveOff, _) => Autosave::Off,
+                        (Command::AutosaveFocusLost, _) => Autosave::FocusLost,
+                        //  Keep the interval already set
+                        (_, Autosave::Every(secs)) => Autosave::Every(secs),
+                        _ => Autosave::default(),
+                    };
                 }
-            };
+                #[cfg(not(target_arch = "wasm32"))]
+                Command::BackupOff | Command::BackupFirstSave | Command::BackupEverySave => {
+                    config.backup = match command {
+                        Command::BackupOff => Backup::Off,
+                        Command::BackupFirstSave => Backup::FirstSave,
+                        _ => Backup::EverySave,
+                    };
+                }
+                Command::ThemeDark | Command::ThemeLight | Command::ThemeHighContrast => {
+                    let choice = match command {
+                        Command::ThemeDark => ThemeChoice::Dark,
+                        Command::ThemeLight => ThemeChoice::Light,
+                        _ => ThemeChoice::HighContrast,
+                    };
+                    CodeShare::apply_theme(ctx, choice, config, theme, err_msg, active_popup);
+                }
+                #[cfg(not(target_arch = "wasm32"))]
+                Command::OpenThemeFile => {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("Theme", &["toml"])
+                        .pick_file()
+                    {
+                        let choice = ThemeChoice::File(path);
+                        CodeShare::apply_theme(ctx, choice, config, theme, err_msg, active_popup);
+                    }
+                }
+                #[cfg(not(target_arch = "wasm32"))]
+                Command::ReloadThemeFile => match &config.theme {
+                    ThemeChoice::File(_) => {
+                        let choice = config.theme.clone();
+                        CodeShare::apply_theme(ctx, choice, config, theme, err_msg, active_popup);
+                    }
+                    _ => *status_msg = Some("No theme file in use".to_string()),
+                },
+                #[cfg(not(target_arch = "wasm32"))]
+                Command::ChooseEditorFont | Command::ChooseUiFont => {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("Font", &["ttf", "otf"])
+                        .pick_file()
+                    {
+                        match command {
+                            Command::ChooseEditorFont => config.editor_font = Some(path),
+                            _ => config.ui_font = Some(path),
+                        }
+                        CodeShare::apply_fonts(ctx, config, err_msg, active_popup);
+                    }
+                }
+                #[cfg(not(target_arch = "wasm32"))]
+                Command::Collaborate => *active_popup = Popup::Collaborate,
+                Command::KeyboardShortcuts => *active_popup = Popup::KeyboardShortcuts,
+                Command::Palette => {
+                    palette.reset();
+                    *active_popup = Popup::CommandPalette;
+                }
+            }
         }
-        //   Save as popup
-        if *active_popup == Popup::SaveAs {
-            match file_status.save_file_as(text_buf) {
-                Ok(Some(_)) => {
-                    *active_popup = Popup::None;
-                    *status_msg = Some("Save Successful".to_string());
+
+        //  Keyboard shortcuts popup
+        if *active_popup == Popup::KeyboardShortcuts {
+            let mut changed = false;
+            egui::Window::new("Keyboard Shortcuts")
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    if let Some(command) = *rebinding {
+                        ui.label(format!(
+                            "Press the keys for {}, or Escape to cancel",
+                            command.name()
+                        ));
+                        if let Some(key) = KeyCombo::first_pressed(ctx.input()) {
+                            if key == KeyCombo::new(egui::Key::Escape, &Default::default()) {
+                                *rebinding = None;
+                            } else if !key.is_shortcut() {
+                                //  Keep waiting for a key that can be bound
+                                *status_msg = Some(key.not_a_shortcut());
+                            } else {
+                                *rebinding = None;
+                                let bound = keymap.commands_for(key);
+                                if let Some(other) = bound.iter().find(|other| **other != command) {
+                                    *status_msg =
+                                        Some(format!("{} is also bound to {}", key, other.name()));
+                                }
+                                keymap.bind(command, key);
+                                changed = true;
+                            }
+                        }
+                    }
+                    egui::ScrollArea::vertical()
+                        .max_height(400.0)
+                        .show(ui, |ui| {
+                            egui::Grid::new("keymap").striped(true).show(ui, |ui| {
+                                for command in Command::ALL.iter().copied() {
+                                    ui.label(command.name());
+                                    ui.horizontal(|ui| {
+                                        for key in keymap.keys(command).to_vec() {
+                                            if ui
+                                                .button(key.to_string())
+                                                .on_hover_text("Click to remove")
+                                                .clicked()
+                                            {
+                                                keymap.unbind(command, key);
+                                                changed = true;
+                                            }
+                                        }
+                                        if ui.small_button("+").on_hover_text("Add keys").clicked()
+                                        {
+                                            *rebinding = Some(command);
+                                        }
+                                    });
+                                    ui.end_row();
+                                }
+                            });
+                        });
+                    for (key, bound) in keymap.conflicts() {
+                        let names: Vec<&str> = bound.iter().map(|command| command.name()).collect();
+                        ui.colored_label(
+                            Color32::RED,
+                            format!(
+                                "{} is bound to {}, only {} runs",
+                                key,
+                                names.join(", "),
+                                names[0]
+                            ),
+                        );
+                    }
+                    ui.horizontal(|ui| {
+                        if ui.button("Reset to Defaults").clicked() {
+                            *keymap = Keymap::default();
+                            changed = true;
+                        }
+                        if ui.button("Close").clicked() {
+                            *rebinding = None;
+                            *active_popup = Popup::None;
+                        }
+                    });
+                });
+            //  Keep changes for next time, the web build only has them until it's closed
+            if changed {
+                #[cfg(not(target_arch = "wasm32"))]
+                if let Err(e) = Keymap::path()
+                    .ok_or_else(|| "no config directory".to_string())
+                    .and_then(|path| keymap.save(&path))
+                {
+                    *err_msg = Some(format!("Could not save keymap {}", e));
+                    *active_popup = Popup::Error;
                 }
-                Ok(None) => {
+            }
+        }
+
+        //  Open file popup
+        if *active_popup == Popup::OpenFile {
+            match FileStatus::open_file_sel_dialog() {
+                Some(path) => match FileCheck::of(&path) {
+                    Ok(FileCheck::Text) => match CodeShare::open_doc(docs, active_doc, &path) {
+                        Ok(_) => {
+                            *active_popup = Popup::None;
+                            *status_msg = Some("Open Successful".to_string());
+                        }
+                        Err(e) => {
+                            *err_msg = Some(e);
+                            *active_popup = Popup::Error;
+                        }
+                    },
+                    Ok(check) => {
+                        pending_opens.push((path, check));
+                        *active_popup = Popup::None;
+                    }
+                    Err(e) => {
+                        *err_msg = Some(format!("{}: {}", path.display(), e));
+                        *active_popup = Popup::Error;
+                    }
+                },
+                None => {
                     *active_popup = Popup::None;
-                    *status_msg = Some("Save Cancelled".to_string());
+                    *status_msg = Some("Open Cancelled".to_string());
                 }
-                Err(e) => {
-                    *err_msg = Some(e.to_string());
-                    *active_popup = Popup::Error;
+            }
+        }
+        //  Binary or very large file popup
+        if *active_popup == Popup::None && !pending_opens.is_empty() {
+            *active_popup = Popup::OpenUnusual;
+        }
+        if *active_popup == Popup::OpenUnusual {
+            match pending_opens.first().cloned() {
+                Some((path, check)) => {
+                    let name = path.display();
+                    let mut choice = None;
+                    egui::Window::new("Open File")
+                        .collapsible(false)
+                        .show(ctx, |ui| {
+                            match check {
+                                FileCheck::Large { size } => ui.label(format!(
+                                    "{} is {}, opening all of it may freeze the editor for a while",
+                                    name,
+                                    preview::format_size(size)
+                                )),
+                                _ => ui.label(format!("{} looks like a binary file", name)),
+                            };
+                            ui.horizontal(|ui| {
+                                let (preview_label, kind) = match check {
+                                    FileCheck::Large { .. } => {
+                                        ("Open in Chunks", PreviewKind::Text)
+                                    }
+                                    _ => ("View as Hex", PreviewKind::Hex),
+                                };
+                                if ui.button(preview_label).clicked() {
+                                    choice = Some(Some(kind));
+                                }
+                                if ui.button("Open Anyway").clicked() {
+                                    choice = Some(None);
+                                }
+                                if ui.button("Cancel").clicked() {
+                                    pending_opens.remove(0);
+                                    *status_msg = Some("Open Cancelled".to_string());
+                                }
+                            });
+                        });
+                    if let Some(kind) = choice {
+                        pending_opens.remove(0);
+                        let opened = match kind {
+                            Some(kind) => Preview::open(&path, kind)
+                                .map(|(preview, first_chunk)| {
+                                    let doc = Document::from_preview(preview, first_chunk);
+                                    CodeShare::add_doc(docs, active_doc, doc);
+                                })
+                                .map_err(|e| format!("{}: {}", name, e)),
+                            None => CodeShare::open_doc(docs, active_doc, &path),
+                        };
+                        match opened {
+                            Ok(_) => *status_msg = Some("Open Successful".to_string()),
+                            Err(e) => {
+                                *err_msg = Some(e);
+                                *active_popup = Popup::Error;
+                            }
+                        }
+                    }
                 }
-            };
+                None => *active_popup = Popup::None,
+            }
         }
-        //  File not saved popup (creating new file)
-        if *active_popup == Popup::FileNotSavedNew {
+        //  Publish to paste server popup
+        #[cfg(not(target_arch = "wasm32"))]
+        if *active_popup == Popup::PublishPaste {
+            egui::Window::new("Publish to Server")
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Server:");
+                        ui.text_edit_singleline(&mut config.paste_server);
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Expires after:");
+                        for expiry in Expiry::ALL.iter() {
+                            ui.radio_value(paste_expiry, *expiry, expiry.name());
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        let publish = egui::Button::new("Publish");
+                        if ui.add_enabled(paste_request.is_none(), publish).clicked() {
+                            let repaint_signal = frame.repaint_signal();
+                            *paste_request = Some(PendingRequest::publish(
+                                config.paste_server.clone(),
+                                docs[*active_doc].to_snippet(),
+                                *paste_expiry,
+                                move || repaint_signal.request_repaint(),
+                            ));
+                        }
+                        if ui.button("Cancel").clicked() {
+                            *paste_request = None;
+                            *active_popup = Popup::None;
+                        }
+                        if paste_request.is_some() {
+                            ui.label("Publishing...");
+                        }
+                    });
+                });
+        }
+        //  Live editing session popup
+        #[cfg(not(target_arch = "wasm32"))]
+        if *active_popup == Popup::Collaborate {
+            egui::Window::new("Collaborate")
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Relay:");
+                        ui.text_edit_singleline(&mut config.relay_url);
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Your name:");
+                        ui.text_edit_singleline(&mut config.user_name);
+                    });
+                    let session_info = docs[*active_doc].collab.as_ref().map(|session| {
+                        (session.session_name.clone(), session.collaborator_count())
+                    });
+                    match session_info {
+                        Some((session_name, others)) => {
+                            ui.label(format!(
+                                "This tab is in session \"{}\" with {} others",
+                                session_name, others
+                            ));
+                            ui.horizontal(|ui| {
+                                if ui.button("Leave").clicked() {
+                                    docs[*active_doc].collab = None;
+                                    *status_msg =
+                                        Some(format!("Left session \"{}\"", session_name));
+                                    *active_popup = Popup::None;
+                                }
+                                if ui.button("Close").clicked() {
+                                    *active_popup = Popup::None;
+                                }
+                            });
+                        }
+                        None => {
+                            ui.horizontal(|ui| {
+                                ui.label("Session:");
+                                ui.text_edit_singleline(session_name_buf);
+                            });
+                            ui.horizontal(|ui| {
+                                if ui.button("Host This Tab").clicked() {
+                                    let repaint_signal = frame.repaint_signal();
+                                    let doc = &mut docs[*active_doc];
+                                    match CollabSession::host(
+                                        &config.relay_url,
+                                        session_name_buf,
+                                        &config.user_name,
+                                        &doc.text_buf.to_string(),
+                                        move || repaint_signal.request_repaint(),
+                                    ) {
+                                        Ok(session) => {
+                                            doc.collab = Some(session);
+                                            *status_msg = Some(format!(
+                                                "Hosting session \"{}\"",
+                                                session_name_buf
+                                            ));
+                                            *active_popup = Popup::None;
+                                        }
+                                        Err(e) => {
+                                            *err_msg = Some(e.to_string());
+                                            *active_popup = Popup::Error;
+                                        }
+                                    }
+                                }
+                                if ui.button("Join").clicked() {
+                                    let repaint_signal = frame.repaint_signal();
+                                    match CollabSession::join(
+                                        &config.relay_url,
+                                        session_name_buf,
+                                        &config.user_name,
+                                        move || repaint_signal.request_repaint(),
+                                    ) {
+                                        Ok((session, text)) => {
+                                            let mut new_doc = Document::default();
+                                            new_doc.load_text(text);
+                                            new_doc.shared_name = Some(session_name_buf.clone());
+                                            new_doc.collab = Some(session);
+                                            docs.push(new_doc);
+                                            *active_doc = docs.len() - 1;
+                                            *status_msg = Some(format!(
+                                                "Joined session \"{}\"",
+                                                session_name_buf
+                                            ));
+                                            *active_popup = Popup::None;
+                                        }
+                                        Err(e) => {
+                                            *err_msg = Some(e.to_string());
+                                            *active_popup = Popup::Error;
+                                        }
+                                    }
+                                }
+                                if ui.button("Cancel").clicked() {
+                                    *active_popup = Popup::None;
+                                }
+                            });
+                        }
+                    }
+                });
+        }
+        //  Open from paste server popup
+        #[cfg(not(target_arch = "wasm32"))]
+        if *active_popup == Popup::OpenPaste {
+            egui::Window::new("Open from Server")
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Server:");
+                        ui.text_edit_singleline(&mut config.paste_server);
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Paste:");
+                        ui.add(
+                            egui::widgets::TextEdit::singleline(paste_id_buf)
+                                .hint_text("Id or link"),
+                        );
+                    });
+                    ui.horizontal(|ui| {
+                        let open = egui::Button::new("Open");
+                        if ui.add_enabled(paste_request.is_none(), open).clicked() {
+                            let repaint_signal = frame.repaint_signal();
+                            *paste_request = Some(PendingRequest::fetch(
+                                config.paste_server.clone(),
+                                paste_id_buf.clone(),
+                                move || repaint_signal.request_repaint(),
+                            ));
+                        }
+                        if ui.button("Cancel").clicked() {
+                            *paste_request = None;
+                            *active_popup = Popup::None;
+                        }
+                        if paste_request.is_some() {
+                            ui.label("Opening...");
+                        }
+                    });
+                });
+        }
+        //  File not saved popup (closing a tab)
+        if *active_popup == Popup::FileNotSavedClose {
+            let index = closing_doc.unwrap_or(*active_doc);
             egui::Window::new("File Not Saved")
                 .collapsible(false)
                 .show(ctx, |ui| {
-                    ui.label("Current file has not been saved");
+                    ui.label(format!(
+                        "{} has not been saved",
+                        docs[index].file_status.get_file_name()
+                    ));
                     ui.horizontal(|ui| {
                         if ui.button("Save").clicked() {
+                            *active_doc = index;
                             *active_popup = Popup::SaveFile;
                         }
                         if ui.button("Save As").clicked() {
+                            *active_doc = index;
                             *active_popup = Popup::SaveAs;
                         }
-                        if ui.button("Continue without saving").clicked() {
-                            text_buf.clear();
-                            file_status.reset();
+                        if ui.button("Close without saving").clicked() {
+                            CodeShare::close_doc(docs, active_doc, index);
+                            *closing_doc = None;
+                            *active_popup = Popup::None;
+                            *status_msg = Some("Closed w/o saving".to_string());
+                        }
+                        if ui.button("Cancel").clicked() {
+                            *closing_doc = None;
                             *active_popup = Popup::None;
-                            *status_msg = Some("Cont. w/o saving".to_string());
                         }
                     });
                 });
         }
-        //  File not saved popup (opening different file)
-        if *active_popup == Popup::FileNotSavedOpen {
-            egui::Window::new("File Not Saved")
+
+        //  File changed on disk popup, once nothing else is being asked
+        changed_on_disk.retain(|id| docs.iter().any(|doc| doc.editor_id() == *id));
+        if *active_popup == Popup::None && !changed_on_disk.is_empty() {
+            *active_popup = Popup::ChangedOnDisk;
+        }
+        if *active_popup == Popup::ChangedOnDisk {
+            match changed_on_disk.first() {
+                Some(id) => {
+                    let index = docs.iter().position(|doc| doc.editor_id() == *id).unwrap();
+                    *active_doc = index;
+                    let doc = &mut docs[index];
+                    egui::Window::new("File Changed on Disk")
+                        .collapsible(false)
+                        .show(ctx, |ui| {
+                            ui.label(format!(
+                                "{} changed on disk, but has unsaved changes here",
+                                doc.file_status.get_file_name()
+                            ));
+                            ui.horizontal(|ui| {
+                                if ui.button("Reload").clicked() {
+                                    changed_on_disk.remove(0);
+                                    *disk_diff = None;
+                                    match doc.file_status.reload() {
+                                        Ok(contents) => doc.reload_text(contents),
+                                        Err(e) => {
+                                            *err_msg = Some(e.to_string());
+                                            *active_popup = Popup::Error;
+                                        }
+                                    }
+                                }
+                                if ui.button("Keep mine").clicked() {
+                                    doc.file_status.sync_modified();
+                                    changed_on_disk.remove(0);
+                                    *disk_diff = None;
+                                }
+                                if ui.button("Diff").clicked() {
+                                    *disk_diff = match disk_diff {
+                                        Some(_) => None,
+                                        None => Some(history::diff_lines(
+                                            &doc.file_status.read_disk().unwrap_or_default(),
+                                            &doc.text_buf.to_string(),
+                                        )),
+                                    };
+                                }
+                            });
+                            CodeShare::show_diff(ui, disk_diff);
+                        });
+                }
+                None => *active_popup = Popup::None,
+            }
+        }
+        //  Recover from swap file popup
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            if *active_popup == Popup::None && !swap_orphans.is_empty() {
+                *active_popup = Popup::RecoverSwap;
+            }
+            if *active_popup == Popup::RecoverSwap {
+                match swap_orphans.first() {
+                    Some(orphan) => {
+                        let mut done = false;
+                        egui::Window::new("Recover Unsaved Changes")
+                            .collapsible(false)
+                            .show(ctx, |ui| {
+                                ui.label(format!(
+                                    "Found unsaved changes to {} from an editor that didn't close properly",
+                                    orphan.name()
+                                ));
+                                if orphan.maybe_in_use {
+                                    ui.label("That editor may still be running, so its swap file is kept");
+                                }
+                                ui.horizontal(|ui| {
+                                    if ui.button("Recover").clicked() {
+                                        CodeShare::recover_swap(docs, active_doc, orphan);
+                                        *status_msg = Some("Unsaved changes recovered".to_string());
+                                        done = true;
+                                    }
+                                    let discard = match orphan.maybe_in_use {
+                                        true => "Skip",
+                                        false => "Discard",
+                                    };
+                                    if ui.button(discard).clicked() {
+                                        orphan.discard();
+                                        done = true;
+                                    }
+                                    if ui.button("Diff").clicked() {
+                                        *disk_diff = match disk_diff {
+                                            Some(_) => None,
+                                            None => Some(history::diff_lines(
+                                                &orphan.disk_contents(),
+                                                &orphan.contents,
+                                            )),
+                                        };
+                                    }
+                                });
+                                CodeShare::show_diff(ui, disk_diff);
+                            });
+                        if done {
+                            swap_orphans.remove(0);
+                            *disk_diff = None;
+                        }
+                    }
+                    None => *active_popup = Popup::None,
+                }
+            }
+        }
+
+        let doc = &mut docs[*active_doc];
+        let editor_id = doc.editor_id();
+        let language = doc.language();
+        let Document {
+            file_status,
+            text_buf,
+            line_nums,
+            finder,
+            history,
+            read_only,
+            shared_name,
+            preview,
+            cursor: last_cursor,
+            scroll_offset,
+            scroll_to,
+            #[cfg(not(target_arch = "wasm32"))]
+            collab,
+            ..
+        } = doc;
+
+        //  Save File "popup"
+        if *active_popup == Popup::SaveFile {
+            match file_status.is_new() {
+                true => *active_popup = Popup::SaveAs,
+                //  Don't silently throw away what another program wrote
+                false if file_status.changed_on_disk() => *active_popup = Popup::OverwriteChanged,
+                false => {
+                    match file_status.save_file(&text_buf.to_string(), config.backup) {
+                        Ok(_) => {
+                            *active_popup = Popup::None;
+                            *status_msg = Some("Save Successful".to_string());
+                        }
+                        Err(e) => {
+                            let error = format!("Save failed: {}", e);
+                            *err_msg = Some(error);
+                            *active_popup = Popup::Error;
+                            *status_msg = Some("Save Failed".to_string())
+                        }
+                    };
+                }
+            };
+        }
+        //  Overwrite a file changed on disk popup
+        if *active_popup == Popup::OverwriteChanged {
+            egui::Window::new("File Changed on Disk")
                 .collapsible(false)
                 .show(ctx, |ui| {
-                    ui.label("Current file has not been saved");
+                    ui.label(format!(
+                        "{} changed on disk since it was opened, saving will overwrite it",
+                        file_status.get_file_name()
+                    ));
                     ui.horizontal(|ui| {
-                        if ui.button("Save").clicked() {
+                        if ui.button("Overwrite").clicked() {
+                            file_status.sync_modified();
                             *active_popup = Popup::SaveFile;
                         }
                         if ui.button("Save As").clicked() {
                             *active_popup = Popup::SaveAs;
                         }
-                        if ui.button("Continue without saving").clicked() {
-                            *active_popup = Popup::OpenFile;
-                            *status_msg = Some("Cont. w/o saving".to_string());
+                        if ui.button("Cancel").clicked() {
+                            *active_popup = Popup::None;
+                            *status_msg = Some("Save Cancelled".to_string());
                         }
                     });
                 });
         }
+        //   Save as popup
+        if *active_popup == Popup::SaveAs {
+            match file_status.save_file_as(&text_buf.to_string(), config.backup) {
+                Ok(Some(_)) => {
+                    *active_popup = Popup::None;
+                    *status_msg = Some("Save Successful".to_string());
+                }
+                Ok(None) => {
+                    *active_popup = Popup::None;
+                    *status_msg = Some("Save Cancelled".to_string());
+                }
+                Err(e) => {
+                    *err_msg = Some(e.to_string());
+                    *active_popup = Popup::Error;
+                }
+            };
+        }
         //  Find Popup
         if *active_popup == Popup::Find {
             egui::Window::new("Find")
@@ -275,66 +1677,171 @@ impl epi::App for CodeShare {
                             egui::widgets::TextEdit::singleline(&mut finder.query_buf)
                                 .hint_text("Find"),
                         );
-                        if search_box.changed() && !finder.get_query().is_empty() {
-                            let query = finder.get_query();
-                            finder.reset_matches();
-                            for (loc, _str) in text_buf.match_indices(&query) {
-                                finder.add_match(loc);
-                            }
-                            CodeShare::highlight_text_no_switch(ctx, finder);
-                        } else if finder.get_query().is_empty() {
-                            finder.reset_matches();
-                        }
                         ui.checkbox(&mut finder.replace_mode, "Replace");
                         ui.label(format!("{} maches found", finder.number_of_matches()));
+                        if search_box.changed() {
+                            finder.search(&text_buf.to_string());
+                            CodeShare::highlight_text_no_switch(ctx, editor_id, text_buf, finder);
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        let regex_box = ui.checkbox(&mut finder.regex_mode, "Regex");
+                        let case_box = ui.checkbox(&mut finder.case_insensitive, "Ignore case");
+                        let word_box = ui.checkbox(&mut finder.whole_word, "Whole word");
+                        if regex_box.changed() || case_box.changed() || word_box.changed() {
+                            finder.search(&text_buf.to_string());
+                            CodeShare::highlight_text_no_switch(ctx, editor_id, text_buf, finder);
+                        }
                     });
+                    if let Some(error) = &finder.error {
+                        ui.colored_label(Color32::RED, error);
+                    }
                     if finder.replace_mode {
                         ui.horizontal(|ui| {
-                            ui.add(egui::widgets::TextEdit::singleline(&mut finder.replace_buf).hint_text("Replace"));
+                            ui.add(
+                                egui::widgets::TextEdit::singleline(&mut finder.replace_buf)
+                                    .hint_text("Replace"),
+                            );
                             if ui.button("Replace").clicked() && finder.initial_click_made {
                                 let (start_loc, end_loc) = match finder.get_current_match() {
-                                    Some((sl, len)) => (sl, sl+len),
+                                    Some(current_match) => current_match,
                                     None => {
                                         *active_popup = Popup::Error;
                                         *err_msg = Some("No text selected".into());
-                                        return
-                                    }   
+                                        return;
+                                    }
                                 };
-                                text_buf.replace_range(start_loc..end_loc, &finder.replace_buf);
-                                finder.update_matches();
-                                CodeShare::highlight_text(ctx, finder, switch_to_editor);
-                                
+                                let text = text_buf.to_string();
+                                let replacement = match finder
+                                    .replacement_for(&text, start_loc, end_loc)
+                                {
+                                    Some(replacement) => replacement,
+                                    None => {
+                                        finder.refresh(&text);
+                                        finder.error =
+                                            Some("The text changed, matches were updated".into());
+                                        return;
+                                    }
+                                };
+                                text_buf.replace_range(start_loc..end_loc, &replacement);
+                                history.record_group(text_buf);
+                                file_status.set_unsaved(true);
+                                *read_only = false;
+                                finder.refresh(&text_buf.to_string());
+                                CodeShare::highlight_text(
+                                    ctx,
+                                    editor_id,
+                                    text_buf,
+                                    finder,
+                                    switch_to_editor,
+                                );
                             }
                             if ui.button("Replace All").clicked() {
-                                for start_loc in (*finder).match_locations.iter() {
-                                    let end_loc = start_loc + finder.query_buf.chars().count();
-                                    text_buf.replace_range(start_loc..&end_loc, &finder.replace_buf);
+                                let selection = CodeShare::get_selection(ctx, editor_id, text_buf);
+                                if finder.in_selection && selection.is_none() {
+                                    finder.error = Some("Nothing selected".into());
+                                } else {
+                                    let within = selection
+                                        .filter(|_| finder.in_selection)
+                                        .map(|(start, end)| (start.byte, end.byte));
+                                    match finder.replace_all(&text_buf.to_string(), within) {
+                                        Ok((replaced, count)) => {
+                                            if count > 0 {
+                                                text_buf.set(replaced);
+                                                history.record_group(text_buf);
+                                                file_status.set_unsaved(true);
+                                                *read_only = false;
+                                            }
+                                            *status_msg =
+                                                Some(format!("{} replacements made", count));
+                                        }
+                                        Err(e) => finder.error = Some(e.to_string()),
+                                    }
+                                    finder.reset_matches();
                                 }
-                                finder.reset_matches();
                             }
+                            ui.checkbox(&mut finder.in_selection, "In selection");
                         });
                     }
                     ui.horizontal(|ui| {
                         let prev_but = ui.add(egui::widgets::Button::new("Previous"));
                         let next_but = ui.add(egui::widgets::Button::new("Next"));
-                        
-                        if prev_but.clicked() && finder.number_of_matches() != 0 {
-                            if finder.initial_click_made {
-                                finder.selected_loc_dec();
-                            }
-                            CodeShare::highlight_text(ctx, finder, switch_to_editor);                            
+                        if prev_but.clicked() || next_but.clicked() {
+                            CodeShare::step_find(
+                                ctx,
+                                editor_id,
+                                text_buf,
+                                finder,
+                                next_but.clicked(),
+                                switch_to_editor,
+                            );
+                        }
+                        if ui.button("Close").clicked() {
+                            finder.full_reset();
+                            *active_popup = Popup::None;
+                        }
+                    });
+                });
+        }
+        //  Go to line popup
+        if *active_popup == Popup::GoToLine {
+            egui::Window::new("Go to Line")
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    let mut go = false;
+                    ui.horizontal(|ui| {
+                        ui.label("Line:");
+                        let input = ui.add(
+                            egui::widgets::TextEdit::singleline(go_to_line_buf)
+                                .hint_text("line[:column]"),
+                        );
+                        go = input.lost_focus() && ctx.input().key_pressed(egui::Key::Enter);
+                        input.request_focus();
+                    });
+                    ui.horizontal(|ui| {
+                        go |= ui.button("Go").clicked();
+                        if ui.button("Cancel").clicked() {
+                            go_to_line_buf.clear();
+                            *active_popup = Popup::None;
                         }
-                        if next_but.clicked() && finder.number_of_matches() != 0 {
-                            if finder.initial_click_made {
-                                finder.selected_loc_inc();
+                    });
+                    if go {
+                        match TextPos::parse_line_col(go_to_line_buf) {
+                            Some((line, column)) => {
+                                let pos = text_buf.pos_at_line_col(line, column);
+                                CodeShare::move_cursor(ctx, editor_id, pos, None, switch_to_editor);
+                                //  The editor doesn't scroll to a cursor moved from outside
+                                let row_height =
+                                    ctx.fonts()[egui::TextStyle::Monospace].row_height();
+                                let above = line_nums
+                                    .row_of_line(pos.line.saturating_sub(GO_TO_LINE_CONTEXT_ROWS));
+                                *scroll_to = Some(above as f32 * row_height);
+                                go_to_line_buf.clear();
+                                *active_popup = Popup::None;
+                            }
+                            None => {
+                                *status_msg = Some(format!("Not a line number: {}", go_to_line_buf))
                             }
-                            CodeShare::highlight_text(ctx, finder, switch_to_editor);
                         }
-                        if finder.number_of_matches() == 0 && (next_but.clicked() || prev_but.clicked()) {
-                            CodeShare::move_cursor(ctx, 0, None, switch_to_editor);
+                    }
+                });
+        }
+        //  Share link popup
+        if *active_popup == Popup::ShareLink {
+            egui::Window::new("Share")
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    ui.label("Anyone with this link can view the snippet:");
+                    ui.add(
+                        egui::widgets::TextEdit::singleline(&mut share_link.as_str())
+                            .desired_width(400.0),
+                    );
+                    ui.horizontal(|ui| {
+                        if ui.button("Copy").clicked() {
+                            ctx.output().copied_text = share_link.clone();
+                            *status_msg = Some("Share link copied".to_string());
                         }
                         if ui.button("Close").clicked() {
-                            finder.full_reset();
                             *active_popup = Popup::None;
                         }
                     });
@@ -357,47 +1864,10 @@ impl epi::App for CodeShare {
                 });
         }
 
-        //  Keyboard Shortcuts
-        //  Save
-        if ctx.input().modifiers.command && ctx.input().key_pressed(egui::Key::S) {
-            *active_popup = Popup::SaveFile;
-        }
-        //  New
-        if ctx.input().modifiers.command && ctx.input().key_pressed(egui::Key::N) {
-            match file_status.is_unsaved() {
-                false => {
-                    text_buf.clear();
-                    file_status.reset();
-                    if let Some(ln) = line_nums {
-                        ln.reset();
-                    }
-                    *status_msg = Some("New File Opened".to_string());
-                }
-                true => {
-                    *active_popup = Popup::FileNotSavedNew;
-                }
-            }
-        }
-        //  Open
-        if ctx.input().modifiers.command && ctx.input().key_pressed(egui::Key::O) {
-            match file_status.is_unsaved() {
-                false => {
-                    *active_popup = Popup::OpenFile;
-                }
-                true => {
-                    *active_popup = Popup::FileNotSavedOpen;
-                }
-            }
-        }
-        // Find
-        if ctx.input().modifiers.command && ctx.input().key_pressed(egui::Key::F) {
-            *active_popup = Popup::Find;
-        }
-
         egui::TopBottomPanel::bottom("info bar")
             .frame(
                 egui::Frame::none()
-                    .fill(Color32::from_rgb(232, 188, 68))
+                    .fill(theme.info_bar_background)
                     .corner_radius(0.0),
             )
             .show(ctx, |ui| {
@@ -412,10 +1882,44 @@ impl epi::App for CodeShare {
                             .code_editor()
                             .frame(false)
                             .interactive(false)
-                            .text_color(egui::Color32::BLACK),
+                            .text_color(theme.info_bar_text),
                     );
 
                     ui.with_layout(egui::Layout::right_to_left(), |ui| {
+                        let mut cursor_line =
+                            match CodeShare::get_cursor_pos(ctx, editor_id, text_buf) {
+                                Some(cursor) => {
+                                    format!("Ln {}, Col {}", cursor.line + 1, cursor.column + 1)
+                                }
+                                None => String::new(),
+                            };
+                        if let Some((start, end)) =
+                            CodeShare::get_selection(ctx, editor_id, text_buf)
+                        {
+                            cursor_line.push_str(&format!(" ({} selected)", end.char - start.char));
+                        }
+                        cursor_line.push_str(&format!("  {} lines", text_buf.line_count()));
+                        let mut format_line = format!(
+                            "{} {}",
+                            file_status.encoding.name(),
+                            file_status.line_ending.name()
+                        );
+                        ui.add(
+                            egui::widgets::TextEdit::singleline(&mut format_line)
+                                .code_editor()
+                                .frame(false)
+                                .interactive(false)
+                                .text_color(theme.info_bar_text)
+                                .desired_width(config.get_font_size() * 7.0),
+                        );
+                        ui.add(
+                            egui::widgets::TextEdit::singleline(&mut cursor_line.as_str())
+                                .code_editor()
+                                .frame(false)
+                                .interactive(false)
+                                .text_color(theme.info_bar_text)
+                                .desired_width(config.get_font_size() * 16.0),
+                        );
                         let mut msg_to_display = match status_msg {
                             Some(msg) => msg,
                             None => "",
@@ -425,140 +1929,938 @@ impl epi::App for CodeShare {
                                 .code_editor()
                                 .frame(false)
                                 .interactive(false)
-                                .text_color(Color32::BLACK)
+                                .text_color(theme.info_bar_text)
                                 .desired_width(config.get_font_size() * 10.0),
                         );
                     });
                 });
             });
 
+        //  Shared snippets and --readonly files stay read-only until the user asks to edit
+        if *read_only {
+            egui::TopBottomPanel::top("read_only_bar").show(ctx, |ui| {
+                ui.horizontal(|ui| match preview {
+                    Some(preview) => {
+                        ui.label(format!("{} (read-only)", preview.describe()));
+                        if !preview.is_complete() && ui.button("Load More").clicked() {
+                            match preview.load_more() {
+                                Ok(chunk) => text_buf.push_str(&chunk),
+                                Err(e) => *status_msg = Some(format!("Could not load more: {}", e)),
+                            }
+                        }
+                    }
+                    None => {
+                        let viewing = match (file_status.get_path(), shared_name.as_deref()) {
+                            (None, Some(name)) => format!("Viewing shared {}", name),
+                            _ => format!("Viewing {}", file_status.get_file_name()),
+                        };
+                        ui.label(format!("{} (read-only)", viewing));
+                        if ui.button("Edit").clicked() {
+                            *read_only = false;
+                            *switch_to_editor = true;
+                        }
+                    }
+                });
+            });
+        }
+
         egui::CentralPanel::default()
             .frame(
                 egui::Frame::none()
-                    .fill(Color32::from_rgb(14, 15, 23))
+                    .fill(theme.editor_background)
                     .corner_radius(0.0),
             )
             .show(ctx, |ui| {
-                egui::ScrollArea::vertical().show(ui, |ui| {
-                    let mut layouter = |ui: &egui::Ui, string: &str, _wrap_width: f32| {
+                let row_height = ui.fonts()[egui::TextStyle::Monospace].row_height();
+                //  Bring the caret into view when something else moved it, e.g. Find,
+                //  or when typing at it while scrolled away
+                if let Some(cursor_range) = egui::TextEdit::load_state(ctx, editor_id)
+                    .and_then(|state| state.ccursor_range())
+                {
+                    let typing =
+                        ui.memory().has_focus(editor_id) && CodeShare::edits_at_cursor(ctx.input());
+                    if scroll_to.is_none() && (typing || cursor_range.primary.index != *last_cursor)
+                    {
+                        let line = text_buf.pos_at_char(cursor_range.primary.index).line;
+                        let row = line_nums.row_of_line(line);
+                        let top = row as f32 * row_height;
+                        if top < *scroll_offset
+                            || top + row_height > *scroll_offset + ui.available_height()
+                        {
+                            let above = row.saturating_sub(GO_TO_LINE_CONTEXT_ROWS);
+                            *scroll_to = Some(above as f32 * row_height);
+                        }
+                    }
+                }
+                //  egui's select all would only select the lines laid out
+                if ui.memory().has_focus(editor_id)
+                    && ctx.input().modifiers.command
+                    && ctx.input().key_pressed(egui::Key::A)
+                {
+                    let end = text_buf.pos_at_byte(text_buf.len());
+                    CodeShare::move_cursor(
+                        ctx,
+                        editor_id,
+                        text_buf.pos_at_byte(0),
+                        Some(end),
+                        switch_to_editor,
+                    );
+                }
+
+                let mut scroll_area = egui::ScrollArea::vertical();
+                if let Some(offset) = scroll_to.take() {
+                    scroll_area = scroll_area.scroll_offset(offset);
+                }
+                scroll_area.show_viewport(ui, |ui, viewport| {
+                    *scroll_offset = viewport.min.y;
+                    let first_row = (viewport.min.y / row_height).floor().max(0.0) as usize;
+                    let last_row = (viewport.max.y / row_height).ceil() as usize;
+                    //  Only the blocks of lines in view and one either side are laid
+                    //  out, along with any selection so it can be copied or typed over
+                    let top_line = line_nums.line_at_row(first_row);
+                    let mut first_line =
+                        (top_line / EDITOR_BLOCK_LINES).saturating_sub(1) * EDITOR_BLOCK_LINES;
+                    let mut end_line = (line_nums.line_at_row(last_row) / EDITOR_BLOCK_LINES + 2)
+                        * EDITOR_BLOCK_LINES;
+                    if let Some((start, end)) = CodeShare::get_selection(ctx, editor_id, text_buf) {
+                        first_line = first_line.min(start.line);
+                        end_line = end_line.max(end.line + 1);
+                    }
+                    let last_line = text_buf.pos_at_byte(text_buf.len()).line;
+                    first_line = first_line.min(last_line);
+                    end_line = end_line.clamp(first_line + 1, last_line + 1);
+                    let lines_after = last_line + 1 - end_line;
+                    let shown = text_buf.line_range(first_line..end_line);
+                    let shown_chars =
+                        text_buf.byte_to_char(shown.start)..text_buf.byte_to_char(shown.end);
+                    //  Where the top line was drawn, to keep it in place if the
+                    //  lines around it wrap differently once laid out
+                    let top_line_row = line_nums.row_of_line(top_line);
+
+                    let mut gutter_layouter = |ui: &egui::Ui, string: &str, _wrap_width: f32| {
                         ui.fonts().layout_no_wrap(
                             string.to_string(),
                             egui::TextStyle::Monospace,
-                            Color32::WHITE,
+                            theme.gutter_text,
                         )
                     };
                     ui.horizontal_top(|ui| {
-                        //let mut lines_str = get_line_num_str(text_buf.lines().count());
-
                         if config.line_nums {
-                            let num_tool = match line_nums {
-                                Some(x) => x,
-                                None => {
-                                    *active_popup = Popup::Error;
-                                    *err_msg = Some("This shouldn't happend".into());
-                                    return;
-                                }
-                            };
-                            let current_line_count = text_buf.lines().count();
-                            let mut lines_str = num_tool.generate(current_line_count);
-                            ui.add(
-                                egui::TextEdit::multiline(&mut lines_str)
-                                    //.desired_width(config.get_font_size() * 2.7)
-                                    .desired_width(0.0)
-                                    .code_editor()
-                                    .frame(false)
-                                    .interactive(false)
-                                    .layouter(&mut layouter),
-                            );
+                            let current_line_count = text_buf.line_count();
+                            let mut lines_str =
+                                line_nums.generate(first_row..last_row + 1, current_line_count);
+                            ui.vertical(|ui| {
+                                let background = ui.painter().add(egui::Shape::Noop);
+                                ui.add_space(first_row as f32 * row_height);
+                                ui.add(
+                                    egui::TextEdit::multiline(&mut lines_str)
+                                        .desired_width(0.0)
+                                        .code_editor()
+                                        .frame(false)
+                                        .interactive(false)
+                                        .layouter(&mut gutter_layouter),
+                                );
+                                let rect = egui::Rect::from_x_y_ranges(
+                                    ui.min_rect().x_range(),
+                                    ui.clip_rect().y_range(),
+                                );
+                                ui.painter().set(
+                                    background,
+                                    egui::Shape::rect_filled(rect, 0.0, theme.gutter_background),
+                                );
+                            });
                         }
                         ui.separator();
+                        //  Measured out here, the horizontal scroll area is endlessly wide
+                        let wrap_width = match config.word_wrap {
+                            WordWrap::Off => f32::INFINITY,
+                            WordWrap::Window => ui.available_width() - 2.0 * TEXT_EDIT_MARGIN,
+                            WordWrap::Column(column) => {
+                                column as f32
+                                    * ui.fonts().glyph_width(egui::TextStyle::Monospace, ' ')
+                            }
+                        };
                         egui::ScrollArea::horizontal().show(ui, |ui| {
-                            let editor = ui.add_sized(
-                                ui.available_size(),
-                                egui::TextEdit::multiline(text_buf)
-                                    .code_editor()
-                                    .lock_focus(true)
-                                    .frame(false)
-                                    .id(egui::Id::new("editor"))
-                                    .desired_width(f32::INFINITY)
-                                    .layouter(&mut layouter),
-                            );
-                            if editor.changed() {
+                            //  Keep the laid out text around for drawing on top of it
+                            let mut editor_galley = None;
+                            let mut layouter = |ui: &egui::Ui, string: &str, _wrap_width: f32| {
+                                let mut layout_job =
+                                    highlight::highlight(ui.ctx(), language, string, theme.syntax);
+                                layout_job.wrap_width = wrap_width;
+                                let galley = ui.fonts().layout_job(layout_job);
+                                editor_galley = Some(galley.clone());
+                                galley
+                            };
+                            let tab = match config.insert_spaces {
+                                true => " ".repeat(config.tab_width as usize),
+                                false => "\t".to_string(),
+                            };
+                            //  The editor only sees the lines laid out, so its cursor
+                            //  is moved into them for the frame, and back after
+                            let saved_state = egui::TextEdit::load_state(ctx, editor_id);
+                            let mut shown_cursor = None;
+                            if let Some(mut state) = saved_state.clone() {
+                                if let Some(range) = state.ccursor_range() {
+                                    let to_shown = |index: usize| {
+                                        egui::epaint::text::cursor::CCursor::new(
+                                            index.clamp(shown_chars.start, shown_chars.end)
+                                                - shown_chars.start,
+                                        )
+                                    };
+                                    let range = egui::text_edit::CCursorRange::two(
+                                        to_shown(range.secondary.index),
+                                        to_shown(range.primary.index),
+                                    );
+                                    state.set_ccursor_range(Some(range));
+                                    egui::TextEdit::store_state(ctx, editor_id, state);
+                                    shown_cursor = Some(range);
+                                }
+                            }
+
+                            let size = ui.available_size();
+                            ui.add_space(first_line as f32 * row_height);
+                            let editor = match *read_only {
+                                true => ui.add_sized(
+                                    size,
+                                    CodeShare::editor_widget(
+                                        &mut text_buf.slice(shown.clone()).as_str(),
+                                        editor_id,
+                                        &mut layouter,
+                                    ),
+                                ),
+                                false => {
+                                    let mut view =
+                                        EditorView::new(&mut *text_buf, shown.clone(), &tab);
+                                    //  The editor applies its own undo on Ctrl+Z, which stays
+                                    //  out of the document history and is reverted by it below
+                                    if !(undo_requested || redo_requested) {
+                                        view = view.recording(&mut *history, ctx.input().time);
+                                    }
+                                    ui.add_sized(
+                                        size,
+                                        CodeShare::editor_widget(
+                                            &mut view,
+                                            editor_id,
+                                            &mut layouter,
+                                        ),
+                                    )
+                                }
+                            };
+                            ui.add_space(lines_after as f32 * row_height);
+
+                            if let Some(mut state) = egui::TextEdit::load_state(ctx, editor_id) {
+                                if let Some(range) = state.ccursor_range() {
+                                    let moved = match shown_cursor {
+                                        Some(shown) => {
+                                            (shown.primary.index, shown.secondary.index)
+                                                != (range.primary.index, range.secondary.index)
+                                        }
+                                        None => true,
+                                    };
+                                    match (moved || editor.changed(), saved_state) {
+                                        (false, Some(saved_state)) => state = saved_state,
+                                        _ => {
+                                            let from_shown = |index: usize| {
+                                                egui::epaint::text::cursor::CCursor::new(
+                                                    shown_chars.start + index,
+                                                )
+                                            };
+                                            state.set_ccursor_range(Some(
+                                                egui::text_edit::CCursorRange::two(
+                                                    from_shown(range.secondary.index),
+                                                    from_shown(range.primary.index),
+                                                ),
+                                            ));
+                                        }
+                                    }
+                                    egui::TextEdit::store_state(ctx, editor_id, state);
+                                }
+                            }
+                            if editor.changed() && !(undo_requested || redo_requested) {
                                 file_status.set_unsaved(true);
+                                //  Keep match offsets valid for the edited text
+                                if !finder.query_buf.is_empty() {
+                                    finder.refresh(&text_buf.to_string());
+                                }
+                            }
+                            line_nums.set_wrapped(match (config.word_wrap, &editor_galley) {
+                                (WordWrap::Off, _) | (_, None) => None,
+                                (_, Some(galley)) => {
+                                    Some(CodeShare::wrapped_rows(galley, first_line))
+                                }
+                            });
+                            let moved_rows =
+                                line_nums.row_of_line(top_line) as f32 - top_line_row as f32;
+                            if moved_rows != 0.0 {
+                                *scroll_to = Some(viewport.min.y + moved_rows * row_height);
+                                ctx.request_repaint();
+                            }
+                            #[cfg(not(target_arch = "wasm32"))]
+                            if let (Some(session), Some(galley)) = (collab.as_ref(), editor_galley)
+                            {
+                                CodeShare::paint_remote_cursors(
+                                    ui,
+                                    editor.rect.min,
+                                    &galley,
+                                    shown_chars.clone(),
+                                    session,
+                                );
                             }
                             if *switch_to_editor {
                                 editor.request_focus();
                                 *switch_to_editor = false;
                             }
+                            //  Remembered for the session
+                            if let Some(cursor_range) = egui::TextEdit::load_state(ctx, editor_id)
+                                .and_then(|state| state.ccursor_range())
+                            {
+                                *last_cursor = cursor_range.primary.index;
+                            }
                         });
                     });
                 });
             });
+
+        if undo_requested || redo_requested {
+            let cursor = match undo_requested {
+                true => history.undo(text_buf),
+                false => history.redo(text_buf),
+            };
+            if let Some(byte_index) = cursor {
+                file_status.set_unsaved(true);
+                if !finder.query_buf.is_empty() {
+                    finder.refresh(&text_buf.to_string());
+                }
+                let cursor_pos = text_buf.pos_at_byte(byte_index);
+                CodeShare::move_cursor(ctx, editor_id, cursor_pos, None, switch_to_editor);
+            }
+        }
     }
 }
 
 impl CodeShare {
-    fn change_app_font_size(ctx: &egui::CtxRef, size: f32) {
-        let mut fonts = egui::FontDefinitions::default();
-        fonts.family_and_size.insert(
-            egui::TextStyle::Monospace,
-            (egui::FontFamily::Monospace, size),
-        );
+    /// A menu button for `command`, with its keys shown on hover.
+    fn command_button(ui: &mut egui::Ui, keymap: &Keymap, command: Command, enabled: bool) -> bool {
+        let button = ui.add_enabled(enabled, egui::widgets::Button::new(command.name()));
+        match keymap.shortcut(command) {
+            Some(shortcut) => button.on_hover_text(shortcut),
+            None => button,
+        }
+        .clicked()
+    }
+
+    /// Off, window or column word wrap, for the View menu and Preferences.
+    fn wrap_editor(ui: &mut egui::Ui, word_wrap: &mut WordWrap) {
+        ui.radio_value(word_wrap, WordWrap::Off, "Off");
+        ui.radio_value(word_wrap, WordWrap::Window, "At the window edge");
+        let column = matches!(word_wrap, WordWrap::Column(_));
+        if ui.radio(column, "At column").clicked() && !column {
+            *word_wrap = WordWrap::Column(80);
+        }
+        if let WordWrap::Column(column) = word_wrap {
+            ui.add(egui::DragValue::new(column).clamp_range(1..=10_000));
+        }
+    }
+
+    /// The editor font, or the UI font with `editor` unset, and buttons to change it.
+    fn font_picker(
+        ui: &mut egui::Ui,
+        config: &AppConfig,
+        editor: bool,
+        commands: &mut Vec<Command>,
+    ) {
+        let (font, default) = match editor {
+            true => (&config.editor_font, Command::DefaultEditorFont),
+            false => (&config.ui_font, Command::DefaultUiFont),
+        };
+        ui.horizontal(|ui| {
+            ui.label(fonts::font_name(font));
+            if font.is_some() && ui.button("Default").clicked() {
+                commands.push(default);
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            if ui.button("Choose...").clicked() {
+                commands.push(match editor {
+                    true => Command::ChooseEditorFont,
+                    false => Command::ChooseUiFont,
+                });
+            }
+        });
+    }
+
+    /// The built-in themes and the theme file in use, with a button to open another.
+    fn theme_picker(
+        ui: &mut egui::Ui,
+        _keymap: &Keymap,
+        config: &AppConfig,
+        commands: &mut Vec<Command>,
+    ) {
+        for (built_in, command) in ThemeChoice::BUILT_IN.iter().zip([
+            Command::ThemeDark,
+            Command::ThemeLight,
+            Command::ThemeHighContrast,
+        ]) {
+            if ui
+                .radio(config.theme == *built_in, built_in.name())
+                .clicked()
+            {
+                commands.push(command);
+            }
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            //  Clicking a theme file again picks up changes to it
+            if let ThemeChoice::File(_) = &config.theme {
+                if ui.radio(true, config.theme.name()).clicked() {
+                    commands.push(Command::ReloadThemeFile);
+                }
+            }
+            if CodeShare::command_button(ui, _keymap, Command::OpenThemeFile, true) {
+                commands.push(Command::OpenThemeFile);
+            }
+        }
+    }
+
+    /// Select the next or previous match, or put the cursor at the start
+    /// when there are none.
+    fn step_find(
+        ctx: &egui::CtxRef,
+        editor_id: egui::Id,
+        text_buf: &TextBuf,
+        finder: &mut FindTools,
+        forward: bool,
+        switch_to_editor: &mut bool,
+    ) {
+        if finder.number_of_matches() == 0 {
+            CodeShare::move_cursor(ctx, editor_id, TextPos::default(), None, switch_to_editor);
+            return;
+        }
+        if finder.initial_click_made {
+            match forward {
+                true => finder.selected_loc_inc(),
+                false => finder.selected_loc_dec(),
+            }
+        }
+        CodeShare::highlight_text(ctx, editor_id, text_buf, finder, switch_to_editor);
+    }
+
+    /// Switch to the theme `choice`, keeping the current one if it can't be loaded.
+    fn apply_theme(
+        ctx: &egui::CtxRef,
+        choice: ThemeChoice,
+        config: &mut AppConfig,
+        theme: &mut Theme,
+        err_msg: &mut Option<String>,
+        active_popup: &mut Popup,
+    ) {
+        match choice.load() {
+            Ok(loaded) => {
+                loaded.apply(ctx);
+                *theme = loaded;
+                config.theme = choice;
+            }
+            Err(e) => {
+                *err_msg = Some(format!("Could not load theme {}", e));
+                *active_popup = Popup::Error;
+            }
+        }
+    }
+
+    /// Set the fonts and size in `config`, reporting fonts that can't be read.
+    fn apply_fonts(
+        ctx: &egui::CtxRef,
+        config: &mut AppConfig,
+        err_msg: &mut Option<String>,
+        active_popup: &mut Popup,
+    ) {
+        let (fonts, errors) = fonts::load(config);
         ctx.set_fonts(fonts);
+        if !errors.is_empty() {
+            *err_msg = Some(format!("Could not load font {}", errors.join("\n")));
+            *active_popup = Popup::Error;
+        }
+    }
+
+    /// The rows each line of `galley`, starting at `first_line`, starts on.
+    fn wrapped_rows(galley: &egui::Galley, first_line: usize) -> WrappedRows {
+        let mut line_starts = vec![0];
+        line_starts.extend(
+            galley
+                .rows
+                .iter()
+                .enumerate()
+                .filter(|(_, row)| row.ends_with_newline)
+                .map(|(index, _)| index + 1),
+        );
+        WrappedRows {
+            first_line,
+            line_starts,
+            row_count: galley.rows.len(),
+        }
+    }
+
+    /// Whether this frame's input edits at the caret or moves it.
+    fn edits_at_cursor(input: &egui::InputState) -> bool {
+        use egui::Key::*;
+        input.events.iter().any(|event| match event {
+            egui::Event::Text(_) | egui::Event::Cut => true,
+            egui::Event::Key {
+                key, pressed: true, ..
+            } => matches!(
+                key,
+                ArrowDown
+                    | ArrowLeft
+                    | ArrowRight
+                    | ArrowUp
+                    | Backspace
+                    | Delete
+                    | End
+                    | Enter
+                    | Home
+                    | PageDown
+                    | PageUp
+                    | Tab
+            ),
+            _ => false,
+        })
+    }
+
+    fn editor_widget<'t>(
+        text: &'t mut dyn egui::TextBuffer,
+        editor_id: egui::Id,
+        layouter: &'t mut dyn FnMut(&egui::Ui, &str, f32) -> std::sync::Arc<egui::Galley>,
+    ) -> egui::TextEdit<'t> {
+        egui::TextEdit::multiline(text)
+            .code_editor()
+            .lock_focus(true)
+            .frame(false)
+            .id(editor_id)
+            .desired_width(f32::INFINITY)
+            .layouter(layouter)
+    }
+
+    /// Queue up what a crashed editor left in the swap files of files
+    /// opened since the last look, to be offered for recovery.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn find_swap_orphans(&mut self) {
+        for doc in self.docs.iter() {
+            if let Some(path) = doc.file_status.get_path() {
+                if !self.swap_checked.contains(path) {
+                    self.swap_checked.insert(path.to_path_buf());
+                    self.swap_orphans.extend(swap::orphan_of(path));
+                }
+            }
+        }
+    }
+
+    /// Write unsaved buffers to their swap files when `config.autosave` says so,
+    /// and drop the swap files of buffers that have been saved.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn autosave(&mut self, ctx: &egui::CtxRef) {
+        let now = ctx.input().time;
+        let has_pointer = ctx.input().pointer.has_pointer();
+        let left_window = self.had_pointer && !has_pointer;
+        self.had_pointer = has_pointer;
+        for doc in self.docs.iter_mut() {
+            if !doc.file_status.is_unsaved() || self.config.autosave == Autosave::Off {
+                doc.swap.remove();
+                continue;
+            }
+            let due = match self.config.autosave {
+                Autosave::Off => false,
+                Autosave::Every(secs) => now - doc.swap.last_write >= secs as f64,
+                Autosave::FocusLost => left_window,
+            };
+            if due {
+                let path = doc.file_status.get_path();
+                if let Err(e) = doc.swap.write(path, &doc.text_buf.to_string(), now) {
+                    self.status_msg = Some(format!("Autosave failed: {}", e));
+                }
+            }
+        }
+    }
+
+    /// Reload files that changed on disk if their buffer is clean, otherwise
+    /// queue them up to ask the user which version to keep. A deleted file's
+    /// buffer is kept, marked unsaved.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn watch_files(&mut self, ctx: &egui::CtxRef) {
+        let now = ctx.input().time;
+        if now - self.last_watch < TICK_SECS as f64 {
+            return;
+        }
+        self.last_watch = now;
+        self.reload_settings(ctx);
+        for doc in self.docs.iter_mut() {
+            let editor_id = doc.editor_id();
+            if !doc.file_status.changed_on_disk() || self.changed_on_disk.contains(&editor_id) {
+                continue;
+            }
+            let name = doc.file_status.get_file_name();
+            if doc.file_status.disk_modified().is_none() {
+                //  Deleted or moved away, the buffer is all that's left
+                doc.file_status.sync_modified();
+                doc.file_status.set_unsaved(true);
+                self.status_msg = Some(format!("{} was removed from disk", name));
+            } else if doc.file_status.is_unsaved() {
+                self.changed_on_disk.push(editor_id);
+            } else {
+                match doc.file_status.reload() {
+                    Ok(contents) => {
+                        doc.reload_text(contents);
+                        let cursor = doc.text_buf.pos_at_char(doc.cursor);
+                        CodeShare::move_cursor(
+                            ctx,
+                            editor_id,
+                            cursor,
+                            None,
+                            &mut self.switch_to_editor,
+                        );
+                        self.status_msg = Some(format!("Reloaded {}", name));
+                    }
+                    Err(e) => self.status_msg = Some(format!("Could not reload {}: {}", name, e)),
+                }
+            }
+        }
+    }
+
+    /// Read the settings file over the settings saved with the app state.
+    /// Without one, a settings file is written on the next frame.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn load_settings(&mut self) {
+        let path = match AppConfig::path().filter(|path| path.exists()) {
+            Some(path) => path,
+            None => return,
+        };
+        match AppConfig::from_file(&path) {
+            Ok(config) => self.config = config,
+            Err(e) => {
+                self.err_msg = Some(format!("Could not load settings {}", e));
+                self.active_popup = Popup::Error;
+            }
+        }
+        //  A file with mistakes is left alone until it's fixed
+        self.settings_modified = file::modified_time(&path);
+        self.saved_settings = Some(self.config.clone());
+    }
+
+    /// Write settings changed in the app to the settings file.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save_settings(&mut self) {
+        if self.saved_settings.as_ref() == Some(&self.config) {
+            return;
+        }
+        if let Some(path) = AppConfig::path() {
+            if let Err(e) = self.config.save(&path) {
+                self.status_msg = Some(format!("Could not save settings {}", e));
+            }
+            self.settings_modified = file::modified_time(&path);
+        }
+        self.saved_settings = Some(self.config.clone());
     }
 
-    fn highlight_text(ctx: &egui::CtxRef, finder: &mut FindTools, switch_to_editor: &mut bool) {
-        if let Some(mut editor_state) = egui::TextEdit::load_state(ctx, egui::Id::new("editor")) {
-            if let Some((start_index, len)) = finder.get_current_match() {
-                let min_curs = egui::epaint::text::cursor::CCursor::new(start_index);
-                let max_curs = egui::epaint::text::cursor::CCursor::new(start_index + len);
+    /// Pick up changes made to the settings file outside the app.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn reload_settings(&mut self, ctx: &egui::CtxRef) {
+        let path = match AppConfig::path() {
+            Some(path) => path,
+            None => return,
+        };
+        let modified = file::modified_time(&path);
+        if modified.is_none() || modified == self.settings_modified {
+            return;
+        }
+        self.settings_modified = modified;
+        let config = match AppConfig::from_file(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                self.err_msg = Some(format!("Could not load settings {}", e));
+                self.active_popup = Popup::Error;
+                return;
+            }
+        };
+        let fonts_changed = config.get_font_size() != self.config.get_font_size()
+            || config.editor_font != self.config.editor_font
+            || config.ui_font != self.config.ui_font;
+        let theme_changed = config.theme != self.config.theme;
+        self.config = config;
+        if theme_changed {
+            match self.config.theme.load() {
+                Ok(theme) => {
+                    theme.apply(ctx);
+                    self.theme = theme;
+                }
+                Err(e) => {
+                    self.err_msg = Some(format!("Could not load theme {}", e));
+                    self.active_popup = Popup::Error;
+                }
+            }
+        }
+        if fonts_changed {
+            CodeShare::apply_fonts(
+                ctx,
+                &mut self.config,
+                &mut self.err_msg,
+                &mut self.active_popup,
+            );
+        }
+        //  Fonts that failed to load aren't written back over the file
+        self.saved_settings = Some(self.config.clone());
+        self.status_msg = Some("Settings reloaded".to_string());
+    }
+
+    /// Put the buffer from a left over swap file back in a tab, unsaved.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn recover_swap(docs: &mut Vec<Document>, active_doc: &mut usize, orphan: &swap::Orphan) {
+        let open_index = orphan.file_path.as_deref().and_then(|path| {
+            docs.iter()
+                .position(|doc| doc.file_status.get_path() == Some(path))
+        });
+        match open_index {
+            Some(index) => *active_doc = index,
+            None => {
+                let mut doc = Document::default();
+                if let Some(path) = &orphan.file_path {
+                    doc.file_status.restore(path.clone(), None);
+                    doc.file_status.sync_modified();
+                }
+                CodeShare::add_doc(docs, active_doc, doc);
+            }
+        }
+        let doc = &mut docs[*active_doc];
+        doc.load_text(orphan.contents.clone());
+        doc.file_status.set_unsaved(true);
+        orphan.discard();
+    }
+
+    fn show_diff(ui: &mut egui::Ui, diff: &Option<String>) {
+        if let Some(diff) = diff {
+            ui.separator();
+            egui::ScrollArea::vertical()
+                .max_height(300.0)
+                .show(ui, |ui| ui.monospace(diff));
+        }
+    }
+
+    /// Read the file at `path` into a new tab.
+    fn open_doc(
+        docs: &mut Vec<Document>,
+        active_doc: &mut usize,
+        path: &Path,
+    ) -> Result<(), String> {
+        let mut doc = Document::default();
+        match doc.file_status.open_path(path) {
+            Ok(contents) => doc.load_text(contents),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        }
+        CodeShare::add_doc(docs, active_doc, doc);
+        Ok(())
+    }
+
+    /// Open `doc` in a tab and switch to it.
+    fn add_doc(docs: &mut Vec<Document>, active_doc: &mut usize, doc: Document) {
+        //  Reuse an untouched untitled tab instead of stacking empty ones
+        match docs[*active_doc].is_pristine() {
+            true => docs[*active_doc] = doc,
+            false => {
+                docs.push(doc);
+                *active_doc = docs.len() - 1;
+            }
+        }
+    }
+
+    /// Ask to close a tab, prompting first if it has unsaved changes.
+    fn request_close(
+        docs: &mut Vec<Document>,
+        active_doc: &mut usize,
+        closing_doc: &mut Option<usize>,
+        active_popup: &mut Popup,
+        index: usize,
+    ) {
+        match docs[index].file_status.is_unsaved() {
+            false => CodeShare::close_doc(docs, active_doc, index),
+            true => {
+                *closing_doc = Some(index);
+                *active_popup = Popup::FileNotSavedClose;
+            }
+        }
+    }
+
+    fn close_doc(docs: &mut Vec<Document>, active_doc: &mut usize, index: usize) {
+        //  Closed without saving, so there's nothing left to recover
+        #[cfg(not(target_arch = "wasm32"))]
+        docs[index].swap.remove();
+        docs.remove(index);
+        if docs.is_empty() {
+            docs.push(Document::default());
+        }
+        if *active_doc > index || *active_doc >= docs.len() {
+            *active_doc = active_doc.saturating_sub(1);
+        }
+    }
+
+    fn highlight_text(
+        ctx: &egui::CtxRef,
+        editor_id: egui::Id,
+        text: &TextBuf,
+        finder: &mut FindTools,
+        switch_to_editor: &mut bool,
+    ) {
+        if let Some(mut editor_state) = egui::TextEdit::load_state(ctx, editor_id) {
+            if let Some((start, end)) = finder.get_current_match_pos(text) {
+                let min_curs = egui::epaint::text::cursor::CCursor::new(start.char);
+                let max_curs = egui::epaint::text::cursor::CCursor::new(end.char);
                 editor_state.set_ccursor_range(Some(egui::text_edit::CCursorRange::two(
                     min_curs, max_curs,
                 )));
-                egui::TextEdit::store_state(ctx, egui::Id::new("editor"), editor_state);
+                egui::TextEdit::store_state(ctx, editor_id, editor_state);
                 *switch_to_editor = true;
             }
         }
     }
 
-    fn highlight_text_no_switch(ctx: &egui::CtxRef, finder: &mut FindTools) {
-        if let Some(mut editor_state) = egui::TextEdit::load_state(ctx, egui::Id::new("editor")) {
-            if let Some((start_index, len)) = finder.get_current_match() {
-                let min_curs = egui::epaint::text::cursor::CCursor::new(start_index);
-                let max_curs = egui::epaint::text::cursor::CCursor::new(start_index + len);
+    fn highlight_text_no_switch(
+        ctx: &egui::CtxRef,
+        editor_id: egui::Id,
+        text: &TextBuf,
+        finder: &mut FindTools,
+    ) {
+        if let Some(mut editor_state) = egui::TextEdit::load_state(ctx, editor_id) {
+            if let Some((start, end)) = finder.get_current_match_pos(text) {
+                let min_curs = egui::epaint::text::cursor::CCursor::new(start.char);
+                let max_curs = egui::epaint::text::cursor::CCursor::new(end.char);
                 editor_state.set_ccursor_range(Some(egui::text_edit::CCursorRange::two(
                     min_curs, max_curs,
                 )));
-                egui::TextEdit::store_state(ctx, egui::Id::new("editor"), editor_state);
+                egui::TextEdit::store_state(ctx, editor_id, editor_state);
                 finder.initial_click_made = false; // Don't change this property with .get_current_match()
             }
         }
     }
 
-    fn move_cursor(ctx: &egui::CtxRef, start: usize, end: Option<usize>, switch_to_editor: &mut bool) {
-        if let Some(mut editor_state) = egui::TextEdit::load_state(ctx, egui::Id::new("editor")) {
-            let min_curs = egui::epaint::text::cursor::CCursor::new(start);
-            if let Some(end) = end {
-                let max_curs = egui::epaint::text::cursor::CCursor::new(end);
-                editor_state.set_ccursor_range(Some(egui::text_edit::CCursorRange::two(
-                    min_curs, max_curs,
-                )));
-            } else {
-                editor_state.set_ccursor_range(Some(egui::text_edit::CCursorRange::one(min_curs)));
-            }
-            egui::TextEdit::store_state(ctx, egui::Id::new("editor"), editor_state);
-            *switch_to_editor = true;
+    fn move_cursor(
+        ctx: &egui::CtxRef,
+        editor_id: egui::Id,
+        start: TextPos,
+        end: Option<TextPos>,
+        switch_to_editor: &mut bool,
+    ) {
+        //  The editor may not have been shown yet, e.g. for files opened on launch
+        let mut editor_state = egui::TextEdit::load_state(ctx, editor_id).unwrap_or_default();
+        let min_curs = egui::epaint::text::cursor::CCursor::new(start.char);
+        if let Some(end) = end {
+            let max_curs = egui::epaint::text::cursor::CCursor::new(end.char);
+            editor_state
+                .set_ccursor_range(Some(egui::text_edit::CCursorRange::two(min_curs, max_curs)));
+        } else {
+            editor_state.set_ccursor_range(Some(egui::text_edit::CCursorRange::one(min_curs)));
+        }
+        egui::TextEdit::store_state(ctx, editor_id, editor_state);
+        *switch_to_editor = true;
+    }
+
+    /// The editor's selected range, in text order, if anything is selected.
+    fn get_selection(
+        ctx: &egui::CtxRef,
+        editor_id: egui::Id,
+        text: &TextBuf,
+    ) -> Option<(TextPos, TextPos)> {
+        let cursor_range = egui::TextEdit::load_state(ctx, editor_id)?.ccursor_range()?;
+        let primary = text.pos_at_char(cursor_range.primary.index);
+        let secondary = text.pos_at_char(cursor_range.secondary.index);
+        match primary.char.cmp(&secondary.char) {
+            std::cmp::Ordering::Equal => None,
+            std::cmp::Ordering::Less => Some((primary, secondary)),
+            std::cmp::Ordering::Greater => Some((secondary, primary)),
         }
     }
 
-    fn _get_cursor_index(ctx: &egui::CtxRef) -> Option<usize> {
-        if let Some(editor_state) = egui::TextEdit::load_state(ctx, egui::Id::new("editor")) {
+    fn get_cursor_pos(ctx: &egui::CtxRef, editor_id: egui::Id, text: &TextBuf) -> Option<TextPos> {
+        if let Some(editor_state) = egui::TextEdit::load_state(ctx, editor_id) {
             if let Some(cursor_range) = editor_state.ccursor_range() {
-                return Some(cursor_range.secondary.index)
+                return Some(text.pos_at_char(cursor_range.primary.index));
             }
         }
         None
     }
+
+    /// Exchange edits with the other people in `doc`'s live session, keeping
+    /// the local selection in place as their edits come in.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn sync_collab(ctx: &egui::CtxRef, doc: &mut Document, status_msg: &mut Option<String>) {
+        let editor_id = doc.editor_id();
+        let session = match &mut doc.collab {
+            Some(session) => session,
+            None => return,
+        };
+        let editor_state = egui::TextEdit::load_state(ctx, editor_id);
+        let applied = session.sync(&mut doc.text_buf);
+        if !applied.is_empty() {
+            doc.file_status.set_unsaved(true);
+            doc.history.record_remote(&doc.text_buf);
+            if !doc.finder.query_buf.is_empty() {
+                doc.finder.refresh(&doc.text_buf.to_string());
+            }
+            if let Some(mut editor_state) = editor_state {
+                if let Some(range) = editor_state.ccursor_range() {
+                    let follow = |index| {
+                        applied
+                            .iter()
+                            .fold(index, |index, op| op.transform_index(index))
+                    };
+                    let primary =
+                        egui::epaint::text::cursor::CCursor::new(follow(range.primary.index));
+                    let secondary =
+                        egui::epaint::text::cursor::CCursor::new(follow(range.secondary.index));
+                    editor_state.set_ccursor_range(Some(egui::text_edit::CCursorRange::two(
+                        secondary, primary,
+                    )));
+                    egui::TextEdit::store_state(ctx, editor_id, editor_state);
+                    //  Moved along, not by someone else, so the view stays put
+                    doc.cursor = follow(doc.cursor);
+                }
+            }
+        }
+        if let Some(cursor_pos) = CodeShare::get_cursor_pos(ctx, editor_id, &doc.text_buf) {
+            session.set_cursor(cursor_pos.char);
+        }
+        if let Some(e) = session.error.clone() {
+            *status_msg = Some(format!("Left session \"{}\": {}", session.session_name, e));
+            doc.collab = None;
+        }
+    }
+
+    /// Draw collaborators' cursors, with their names, over the editor showing
+    /// `galley`, the chars `shown_chars` of the text.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn paint_remote_cursors(
+        ui: &egui::Ui,
+        origin: egui::Pos2,
+        galley: &egui::Galley,
+        shown_chars: std::ops::Range<usize>,
+        session: &CollabSession,
+    ) {
+        let painter = ui.painter();
+        for (client_id, cursor) in session.remote_cursors.iter() {
+            if !(shown_chars.start..=shown_chars.end).contains(&cursor.pos) {
+                continue;
+            }
+            let (r, g, b) = CollabSession::cursor_color(*client_id);
+            let color = Color32::from_rgb(r, g, b);
+            let ccursor = egui::epaint::text::cursor::CCursor::new(cursor.pos - shown_chars.start);
+            let rect = galley
+                .pos_from_cursor(&galley.from_ccursor(ccursor))
+                .translate(origin.to_vec2());
+            painter.line_segment([rect.left_top(), rect.left_bottom()], (2.0, color));
+            painter.text(
+                rect.left_top(),
+                egui::Align2::LEFT_BOTTOM,
+                &cursor.name,
+                egui::TextStyle::Small,
+                color,
+            );
+        }
+    }
 }
 
 #[derive(PartialEq)]
@@ -567,10 +2869,25 @@ enum Popup {
     OpenFile,
     SaveFile,
     SaveAs,
-    FileNotSavedNew,
-    FileNotSavedOpen,
+    FileNotSavedClose,
+    ShareLink,
+    #[cfg(not(target_arch = "wasm32"))]
+    PublishPaste,
+    #[cfg(not(target_arch = "wasm32"))]
+    OpenPaste,
+    #[cfg(not(target_arch = "wasm32"))]
+    Collaborate,
+    ChangedOnDisk,
+    OverwriteChanged,
+    OpenUnusual,
+    #[cfg(not(target_arch = "wasm32"))]
+    RecoverSwap,
     Error,
     Find,
+    GoToLine,
+    KeyboardShortcuts,
+    CommandPalette,
+    Preferences,
     FindAndReplace,
     None,
 }
@@ -1,7 +1,65 @@
+//! Settings, kept in `settings.toml` in the config directory and nowhere
+//! else, so the file always wins. Only the web build, which has no config
+//! directory, keeps them with the rest of the app state. Each key is listed
+//! in `SETTINGS`.
+
+use crate::theme::ThemeChoice;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Keys of the settings file, with what each should be.
+const SETTINGS: [(&str, &str); 13] = [
+    ("font_size", "a size from 1 to 200"),
+    (
+        "editor_font",
+        "the path of a TTF or OTF file, or \"\" for the built-in font",
+    ),
+    (
+        "ui_font",
+        "the path of a TTF or OTF file, or \"\" for the built-in font",
+    ),
+    (
+        "theme",
+        "\"dark\", \"light\", \"high-contrast\" or the path of a theme file",
+    ),
+    ("line_numbers", "true or false"),
+    ("tab_width", "a number of spaces from 1 to 16"),
+    ("insert_spaces", "true or false"),
+    ("word_wrap", "\"off\", \"window\" or a column number"),
+    (
+        "autosave",
+        "\"off\", \"focus-lost\" or a number of seconds from 5 to 3600",
+    ),
+    ("backup", "\"off\", \"first-save\" or \"every-save\""),
+    ("paste_server", "a URL"),
+    ("relay_url", "a URL"),
+    ("user_name", "a name"),
+];
+
 #[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AppConfig {
     font_size: u32,
     pub line_nums: bool,
+    /// Spaces the Tab key inserts when `insert_spaces` is set. egui always
+    /// draws a tab character four spaces wide.
+    pub tab_width: u32,
+    pub insert_spaces: bool,
+    pub word_wrap: WordWrap,
+    /// Base URL of the paste server used by Publish/Open from Server.
+    pub paste_server: String,
+    /// WebSocket URL of the relay used for live editing sessions.
+    pub relay_url: String,
+    /// Name shown to collaborators next to this user's cursor.
+    pub user_name: String,
+    /// When unsaved buffers are written to their swap files.
+    pub autosave: Autosave,
+    /// When saving keeps the previous version of a file.
+    pub backup: Backup,
+    pub theme: ThemeChoice,
+    /// TTF or OTF files used instead of egui's fonts, see `fonts`.
+    pub ui_font: Option<PathBuf>,
+    pub editor_font: Option<PathBuf>,
 }
 
 impl Default for AppConfig {
@@ -9,12 +67,23 @@ impl Default for AppConfig {
         AppConfig {
             font_size: 15,
             line_nums: true,
+            tab_width: 4,
+            insert_spaces: false,
+            word_wrap: WordWrap::default(),
+            paste_server: "http://127.0.0.1:8090".to_string(),
+            relay_url: "ws://127.0.0.1:8091".to_string(),
+            user_name: std::env::var("USER").unwrap_or_else(|_| "guest".to_string()),
+            autosave: Autosave::default(),
+            backup: Backup::default(),
+            theme: ThemeChoice::default(),
+            ui_font: None,
+            editor_font: None,
         }
     }
 }
 
 impl AppConfig {
-    pub fn _set_font_size(&mut self, size: u32) {
+    pub fn set_font_size(&mut self, size: u32) {
         self.font_size = size;
     }
     pub fn get_font_size(&self) -> f32 {
@@ -31,49 +100,286 @@ impl AppConfig {
         }
     }
 
-    pub fn _set_line_nums(&mut self, state: bool) {
-        self.line_nums = state;
+    /// Where the settings file is kept.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join("settings.toml"))
+    }
+
+    pub fn from_file(path: &Path) -> Result<AppConfig, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        AppConfig::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Settings from the TOML text of a settings file, with the defaults
+    /// for anything left out.
+    pub fn parse(text: &str) -> Result<AppConfig, String> {
+        use toml::Value;
+        let table: toml::Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| e.message().to_string())?;
+        let mut config = AppConfig::default();
+        for (key, value) in table.iter() {
+            let font_path = |path: &str| match path.is_empty() {
+                true => None,
+                false => Some(PathBuf::from(path)),
+            };
+            match (key.as_str(), value) {
+                ("font_size", Value::Integer(size)) if (1..=200).contains(size) => {
+                    config.font_size = *size as u32
+                }
+                ("editor_font", Value::String(path)) => config.editor_font = font_path(path),
+                ("ui_font", Value::String(path)) => config.ui_font = font_path(path),
+                ("theme", Value::String(theme)) => {
+                    config.theme = match theme.as_str() {
+                        "dark" => ThemeChoice::Dark,
+                        "light" => ThemeChoice::Light,
+                        "high-contrast" => ThemeChoice::HighContrast,
+                        path => ThemeChoice::File(PathBuf::from(path)),
+                    }
+                }
+                ("line_numbers", Value::Boolean(on)) => config.line_nums = *on,
+                ("tab_width", Value::Integer(width)) if (1..=16).contains(width) => {
+                    config.tab_width = *width as u32
+                }
+                ("insert_spaces", Value::Boolean(on)) => config.insert_spaces = *on,
+                ("word_wrap", Value::String(wrap)) if wrap == "off" => {
+                    config.word_wrap = WordWrap::Off
+                }
+                ("word_wrap", Value::String(wrap)) if wrap == "window" => {
+                    config.word_wrap = WordWrap::Window
+                }
+                ("word_wrap", Value::Integer(column)) if (1..=10_000).contains(column) => {
+                    config.word_wrap = WordWrap::Column(*column as u32)
+                }
+                ("autosave", Value::String(when)) if when == "off" => {
+                    config.autosave = Autosave::Off
+                }
+                ("autosave", Value::String(when)) if when == "focus-lost" => {
+                    config.autosave = Autosave::FocusLost
+                }
+                ("autosave", Value::Integer(secs)) if (5..=3600).contains(secs) => {
+                    config.autosave = Autosave::Every(*secs as u32)
+                }
+                ("backup", Value::String(when)) if when == "off" => config.backup = Backup::Off,
+                ("backup", Value::String(when)) if when == "first-save" => {
+                    config.backup = Backup::FirstSave
+                }
+                ("backup", Value::String(when)) if when == "every-save" => {
+                    config.backup = Backup::EverySave
+                }
+                ("paste_server", Value::String(url)) => config.paste_server = url.clone(),
+                ("relay_url", Value::String(url)) => config.relay_url = url.clone(),
+                ("user_name", Value::String(name)) => config.user_name = name.clone(),
+                (key, _) => {
+                    return Err(match SETTINGS.iter().find(|(name, _)| *name == key) {
+                        Some((_, expected)) => format!("{} should be {}", key, expected),
+                        None => format!("unknown setting {}", key),
+                    })
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// The settings file text, with each key's possible values above it.
+    pub fn to_toml(&self) -> String {
+        use toml::Value;
+        let string = |text: &str| Value::String(text.to_string());
+        let path = |path: &Option<PathBuf>| match path {
+            Some(path) => string(&path.display().to_string()),
+            None => string(""),
+        };
+        let values = [
+            Value::Integer(self.font_size as i64),
+            path(&self.editor_font),
+            path(&self.ui_font),
+            match &self.theme {
+                ThemeChoice::Dark => string("dark"),
+                ThemeChoice::Light => string("light"),
+                ThemeChoice::HighContrast => string("high-contrast"),
+                ThemeChoice::File(file) => string(&file.display().to_string()),
+            },
+            Value::Boolean(self.line_nums),
+            Value::Integer(self.tab_width as i64),
+            Value::Boolean(self.insert_spaces),
+            match self.word_wrap {
+                WordWrap::Off => string("off"),
+                WordWrap::Window => string("window"),
+                WordWrap::Column(column) => Value::Integer(column as i64),
+            },
+            match self.autosave {
+                Autosave::Off => string("off"),
+                Autosave::FocusLost => string("focus-lost"),
+                Autosave::Every(secs) => Value::Integer(secs as i64),
+            },
+            match self.backup {
+                Backup::Off => string("off"),
+                Backup::FirstSave => string("first-save"),
+                Backup::EverySave => string("every-save"),
+            },
+            string(&self.paste_server),
+            string(&self.relay_url),
+            string(&self.user_name),
+        ];
+        let mut text =
+            "# code_share settings, picked up as soon as this file is saved\n".to_string();
+        for ((key, expected), value) in SETTINGS.iter().zip(values.iter()) {
+            text.push_str(&format!("\n# {}\n{} = {}\n", expected, key, value));
+        }
+        text
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        std::fs::write(path, self.to_toml()).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+/// Where settings files such as the keymap are kept.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn config_dir() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("code_share"))
+}
+
+/// How long lines are wrapped in the editor.
+#[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WordWrap {
+    #[default]
+    Off,
+    /// At the edge of the window.
+    Window,
+    /// After this many columns.
+    Column(u32),
+}
+
+/// When unsaved buffers are written to their swap files, see `swap`.
+#[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Autosave {
+    Off,
+    /// Every this many seconds.
+    Every(u32),
+    /// When the pointer leaves the window, egui doesn't report window focus.
+    FocusLost,
+}
+
+impl Default for Autosave {
+    fn default() -> Self {
+        Autosave::Every(30)
     }
 }
 
+/// When saving copies the previous version of a file to `<name>.bak`.
 #[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Backup {
+    #[default]
+    Off,
+    /// Only on the first save after opening, keeping the version from before editing.
+    FirstSave,
+    EverySave,
+}
+
+/// Where the editor's rows fall when long lines wrap onto more than one.
+/// Only the lines the editor laid out are known, the others count as one row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WrappedRows {
+    /// The first line laid out.
+    pub first_line: usize,
+    /// The row each line laid out starts on, counted from `first_line`'s.
+    pub line_starts: Vec<usize>,
+    /// Rows taken by the lines laid out.
+    pub row_count: usize,
+}
+
+/// The gutter's line numbers, only for the rows in view.
+#[derive(Default)]
 pub struct LineNumbers {
     num_string: String,
+    rows: Range<usize>,
     pub line_count: usize,
+    /// `None` while there's one row per line.
+    wrapped: Option<WrappedRows>,
 }
 
-impl Default for LineNumbers {
-    fn default() -> Self {
-        LineNumbers {
-            num_string: String::from("~"),
-            line_count: 0,
+impl LineNumbers {
+    /// Number the rows the editor last laid its text out in.
+    pub fn set_wrapped(&mut self, wrapped: Option<WrappedRows>) {
+        if self.wrapped != wrapped {
+            self.wrapped = wrapped;
+            self.num_string.clear();
         }
     }
-}
 
-impl LineNumbers {
-    pub fn generate(&mut self, current_count: usize) -> String {
-        let Self {
-            num_string,
-            line_count,
-        } = self;
-        if *line_count != current_count {
-            num_string.clear();
+    /// The 0-based line shown on `row`.
+    pub fn line_at_row(&self, row: usize) -> usize {
+        let wrapped = match &self.wrapped {
+            Some(wrapped) if row >= wrapped.first_line => wrapped,
+            _ => return row,
+        };
+        let row = row - wrapped.first_line;
+        let line = match row < wrapped.row_count {
+            true => wrapped
+                .line_starts
+                .partition_point(|start| *start <= row)
+                .saturating_sub(1),
+            false => wrapped.line_starts.len() + row - wrapped.row_count,
+        };
+        wrapped.first_line + line
+    }
+
+    /// The row the 0-based `line` starts on.
+    pub fn row_of_line(&self, line: usize) -> usize {
+        let wrapped = match &self.wrapped {
+            Some(wrapped) if line >= wrapped.first_line => wrapped,
+            _ => return line,
+        };
+        let line = line - wrapped.first_line;
+        let row = match wrapped.line_starts.get(line) {
+            Some(row) => *row,
+            None => wrapped.row_count + line - wrapped.line_starts.len(),
+        };
+        wrapped.first_line + row
+    }
+
+    /// Numbers for the 0-based `rows`, padded to the width of the largest
+    /// number so the gutter doesn't change width while scrolling. A wrapped
+    /// line is only numbered on its first row. A `~` marks the row after
+    /// the last line.
+    pub fn generate(&mut self, rows: Range<usize>, current_count: usize) -> String {
+        if self.line_count != current_count || self.rows != rows || self.num_string.is_empty() {
+            let mut num_string = String::new();
             let num_digits = LineNumbers::get_num_digits(current_count);
+            let end_row = self.row_of_line(current_count);
 
-            for i in 1..=current_count {
-                let leading_spaces = num_digits - LineNumbers::get_num_digits(i);
-                let temp_str = format!("{:width$}{}", "", i, width = leading_spaces + 1);
-                num_string.push_str(&format!("{}\n", temp_str));
+            for row in rows.start..rows.end.min(end_row) {
+                let line = self.line_at_row(row);
+                if self.row_of_line(line) == row {
+                    let leading_spaces = num_digits - LineNumbers::get_num_digits(line + 1);
+                    let temp_str = format!("{:width$}{}", "", line + 1, width = leading_spaces + 1);
+                    num_string.push_str(&format!("{}\n", temp_str));
+                } else {
+                    num_string.push_str(&format!("{:width$}\n", "", width = num_digits + 1));
+                }
             }
 
-            num_string.push_str(&format!(" {:width$}~", "", width = num_digits - 1));
-            return num_string.clone();
+            if rows.end > end_row {
+                num_string.push_str(&format!(" {:width$}~", "", width = num_digits - 1));
+            }
+            self.num_string = num_string;
+            self.rows = rows;
+            self.line_count = current_count;
         }
-        num_string.clone()
-    }
-    pub fn reset(&mut self) {
-        *self = Self::default();
+        self.num_string.clone()
     }
 
     fn get_num_digits(num: usize) -> usize {
@@ -86,3 +392,61 @@ impl LineNumbers {
         dig_count
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_files_round_trip() {
+        let mut config = AppConfig::parse(
+            r#"
+            font_size = 18
+            theme = "/themes/dusk.toml"
+            word_wrap = 100
+            autosave = "focus-lost"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.get_font_size(), 18.0);
+        assert_eq!(
+            config.theme,
+            ThemeChoice::File(PathBuf::from("/themes/dusk.toml"))
+        );
+        assert_eq!(config.word_wrap, WordWrap::Column(100));
+        assert_eq!(config.autosave, Autosave::FocusLost);
+        assert_eq!(config.backup, Backup::Off);
+        config.editor_font = Some(PathBuf::from("/fonts/mono \"bold\".ttf"));
+        config.insert_spaces = true;
+        assert_eq!(AppConfig::parse(&config.to_toml()), Ok(config));
+
+        assert!(AppConfig::parse("tab_width = 0").is_err());
+        assert!(AppConfig::parse("backup = \"sometimes\"").is_err());
+        assert!(AppConfig::parse("line_nums = true").is_err());
+    }
+
+    #[test]
+    fn wrapped_lines_are_numbered_once() {
+        let mut line_nums = LineNumbers::default();
+        assert_eq!(line_nums.generate(0..4, 3), " 1\n 2\n 3\n ~");
+        //  The second line takes three rows
+        line_nums.set_wrapped(Some(WrappedRows {
+            first_line: 0,
+            line_starts: vec![0, 1, 4],
+            row_count: 5,
+        }));
+        assert_eq!(line_nums.generate(0..4, 3), " 1\n 2\n  \n  \n");
+        assert_eq!(line_nums.generate(3..6, 3), "  \n 3\n ~");
+        assert_eq!(line_nums.line_at_row(2), 1);
+        assert_eq!(line_nums.row_of_line(2), 4);
+        //  Only lines 2 and 3 were laid out, line 2 on two rows
+        line_nums.set_wrapped(Some(WrappedRows {
+            first_line: 2,
+            line_starts: vec![0, 2],
+            row_count: 3,
+        }));
+        assert_eq!(line_nums.generate(0..7, 5), " 1\n 2\n 3\n  \n 4\n 5\n ~");
+        assert_eq!(line_nums.line_at_row(6), 5);
+        assert_eq!(line_nums.row_of_line(5), 6);
+    }
+}
@@ -0,0 +1,37 @@
+#![forbid(unsafe_code)]
+#![cfg_attr(not(debug_assertions), deny(warnings))] // Forbid warnings in release builds
+#![warn(clippy::all, rust_2018_idioms)]
+
+//  Relay for live editing sessions. Run one and point every editor's
+//  Tools > Collaborate at it, e.g. two native instances on one machine.
+//  Usage: collab_relay [--addr 127.0.0.1:8091]
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    use code_share::collab;
+    use std::net::TcpListener;
+
+    let mut addr = collab::DEFAULT_RELAY_ADDR.to_string();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--addr", Some(value)) => addr = value,
+            _ => {
+                eprintln!("Usage: collab_relay [--addr HOST:PORT]");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let listener = match TcpListener::bind(&addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not listen on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    println!("Relaying sessions on ws://{}", addr);
+    if let Err(e) = collab::serve_relay(listener) {
+        eprintln!("Relay stopped: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,46 @@
+#![forbid(unsafe_code)]
+#![cfg_attr(not(debug_assertions), deny(warnings))] // Forbid warnings in release builds
+#![warn(clippy::all, rust_2018_idioms)]
+
+//  Paste service for sharing snippets on a local network.
+//  Usage: paste_server [--addr 127.0.0.1:8090] [--dir pastes]
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    use code_share::paste::{self, PasteStore};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    let mut addr = paste::DEFAULT_ADDR.to_string();
+    let mut dir = PathBuf::from("pastes");
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--addr", Some(value)) => addr = value,
+            ("--dir", Some(value)) => dir = PathBuf::from(value),
+            _ => {
+                eprintln!("Usage: paste_server [--addr HOST:PORT] [--dir DIRECTORY]");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let store = match PasteStore::open(&dir) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("Could not open paste directory {}: {}", dir.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let listener = match TcpListener::bind(&addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Could not listen on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    println!("Serving pastes from {} on http://{}", dir.display(), addr);
+    if let Err(e) = paste::serve(listener, store) {
+        eprintln!("Server stopped: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,193 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "\
+Usage: code_share [OPTIONS] [FILE[:LINE[:COLUMN]]]...
+
+Opens each FILE in its own tab, with the cursor at LINE and COLUMN if given.
+A FILE of - reads the buffer from stdin.
+
+Options:
+    --readonly          Open the files read-only
+    --font-size SIZE    Editor font size
+    --new-window        Don't hand the files to an already running editor
+    -h, --help          Print this help";
+
+/// A file named on the command line, with an optional 1-based line and column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileArg {
+    pub path: PathBuf,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+impl FileArg {
+    /// Split `path:line:col` into its parts. An existing file whose name
+    /// happens to end in `:<digits>` is taken as is.
+    pub fn parse(arg: &str) -> FileArg {
+        let whole = FileArg {
+            path: PathBuf::from(arg),
+            line: None,
+            column: None,
+        };
+        if Path::new(arg).exists() {
+            return whole;
+        }
+        let mut parts = arg.rsplitn(3, ':');
+        let last = parts.next().and_then(|part| part.parse::<usize>().ok());
+        let middle = parts.next();
+        match (last, middle, parts.next()) {
+            (Some(column), Some(line), Some(path)) if !path.is_empty() => match line.parse() {
+                Ok(line) => FileArg {
+                    path: PathBuf::from(path),
+                    line: Some(line),
+                    column: Some(column),
+                },
+                Err(_) => FileArg {
+                    path: PathBuf::from(format!("{}:{}", path, line)),
+                    line: Some(column),
+                    column: None,
+                },
+            },
+            (Some(line), Some(path), None) if !path.is_empty() => FileArg {
+                path: PathBuf::from(path),
+                line: Some(line),
+                column: None,
+            },
+            _ => whole,
+        }
+    }
+}
+
+/// What the native binary was asked to do on launch.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LaunchOptions {
+    pub files: Vec<FileArg>,
+    /// `-` was given, read a buffer from stdin.
+    pub stdin: bool,
+    pub stdin_text: Option<String>,
+    pub read_only: bool,
+    pub font_size: Option<u32>,
+    pub new_window: bool,
+    pub help: bool,
+}
+
+impl LaunchOptions {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<LaunchOptions, String> {
+        let mut options = LaunchOptions::default();
+        let mut args = args.into_iter();
+        let mut only_files = false;
+        while let Some(arg) = args.next() {
+            if only_files {
+                options.files.push(FileArg::parse(&arg));
+                continue;
+            }
+            match arg.as_str() {
+                "--" => only_files = true,
+                "-" => options.stdin = true,
+                "--readonly" => options.read_only = true,
+                "--new-window" => options.new_window = true,
+                "-h" | "--help" => options.help = true,
+                "--font-size" => {
+                    let size = args.next().ok_or("--font-size needs a size")?;
+                    options.font_size = Some(LaunchOptions::parse_font_size(&size)?);
+                }
+                _ => match arg.strip_prefix("--font-size=") {
+                    Some(size) => options.font_size = Some(LaunchOptions::parse_font_size(size)?),
+                    None if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+                    None => options.files.push(FileArg::parse(&arg)),
+                },
+            }
+        }
+        Ok(options)
+    }
+
+    fn parse_font_size(size: &str) -> Result<u32, String> {
+        match size.parse() {
+            Ok(size) if (1..=200).contains(&size) => Ok(size),
+            _ => Err(format!(
+                "Font size must be a number from 1 to 200, not {}",
+                size
+            )),
+        }
+    }
+
+    /// Read the buffer for `-` from stdin.
+    pub fn read_stdin(&mut self) -> std::io::Result<()> {
+        if self.stdin {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            self.stdin_text = Some(text);
+        }
+        Ok(())
+    }
+
+    /// These options as arguments again, with paths made absolute,
+    /// so another process can parse them from a different directory.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.read_only {
+            args.push("--readonly".to_string());
+        }
+        if let Some(size) = self.font_size {
+            args.push(format!("--font-size={}", size));
+        }
+        args.push("--".to_string());
+        for file in self.files.iter() {
+            let path = std::env::current_dir()
+                .map(|dir| dir.join(&file.path))
+                .unwrap_or_else(|_| file.path.clone());
+            let mut arg = path.to_string_lossy().into_owned();
+            if let Some(line) = file.line {
+                arg.push_str(&format!(":{}", line));
+                if let Some(column) = file.column {
+                    arg.push_str(&format!(":{}", column));
+                }
+            }
+            args.push(arg);
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<LaunchOptions, String> {
+        LaunchOptions::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn files_with_line_and_column() {
+        let options = parse(&["src/nope.rs:12:5", "notes.md:3", "--readonly", "-"]).unwrap();
+        assert_eq!(
+            options.files,
+            vec![
+                FileArg {
+                    path: PathBuf::from("src/nope.rs"),
+                    line: Some(12),
+                    column: Some(5),
+                },
+                FileArg {
+                    path: PathBuf::from("notes.md"),
+                    line: Some(3),
+                    column: None,
+                },
+            ]
+        );
+        assert!(options.read_only && options.stdin && !options.new_window);
+        assert_eq!(FileArg::parse("C:\\code\\x.rs").line, None);
+    }
+
+    #[test]
+    fn flags_and_errors() {
+        let options = parse(&["--font-size", "18", "--new-window", "--", "--weird"]).unwrap();
+        assert_eq!(options.font_size, Some(18));
+        assert!(options.new_window);
+        assert_eq!(options.files[0].path, PathBuf::from("--weird"));
+        assert!(parse(&["--font-size=0"]).is_err());
+        assert!(parse(&["--font-size"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+    }
+}
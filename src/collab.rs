@@ -0,0 +1,611 @@
+//! Live editing sessions, where several people edit the same buffer at once.
+//!
+//! A relay holds each session's text and puts every edit in one order.
+//! Clients send their edits as `Operation`s based on the last revision they
+//! saw; the relay transforms them past anything that came in meanwhile,
+//! applies them and broadcasts the result. Messages are WebSocket text
+//! frames, with the message kind and its fields on separate lines:
+//!
+//! - `host` / `join`, session, user name (and the starting text for `host`)
+//! - `welcome`, client id, revision, text: the reply to `host` and `join`
+//! - `op`, revision or author, encoded operation; `ack` confirms the sender's own op
+//! - `cursor`, revision or author, char index (and the author's name from the relay)
+//! - `left`, client id; `error`, message
+
+use crate::ot::Operation;
+use crate::text_buf::TextBuf;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tungstenite::{Message, WebSocket};
+
+pub const DEFAULT_RELAY_ADDR: &str = "127.0.0.1:8091";
+pub const DEFAULT_RELAY_URL: &str = "ws://127.0.0.1:8091";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+//  How long a connection waits for messages before checking for ones to send
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A collaborator's caret, as a char index into the shared text.
+pub struct RemoteCursor {
+    pub name: String,
+    pub pos: usize,
+}
+
+/// This client's side of a session. The connection runs on its own thread;
+/// `sync` exchanges changes with it once per frame.
+pub struct CollabSession {
+    pub session_name: String,
+    revision: usize,
+    /// Local operations the relay hasn't confirmed yet. The first one has been sent.
+    pending: VecDeque<Operation>,
+    /// The text as of the last sync, for turning the buffer's edits since
+    /// into operations.
+    shadow: TextBuf,
+    shadow_revision: u64,
+    pub remote_cursors: BTreeMap<u64, RemoteCursor>,
+    cursor: Option<usize>,
+    cursor_sent: bool,
+    outgoing: Sender<String>,
+    incoming: Receiver<Result<String, String>>,
+    /// Set once the session can't go on, e.g. when the relay went away.
+    pub error: Option<String>,
+}
+
+impl CollabSession {
+    /// Start a session on the relay at `relay_url`, sharing `text`.
+    /// `on_message` is called from the connection thread when something arrives.
+    pub fn host(
+        relay_url: &str,
+        session_name: &str,
+        user_name: &str,
+        text: &str,
+        on_message: impl Fn() + Send + 'static,
+    ) -> Result<CollabSession, Box<dyn std::error::Error>> {
+        let hello = format!(
+            "host\n{}\n{}\n{}",
+            session_name,
+            CollabSession::clean_name(user_name),
+            text
+        );
+        let (session, _) = CollabSession::connect(relay_url, session_name, hello, on_message)?;
+        Ok(session)
+    }
+
+    /// Join a running session, returning it along with its current text.
+    pub fn join(
+        relay_url: &str,
+        session_name: &str,
+        user_name: &str,
+        on_message: impl Fn() + Send + 'static,
+    ) -> Result<(CollabSession, String), Box<dyn std::error::Error>> {
+        let hello = format!(
+            "join\n{}\n{}",
+            session_name,
+            CollabSession::clean_name(user_name)
+        );
+        CollabSession::connect(relay_url, session_name, hello, on_message)
+    }
+
+    //  Names and session names go on their own line
+    fn clean_name(name: &str) -> String {
+        name.replace('\n', " ")
+    }
+
+    fn connect(
+        relay_url: &str,
+        session_name: &str,
+        hello: String,
+        on_message: impl Fn() + Send + 'static,
+    ) -> Result<(CollabSession, String), Box<dyn std::error::Error>> {
+        if session_name.is_empty() || session_name.contains('\n') {
+            return Err("Enter a session name".into());
+        }
+        let host = match relay_url.strip_prefix("ws://") {
+            Some(rest) => rest.split('/').next().unwrap_or(rest),
+            None => {
+                return Err(format!("Only ws:// relay URLs are supported: {}", relay_url).into())
+            }
+        };
+        let addr = match host.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(format!("Could not resolve {}", host).into()),
+        };
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+            .map_err(|e| format!("Could not reach relay at {}: {}", host, e))?;
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        let (mut socket, _) = tungstenite::client(relay_url, stream).map_err(|e| e.to_string())?;
+        socket.write_message(Message::Text(hello))?;
+
+        let welcome = read_text(&mut socket)?;
+        let mut fields = welcome.splitn(4, '\n');
+        match fields.next() {
+            Some("welcome") => (),
+            Some("error") => return Err(fields.next().unwrap_or("Relay error").into()),
+            _ => return Err("Unexpected reply from relay".into()),
+        }
+        //  The client id only matters to the relay
+        let (revision, text) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(_client_id), Some(revision), Some(text)) => (revision.parse()?, text.to_string()),
+            _ => return Err("Malformed welcome from relay".into()),
+        };
+        socket.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let (outgoing, outgoing_rx) = mpsc::channel();
+        let (incoming_tx, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            let result = pump(&mut socket, &outgoing_rx, |msg| {
+                let _ = incoming_tx.send(Ok(msg));
+                on_message();
+            });
+            let reason = match result {
+                Ok(()) => "The relay closed the session".to_string(),
+                Err(e) => format!("Lost connection to relay: {}", e),
+            };
+            let _ = incoming_tx.send(Err(reason));
+            on_message();
+        });
+
+        let session = CollabSession {
+            session_name: session_name.to_string(),
+            revision,
+            pending: VecDeque::new(),
+            shadow: TextBuf::from(text.clone()),
+            //  Compared against the buffer on the first sync
+            shadow_revision: 0,
+            remote_cursors: BTreeMap::new(),
+            cursor: None,
+            cursor_sent: false,
+            outgoing,
+            incoming,
+            error: None,
+        };
+        Ok((session, text))
+    }
+
+    /// Send what changed in `text` since the last sync, then apply what
+    /// collaborators changed. Returns the remote operations applied to `text`,
+    /// so the caller can move its cursor along.
+    pub fn sync(&mut self, text: &mut TextBuf) -> Vec<Operation> {
+        match text.edits_since(self.shadow_revision) {
+            Some(edits) => {
+                for edit in edits.iter() {
+                    let op = Operation::from_edit(&self.shadow, edit);
+                    self.push_local(op);
+                }
+            }
+            //  The first sync, or more edits than the buffer keeps
+            None => {
+                let shadow = self.shadow.to_string();
+                if let Some(op) = Operation::from_diff(&shadow, &text.to_string()) {
+                    self.push_local(op);
+                }
+            }
+        }
+
+        let mut applied = Vec::new();
+        while self.error.is_none() {
+            let result = match self.incoming.try_recv() {
+                Ok(Ok(msg)) => self.receive(&msg, text, &mut applied),
+                Ok(Err(e)) => Err(e),
+                Err(_) => break,
+            };
+            if let Err(e) = result {
+                self.error = Some(e);
+            }
+        }
+        self.shadow_revision = text.revision();
+        applied
+    }
+
+    /// Queue a local operation, sending it if nothing else is in flight.
+    fn push_local(&mut self, op: Operation) {
+        for cursor in self.remote_cursors.values_mut() {
+            cursor.pos = op.transform_index(cursor.pos);
+        }
+        if let Err(e) = op.apply_to(&mut self.shadow) {
+            self.error = Some(e);
+        }
+        self.pending.push_back(op);
+        if self.pending.len() == 1 {
+            self.send_pending();
+        }
+    }
+
+    /// Tell collaborators where the local caret is, as a char index.
+    /// Only sent while no local edits are in flight, so the relay can place it.
+    pub fn set_cursor(&mut self, pos: usize) {
+        if self.cursor != Some(pos) {
+            self.cursor = Some(pos);
+            self.cursor_sent = false;
+        }
+        if !self.cursor_sent && self.pending.is_empty() {
+            self.send(format!("cursor\n{}\n{}", self.revision, pos));
+            self.cursor_sent = true;
+        }
+    }
+
+    /// Number of other people in the session that have shown their cursor.
+    pub fn collaborator_count(&self) -> usize {
+        self.remote_cursors.len()
+    }
+
+    fn receive(
+        &mut self,
+        msg: &str,
+        text: &mut TextBuf,
+        applied: &mut Vec<Operation>,
+    ) -> Result<(), String> {
+        let mut fields = msg.splitn(3, '\n');
+        let kind = fields.next().unwrap_or("");
+        let client_id = fields.next().and_then(|id| id.parse::<u64>().ok());
+        match (kind, client_id) {
+            ("ack", _) => {
+                self.pending.pop_front();
+                self.revision += 1;
+                self.send_pending();
+            }
+            ("op", Some(_)) => {
+                let mut remote = fields
+                    .next()
+                    .and_then(Operation::decode)
+                    .ok_or("Malformed operation from relay")?;
+                //  The relay orders our pending edits after this one
+                for pending in self.pending.iter_mut() {
+                    let (pending_prime, remote_prime) = Operation::transform(pending, &remote)?;
+                    *pending = pending_prime;
+                    remote = remote_prime;
+                }
+                //  The buffer matches the shadow since the local changes were taken
+                remote.apply_to(&mut self.shadow)?;
+                remote.apply_to(text)?;
+                self.revision += 1;
+                for cursor in self.remote_cursors.values_mut() {
+                    cursor.pos = remote.transform_index(cursor.pos);
+                }
+                applied.push(remote);
+            }
+            ("cursor", Some(client_id)) => {
+                let mut fields = fields.next().unwrap_or("").splitn(2, '\n');
+                let pos = fields.next().and_then(|pos| pos.parse::<usize>().ok());
+                let name = fields.next().unwrap_or("").to_string();
+                if let Some(pos) = pos {
+                    let pos = self
+                        .pending
+                        .iter()
+                        .fold(pos, |pos, op| op.transform_index(pos));
+                    self.remote_cursors
+                        .insert(client_id, RemoteCursor { name, pos });
+                }
+            }
+            ("left", Some(client_id)) => {
+                self.remote_cursors.remove(&client_id);
+            }
+            ("error", _) => return Err(msg.trim_start_matches("error\n").to_string()),
+            _ => return Err(format!("Unexpected message from relay: {}", kind)),
+        }
+        Ok(())
+    }
+
+    fn send_pending(&mut self) {
+        if let Some(op) = self.pending.front() {
+            let msg = format!("op\n{}\n{}", self.revision, op.encode());
+            self.send(msg);
+        }
+    }
+
+    fn send(&mut self, msg: String) {
+        if self.outgoing.send(msg).is_err() && self.error.is_none() {
+            self.error = Some("Lost connection to relay".to_string());
+        }
+    }
+
+    /// Colour for a collaborator's cursor, the same on every frame.
+    pub fn cursor_color(client_id: u64) -> (u8, u8, u8) {
+        const COLORS: [(u8, u8, u8); 6] = [
+            (102, 217, 239),
+            (249, 38, 114),
+            (166, 226, 46),
+            (253, 151, 31),
+            (174, 129, 255),
+            (230, 219, 116),
+        ];
+        COLORS[(client_id % COLORS.len() as u64) as usize]
+    }
+}
+
+/// Exchange messages on `socket` until it closes or `outgoing` is dropped.
+fn pump(
+    socket: &mut WebSocket<TcpStream>,
+    outgoing: &Receiver<String>,
+    mut on_message: impl FnMut(String),
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        loop {
+            match outgoing.try_recv() {
+                Ok(msg) => socket.write_message(Message::Text(msg))?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let _ = socket.close(None);
+                    let _ = socket.write_pending();
+                    return Ok(());
+                }
+            }
+        }
+        match socket.read_message() {
+            Ok(Message::Text(msg)) => on_message(msg),
+            Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => {
+                let _ = socket.write_pending();
+                return Ok(());
+            }
+            Ok(_) => (),
+            Err(tungstenite::Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn read_text(socket: &mut WebSocket<TcpStream>) -> Result<String, Box<dyn std::error::Error>> {
+    loop {
+        match socket.read_message()? {
+            Message::Text(text) => return Ok(text),
+            Message::Close(_) => return Err("The relay closed the connection".into()),
+            _ => (),
+        }
+    }
+}
+
+struct RelaySession {
+    text: String,
+    /// Every operation applied so far; revision `n` is the text after `history[..n]`.
+    history: Vec<Operation>,
+    clients: HashMap<u64, RelayClient>,
+}
+
+struct RelayClient {
+    name: String,
+    outgoing: Sender<String>,
+}
+
+type Sessions = Mutex<HashMap<String, RelaySession>>;
+
+/// Run a relay on `listener`, one thread per connected client.
+pub fn serve_relay(listener: TcpListener) -> io::Result<()> {
+    let sessions = Arc::new(Sessions::default());
+    let next_id = AtomicU64::new(1);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let sessions = Arc::clone(&sessions);
+        let client_id = next_id.fetch_add(1, Ordering::Relaxed);
+        thread::spawn(move || {
+            if let Err(e) = relay_client(stream, &sessions, client_id) {
+                eprintln!("Error relaying client {}: {}", client_id, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn relay_client(
+    stream: TcpStream,
+    sessions: &Sessions,
+    client_id: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    let mut socket = tungstenite::accept(stream).map_err(|e| e.to_string())?;
+    let hello = read_text(&mut socket)?;
+    let (outgoing, outgoing_rx) = mpsc::channel();
+    let session_name = match enter_session(sessions, &hello, client_id, outgoing.clone()) {
+        Ok(session_name) => session_name,
+        Err(e) => {
+            socket.write_message(Message::Text(format!("error\n{}", e)))?;
+            socket.close(None)?;
+            let _ = socket.write_pending();
+            return Ok(());
+        }
+    };
+    socket.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+    let result = pump(&mut socket, &outgoing_rx, |msg| {
+        if let Err(e) = relay_message(sessions, &session_name, client_id, &msg) {
+            let _ = outgoing.send(format!("error\n{}", e));
+        }
+    });
+    leave_session(sessions, &session_name, client_id);
+    result
+}
+
+//  Handle a `host` or `join` message, returning the session's name
+fn enter_session(
+    sessions: &Sessions,
+    hello: &str,
+    client_id: u64,
+    outgoing: Sender<String>,
+) -> Result<String, String> {
+    let mut fields = hello.splitn(4, '\n');
+    let (kind, session_name, name) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(kind), Some(session_name), Some(name)) => (kind, session_name, name),
+        _ => return Err("Expected host or join".to_string()),
+    };
+    let mut sessions = sessions.lock().map_err(|e| e.to_string())?;
+    let session = match kind {
+        "host" if sessions.contains_key(session_name) => {
+            return Err(format!("Session \"{}\" already exists", session_name));
+        }
+        "host" => sessions
+            .entry(session_name.to_string())
+            .or_insert(RelaySession {
+                text: fields.next().unwrap_or("").to_string(),
+                history: Vec::new(),
+                clients: HashMap::new(),
+            }),
+        "join" => match sessions.get_mut(session_name) {
+            Some(session) => session,
+            None => return Err(format!("No session named \"{}\"", session_name)),
+        },
+        _ => return Err("Expected host or join".to_string()),
+    };
+    let welcome = format!(
+        "welcome\n{}\n{}\n{}",
+        client_id,
+        session.history.len(),
+        session.text
+    );
+    let _ = outgoing.send(welcome);
+    session.clients.insert(
+        client_id,
+        RelayClient {
+            name: name.to_string(),
+            outgoing,
+        },
+    );
+    Ok(session_name.to_string())
+}
+
+fn relay_message(
+    sessions: &Sessions,
+    session_name: &str,
+    client_id: u64,
+    msg: &str,
+) -> Result<(), String> {
+    let mut sessions = sessions.lock().map_err(|e| e.to_string())?;
+    let session = sessions.get_mut(session_name).ok_or("Session has ended")?;
+    let mut fields = msg.splitn(3, '\n');
+    let kind = fields.next().unwrap_or("");
+    let revision = fields
+        .next()
+        .and_then(|revision| revision.parse::<usize>().ok())
+        .filter(|revision| *revision <= session.history.len())
+        .ok_or("Missing or unknown revision")?;
+    let newer = &session.history[revision..];
+    let broadcast = match kind {
+        "op" => {
+            let mut op = fields
+                .next()
+                .and_then(Operation::decode)
+                .ok_or("Malformed operation")?;
+            for past in newer {
+                op = Operation::transform(&op, past)?.0;
+            }
+            session.text = op.apply(&session.text)?;
+            let broadcast = format!("op\n{}\n{}", client_id, op.encode());
+            session.history.push(op);
+            if let Some(client) = session.clients.get(&client_id) {
+                let _ = client.outgoing.send("ack".to_string());
+            }
+            broadcast
+        }
+        "cursor" => {
+            let pos = fields
+                .next()
+                .and_then(|pos| pos.parse::<usize>().ok())
+                .ok_or("Malformed cursor")?;
+            let pos = newer.iter().fold(pos, |pos, op| op.transform_index(pos));
+            let name = session
+                .clients
+                .get(&client_id)
+                .map_or("", |client| client.name.as_str());
+            format!("cursor\n{}\n{}\n{}", client_id, pos, name)
+        }
+        _ => return Err(format!("Unknown message: {}", kind)),
+    };
+    for (id, client) in session.clients.iter() {
+        if *id != client_id {
+            let _ = client.outgoing.send(broadcast.clone());
+        }
+    }
+    Ok(())
+}
+
+fn leave_session(sessions: &Sessions, session_name: &str, client_id: u64) {
+    let mut sessions = match sessions.lock() {
+        Ok(sessions) => sessions,
+        Err(_) => return,
+    };
+    if let Some(session) = sessions.get_mut(session_name) {
+        session.clients.remove(&client_id);
+        for client in session.clients.values() {
+            let _ = client.outgoing.send(format!("left\n{}", client_id));
+        }
+        if session.clients.is_empty() {
+            sessions.remove(session_name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn start_relay() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        thread::spawn(move || serve_relay(listener));
+        url
+    }
+
+    //  Keep syncing both ends until they agree, or give up after a while
+    fn settle(
+        a: &mut CollabSession,
+        a_text: &mut TextBuf,
+        b: &mut CollabSession,
+        b_text: &mut TextBuf,
+    ) {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            a.sync(a_text);
+            b.sync(b_text);
+            if a_text.to_string() == b_text.to_string()
+                && a.pending.is_empty()
+                && b.pending.is_empty()
+            {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Sessions didn't converge: {:?} vs {:?}", a_text, b_text);
+    }
+
+    #[test]
+    fn two_clients_converge_through_relay() {
+        let relay = start_relay();
+        let mut host_text = TextBuf::from("fn main() {}\n".to_string());
+        let mut host =
+            CollabSession::host(&relay, "demo", "ana", &host_text.to_string(), || ()).unwrap();
+        let (mut guest, guest_text) = CollabSession::join(&relay, "demo", "bo", || ()).unwrap();
+        assert_eq!(guest_text, host_text.to_string());
+        let mut guest_text = TextBuf::from(guest_text);
+        assert!(CollabSession::host(&relay, "demo", "cy", "", || ()).is_err());
+
+        //  Concurrent edits on both sides, several before anything is confirmed
+        host_text.replace_range(0..0, "// hé\n");
+        host.sync(&mut host_text);
+        //  Each edit since the last sync goes out as its own operation
+        host_text.push_str("// ");
+        host_text.push_str("end\n");
+        guest_text.set(guest_text.to_string().replace("{}", "{ run(); }"));
+        guest.sync(&mut guest_text);
+        settle(&mut host, &mut host_text, &mut guest, &mut guest_text);
+        assert_eq!(
+            host_text.to_string(),
+            "// hé\nfn main() { run(); }\n// end\n"
+        );
+
+        guest.set_cursor(3);
+        let start = Instant::now();
+        while host.remote_cursors.is_empty() && start.elapsed() < Duration::from_secs(5) {
+            host.sync(&mut host_text);
+            thread::sleep(Duration::from_millis(10));
+        }
+        let cursor = host.remote_cursors.values().next().unwrap();
+        assert_eq!((cursor.name.as_str(), cursor.pos), ("bo", 3));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::app_config::LineNumbers;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::collab::CollabSession;
+use crate::file::FileStatus;
+use crate::find::FindTools;
+use crate::highlight::Language;
+use crate::history::History;
+use crate::preview::Preview;
+use crate::share::Snippet;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::swap::Swap;
+use crate::text_buf::TextBuf;
+use eframe::egui;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_DOC_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// One open tab: a text buffer plus everything that tracks it.
+/// Tabs are persisted between runs as a `session::Session`.
+pub struct Document {
+    id: usize,
+    pub file_status: FileStatus,
+    pub text_buf: TextBuf,
+    pub line_nums: LineNumbers,
+    pub finder: FindTools,
+    /// Syntax chosen from the View menu, overriding the file extension.
+    pub language_override: Option<Language>,
+    /// Set for snippets opened from a share link until the user chooses to edit them.
+    pub read_only: bool,
+    /// File name carried by a share link, the snippet itself has no path.
+    pub shared_name: Option<String>,
+    /// Set for binaries and very large files shown a chunk at a time.
+    pub preview: Option<Preview>,
+    pub history: History,
+    /// Char index of the cursor as of the last frame.
+    pub cursor: usize,
+    /// Vertical scroll position as of the last frame.
+    pub scroll_offset: f32,
+    /// Scroll position to jump to on the next frame.
+    pub scroll_to: Option<f32>,
+    /// Live editing session this tab is shared in.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub collab: Option<CollabSession>,
+    /// Copy of the unsaved buffer kept for crash recovery.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub swap: Swap,
+}
+
+impl Default for Document {
+    fn default() -> Self {
+        let mut file_status = FileStatus::default();
+        file_status.set_unsaved(false);
+        let id = NEXT_DOC_ID.fetch_add(1, Ordering::Relaxed);
+        Self {
+            id,
+            file_status,
+            text_buf: TextBuf::default(),
+            line_nums: LineNumbers::default(),
+            finder: FindTools::default(),
+            language_override: None,
+            read_only: false,
+            shared_name: None,
+            preview: None,
+            history: History::default(),
+            cursor: 0,
+            scroll_offset: 0.0,
+            scroll_to: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            collab: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            swap: Swap::new(id),
+        }
+    }
+}
+
+impl Document {
+    /// Id of this document's editor widget, so every tab keeps its own cursor.
+    pub fn editor_id(&self) -> egui::Id {
+        egui::Id::new("editor").with(self.id)
+    }
+
+    /// A read-only tab showing the start of the file `preview` is for.
+    pub fn from_preview(preview: Preview, first_chunk: String) -> Self {
+        let mut doc = Document::default();
+        doc.load_text(first_chunk);
+        doc.read_only = true;
+        //  No path, so saving can't write the shown part over the whole file
+        doc.shared_name = Some(preview.title());
+        doc.preview = Some(preview);
+        doc
+    }
+
+    pub fn from_snippet(snippet: Snippet) -> Self {
+        let mut doc = Document::default();
+        doc.load_text(snippet.contents);
+        doc.language_override = Some(snippet.language);
+        doc.read_only = true;
+        doc.shared_name = snippet.file_name;
+        doc
+    }
+
+    /// The buffer as a snippet, for sharing.
+    pub fn to_snippet(&self) -> Snippet {
+        Snippet {
+            file_name: match self.file_status.get_path() {
+                Some(_) => Some(self.file_status.get_file_name()),
+                None => self.shared_name.clone(),
+            },
+            language: self.language(),
+            contents: self.text_buf.to_string(),
+        }
+    }
+
+    /// Replace the whole buffer, e.g. after opening a file. Clears the undo history.
+    pub fn load_text(&mut self, contents: String) {
+        self.text_buf = TextBuf::from(contents);
+        self.history.reset(&self.text_buf);
+    }
+
+    /// Replace the buffer with the file's contents after it changed on disk,
+    /// as a step that can be undone.
+    pub fn reload_text(&mut self, contents: String) {
+        self.text_buf.set(contents);
+        self.history.record_group(&self.text_buf);
+    }
+
+    pub fn language(&self) -> Language {
+        match self.language_override {
+            Some(language) => language,
+            None => Language::from_path(self.file_status.get_path()),
+        }
+    }
+
+    pub fn title(&self) -> String {
+        let indicator = match self.file_status.is_unsaved() {
+            true => "*",
+            false => "",
+        };
+        let name = match (self.file_status.get_path(), &self.shared_name) {
+            (None, Some(shared_name)) => shared_name.clone(),
+            _ => self.file_status.get_file_name(),
+        };
+        format!("{}{}", name, indicator)
+    }
+
+    /// An untouched untitled tab, which can be replaced when opening a file.
+    pub fn is_pristine(&self) -> bool {
+        self.file_status.is_new() && !self.file_status.is_unsaved() && self.text_buf.is_empty()
+    }
+}
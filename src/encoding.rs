@@ -0,0 +1,191 @@
+//! Text encodings and line endings of files on disk. Buffers always hold
+//! UTF-8 with `\n` line endings, files are converted on open and save.
+
+/// How a file's text is stored as bytes.
+#[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// UTF-8 starting with a byte order mark.
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16_LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16_BE_BOM: &[u8] = &[0xFE, 0xFF];
+
+impl Encoding {
+    pub const ALL: [Encoding; 5] = [
+        Encoding::Utf8,
+        Encoding::Utf8Bom,
+        Encoding::Utf16Le,
+        Encoding::Utf16Be,
+        Encoding::Latin1,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf8Bom => "UTF-8 BOM",
+            Encoding::Utf16Le => "UTF-16 LE",
+            Encoding::Utf16Be => "UTF-16 BE",
+            Encoding::Latin1 => "Latin-1",
+        }
+    }
+
+    /// Guess from a byte order mark, falling back to Latin-1 for anything
+    /// that isn't valid UTF-8, as every byte string is valid Latin-1.
+    pub fn detect(bytes: &[u8]) -> Encoding {
+        if bytes.starts_with(UTF8_BOM) {
+            Encoding::Utf8Bom
+        } else if bytes.starts_with(UTF16_LE_BOM) {
+            Encoding::Utf16Le
+        } else if bytes.starts_with(UTF16_BE_BOM) {
+            Encoding::Utf16Be
+        } else if std::str::from_utf8(bytes).is_ok() {
+            Encoding::Utf8
+        } else {
+            Encoding::Latin1
+        }
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Result<String, String> {
+        match self {
+            Encoding::Utf8 | Encoding::Utf8Bom => {
+                let bytes = bytes.strip_prefix(UTF8_BOM).unwrap_or(bytes);
+                String::from_utf8(bytes.to_vec()).map_err(|_| "Not valid UTF-8".to_string())
+            }
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let bom = match self {
+                    Encoding::Utf16Le => UTF16_LE_BOM,
+                    _ => UTF16_BE_BOM,
+                };
+                let bytes = bytes.strip_prefix(bom).unwrap_or(bytes);
+                if bytes.len() % 2 != 0 {
+                    return Err(format!("Not valid {}, odd number of bytes", self.name()));
+                }
+                let units: Vec<u16> = bytes
+                    .chunks(2)
+                    .map(|pair| match self {
+                        Encoding::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                        _ => u16::from_be_bytes([pair[0], pair[1]]),
+                    })
+                    .collect();
+                String::from_utf16(&units).map_err(|_| format!("Not valid {}", self.name()))
+            }
+            Encoding::Latin1 => Ok(bytes.iter().map(|&byte| byte as char).collect()),
+        }
+    }
+
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Utf8Bom => Ok([UTF8_BOM, text.as_bytes()].concat()),
+            Encoding::Utf16Le => Ok(UTF16_LE_BOM
+                .iter()
+                .copied()
+                .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+                .collect()),
+            Encoding::Utf16Be => Ok(UTF16_BE_BOM
+                .iter()
+                .copied()
+                .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+                .collect()),
+            Encoding::Latin1 => text
+                .chars()
+                .map(|ch| match ch as u32 {
+                    code @ 0..=0xFF => Ok(code as u8),
+                    _ => Err(format!("{} can't be saved as Latin-1", ch)),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// What ends each line of a file.
+#[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl LineEnding {
+    pub const ALL: [LineEnding; 3] = [LineEnding::Lf, LineEnding::CrLf, LineEnding::Cr];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+            LineEnding::Cr => "CR",
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+
+    /// The most used line ending in `text`, LF if it has none.
+    pub fn detect(text: &str) -> LineEnding {
+        let crlf = text.matches("\r\n").count();
+        let cr = text.matches('\r').count() - crlf;
+        let lf = text.matches('\n').count() - crlf;
+        if crlf > lf && crlf >= cr {
+            LineEnding::CrLf
+        } else if cr > lf {
+            LineEnding::Cr
+        } else {
+            LineEnding::Lf
+        }
+    }
+
+    /// `text` with every line ending turned into `\n`, as buffers hold it.
+    pub fn normalize(text: &str) -> String {
+        text.replace("\r\n", "\n").replace('\r', "\n")
+    }
+
+    /// A buffer's `\n` line endings turned into this one.
+    pub fn apply(&self, text: &str) -> String {
+        match self {
+            LineEnding::Lf => text.to_string(),
+            _ => text.replace('\n', self.as_str()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodings_round_trip() {
+        let text = "naïve café\n";
+        for encoding in Encoding::ALL.iter() {
+            let bytes = encoding.encode(text).unwrap();
+            assert_eq!(Encoding::detect(&bytes), *encoding);
+            assert_eq!(encoding.decode(&bytes).unwrap(), text);
+        }
+        assert!(Encoding::Latin1.encode("🦀").is_err());
+        assert!(Encoding::Utf16Le.decode(&[0xFF, 0xFE, 0x41]).is_err());
+    }
+
+    #[test]
+    fn line_endings() {
+        assert_eq!(LineEnding::detect("a\r\nb\r\nc\n"), LineEnding::CrLf);
+        assert_eq!(LineEnding::detect("a\rb\r"), LineEnding::Cr);
+        assert_eq!(LineEnding::detect("no breaks"), LineEnding::Lf);
+        let buffer = LineEnding::normalize("a\r\nb\rc\n");
+        assert_eq!(buffer, "a\nb\nc\n");
+        assert_eq!(LineEnding::CrLf.apply(&buffer), "a\r\nb\r\nc\r\n");
+    }
+}
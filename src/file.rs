@@ -1,11 +1,42 @@
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::PathBuf;
+use crate::app_config::Backup;
+use crate::encoding::{Encoding, LineEnding};
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Modification time of the file at `path`, if it can be read.
+pub fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// OS error codes for writing to a read-only file system.
+#[cfg(unix)]
+const READ_ONLY_FS_ERRORS: &[i32] = &[30]; // EROFS
+#[cfg(windows)]
+const READ_ONLY_FS_ERRORS: &[i32] = &[19]; // ERROR_WRITE_PROTECT
+#[cfg(not(any(unix, windows)))]
+const READ_ONLY_FS_ERRORS: &[i32] = &[];
+
+/// OS error codes for running out of disk space.
+#[cfg(unix)]
+const DISK_FULL_ERRORS: &[i32] = &[28]; // ENOSPC
+#[cfg(windows)]
+const DISK_FULL_ERRORS: &[i32] = &[39, 112]; // ERROR_HANDLE_DISK_FULL, ERROR_DISK_FULL
+#[cfg(not(any(unix, windows)))]
+const DISK_FULL_ERRORS: &[i32] = &[];
 
 pub struct FileStatus {
     path: Option<PathBuf>,
     is_unsaved: bool,
     is_new: bool,
+    /// Modification time of the file when it was last read or written.
+    modified: Option<SystemTime>,
+    /// A `.bak` was made since the file was opened, for `Backup::FirstSave`.
+    backed_up: bool,
+    /// How the file is stored on disk, kept when saving.
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
 }
 
 impl Default for FileStatus {
@@ -14,17 +45,15 @@ impl Default for FileStatus {
             path: None,
             is_unsaved: true,
             is_new: true,
+            modified: None,
+            backed_up: false,
+            encoding: Encoding::default(),
+            line_ending: LineEnding::default(),
         }
     }
 }
 
 impl FileStatus {
-    pub fn reset(&mut self) {
-        self.path = None;
-        self.is_unsaved = true;
-        self.is_new = true;
-    }
-
     pub fn _set_is_new(&mut self, status: bool) {
         self.is_new = status;
     }
@@ -39,6 +68,10 @@ impl FileStatus {
         self.is_unsaved = status;
     }
 
+    pub fn get_path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
     pub fn get_path_string(&self) -> String {
         match self.path.clone() {
             Some(path) => String::from(path.to_str().unwrap()),
@@ -46,16 +79,56 @@ impl FileStatus {
         }
     }
 
-    pub fn save_file(&mut self, contents: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn get_modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+    /// Take the file's current state on disk as the one the buffer is based on.
+    pub fn sync_modified(&mut self) {
+        self.modified = self.disk_modified();
+    }
+
+    /// Modification time of the file on disk right now.
+    pub fn disk_modified(&self) -> Option<SystemTime> {
+        modified_time(self.path.as_ref()?)
+    }
+
+    /// Whether something else wrote the file since it was last read or written here.
+    pub fn changed_on_disk(&self) -> bool {
+        self.path.is_some() && self.disk_modified() != self.modified
+    }
+
+    /// Point at `path` again without reading it, e.g. when restoring a
+    /// session whose unsaved buffer was kept.
+    pub fn restore(&mut self, path: PathBuf, modified: Option<SystemTime>) {
+        self.path = Some(path);
+        self.modified = modified;
+        self.is_unsaved = true;
+        self.is_new = false;
+        self.backed_up = false;
+    }
+
+    pub fn get_file_name(&self) -> String {
+        match self.path.as_ref().and_then(|path| path.file_name()) {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => String::from("UNTITLED"),
+        }
+    }
+
+    pub fn save_file(
+        &mut self,
+        contents: &str,
+        backup: Backup,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         let file_path = match &self.path {
-            Some(path) => path,
+            Some(path) => path.clone(),
             None => return Err("Path not set".into()),
         };
 
-        match std::fs::write(file_path, contents) {
+        match self.write_file(&file_path, contents, backup) {
             Ok(_) => {
                 self.is_new = false;
                 self.is_unsaved = false;
+                self.sync_modified();
                 Ok(())
             }
             Err(e) => Err(e.into()),
@@ -64,17 +137,22 @@ impl FileStatus {
 
     pub fn save_file_as(
         &mut self,
-        contents: &mut String,
+        contents: &str,
+        backup: Backup,
     ) -> Result<Option<()>, Box<dyn std::error::Error>> {
         let saved_path = match Self::open_file_save_dialog() {
             Some(path) => path,
             None => return Ok(None),
         };
-        match std::fs::write(&saved_path, contents) {
+        if self.path.as_ref() != Some(&saved_path) {
+            self.backed_up = false;
+        }
+        match self.write_file(&saved_path, contents, backup) {
             Ok(_) => {
                 self.is_new = false;
                 self.is_unsaved = false;
                 self.path = Some(saved_path);
+                self.sync_modified();
                 Ok(Some(()))
             }
             Err(e) => {
@@ -84,49 +162,134 @@ impl FileStatus {
         }
     }
 
-    pub fn open_file(&mut self) -> Result<Option<String>, Box<dyn std::error::Error>> {
-        let old_path = self.path.clone();
-        let old_is_unsaved = self.is_unsaved;
-        let old_is_new = self.is_new;
+    /// Write `contents` to a temp file and rename it over `path`, so a failed
+    /// save never leaves the file half written. Keeps the file's permissions,
+    /// and copies the old version to `<name>.bak` first if `backup` asks for it.
+    fn write_file(&mut self, path: &Path, contents: &str, backup: Backup) -> Result<(), String> {
+        let make_backup = match backup {
+            Backup::Off => false,
+            Backup::FirstSave => !self.backed_up,
+            Backup::EverySave => true,
+        };
+        if make_backup && path.is_file() {
+            let mut backup_name = path.as_os_str().to_owned();
+            backup_name.push(".bak");
+            if let Err(e) = fs::copy(path, PathBuf::from(backup_name)) {
+                return Err(format!(
+                    "no backup made, {}",
+                    Self::describe_write_error(path, &e)
+                ));
+            }
+            self.backed_up = true;
+        }
+        let bytes = self.encoding.encode(&self.line_ending.apply(contents))?;
+        match Self::write_atomic(path, &bytes) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Self::describe_write_error(path, &e)),
+        }
+    }
 
-        let open_path = match Self::open_file_sel_dialog() {
-            Some(p) => p,
-            None => return Ok(None),
+    fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+        //  Replace the file a symlink points at rather than the link
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let permissions = fs::metadata(&path).map(|meta| meta.permissions()).ok();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+        let write = || -> io::Result<()> {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(contents)?;
+            file.sync_all()?;
+            if let Some(permissions) = permissions {
+                fs::set_permissions(&temp_path, permissions)?;
+            }
+            fs::rename(&temp_path, &path)
         };
-        self.path = Some(open_path);
+        let written = write();
+        if written.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        written
+    }
+
+    /// Why a write failed, in words, with the common causes told apart.
+    fn describe_write_error(path: &Path, e: &io::Error) -> String {
+        let path = path.display();
+        match (e.kind(), e.raw_os_error()) {
+            (io::ErrorKind::PermissionDenied, _) => {
+                format!("permission denied writing {}", path)
+            }
+            (_, Some(code)) if READ_ONLY_FS_ERRORS.contains(&code) => {
+                format!("{} is on a read-only file system", path)
+            }
+            (_, Some(code)) if DISK_FULL_ERRORS.contains(&code) => {
+                format!("the disk is full, {} was left as it was", path)
+            }
+            _ => format!("{}: {}", path, e),
+        }
+    }
+
+    /// Point at `path` and read it, e.g. for a file named on the command line.
+    /// A file that doesn't exist yet opens empty and is created on save.
+    pub fn open_path(&mut self, path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+        self.path = Some(path.to_path_buf());
         self.is_unsaved = false;
         self.is_new = false;
-        match self.get_contents() {
-            Ok(contents) => Ok(Some(contents)),
-            Err(e) => {
-                // Pass error and return to previous state
-                self.path = old_path;
-                self.is_unsaved = old_is_unsaved;
-                self.is_new = old_is_new;
-                Err(e)
+        self.backed_up = false;
+        match path.exists() {
+            true => self.get_contents(),
+            false => {
+                self.modified = None;
+                Ok(String::new())
             }
         }
     }
 
+    /// What's in the file on disk now, leaving the buffer's state alone.
+    pub fn read_disk(&self) -> Result<String, Box<dyn std::error::Error>> {
+        match &self.path {
+            Some(path) => Ok(Self::decode(&fs::read(path)?)?.0),
+            None => Ok(String::new()),
+        }
+    }
+
+    /// Text of a file as the buffer holds it, with how it was stored.
+    fn decode(bytes: &[u8]) -> Result<(String, Encoding, LineEnding), String> {
+        let encoding = Encoding::detect(bytes);
+        let text = encoding.decode(bytes)?;
+        let line_ending = LineEnding::detect(&text);
+        Ok((LineEnding::normalize(&text), encoding, line_ending))
+    }
+
+    /// Read the file again, dropping any unsaved changes.
+    pub fn reload(&mut self) -> Result<String, Box<dyn std::error::Error>> {
+        let contents = self.get_contents()?;
+        self.is_unsaved = false;
+        Ok(contents)
+    }
+
     fn get_contents(&mut self) -> Result<String, Box<dyn std::error::Error>> {
         let file_path = match &self.path {
             Some(path) => path,
             None => return Err("Path not set".into()),
         };
 
-        let mut file = match File::open(file_path) {
-            Ok(file) => file,
-            Err(e) => return Err(e.into()),
-        };
-        let mut contents = String::new();
-        match file.read_to_string(&mut contents) {
+        let mut file = File::open(file_path)?;
+        let mut bytes = Vec::new();
+        match file.read_to_end(&mut bytes) {
             Ok(_) => (),
             Err(e) => return Err(format!("Error opening file: {}", e).into()),
         };
+        let (contents, encoding, line_ending) = match Self::decode(&bytes) {
+            Ok(decoded) => decoded,
+            Err(e) => return Err(format!("Error opening file: {}", e).into()),
+        };
+        self.encoding = encoding;
+        self.line_ending = line_ending;
+        self.modified = file.metadata().and_then(|meta| meta.modified()).ok();
         Ok(contents)
     }
 
-    fn open_file_sel_dialog() -> Option<PathBuf> {
+    pub fn open_file_sel_dialog() -> Option<PathBuf> {
         rfd::FileDialog::new()
             .set_directory(std::env::var("HOME").unwrap())
             .pick_file()
@@ -138,3 +301,91 @@ impl FileStatus {
             .save_file()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn saves_replace_the_file_and_keep_backups() {
+        let dir = TestDir::new("save");
+        let path = dir.join("notes.txt");
+        fs::write(&path, "first\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        }
+
+        let mut file_status = FileStatus::default();
+        file_status.open_path(&path).unwrap();
+        file_status
+            .save_file("second\n", Backup::FirstSave)
+            .unwrap();
+        file_status.save_file("third\n", Backup::FirstSave).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("notes.txt.bak")).unwrap(),
+            "first\n"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+        //  No temp files left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn saves_keep_encoding_and_line_endings() {
+        let dir = TestDir::new("latin1");
+        let path = dir.join("notes.txt");
+        let bytes = b"caf\xe9\r\nna\xefve\r\n";
+        fs::write(&path, bytes).unwrap();
+        let mut file_status = FileStatus::default();
+        let contents = file_status.open_path(&path).unwrap();
+        assert_eq!(contents, "café\nnaïve\n");
+        assert_eq!(file_status.encoding, Encoding::Latin1);
+        assert_eq!(file_status.line_ending, LineEnding::CrLf);
+        file_status.save_file(&contents, Backup::Off).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+        assert!(file_status.save_file("🦀", Backup::Off).is_err());
+    }
+
+    #[test]
+    fn notices_changes_on_disk() {
+        let dir = TestDir::new("watch");
+        let path = dir.join("notes.txt");
+        fs::write(&path, "on disk\n").unwrap();
+        let mut file_status = FileStatus::default();
+        file_status.open_path(&path).unwrap();
+        assert!(!file_status.changed_on_disk());
+
+        //  As if the file was written since it was read
+        file_status.restore(path.clone(), Some(SystemTime::UNIX_EPOCH));
+        assert!(file_status.changed_on_disk());
+        assert_eq!(file_status.read_disk().unwrap(), "on disk\n");
+        file_status.sync_modified();
+        assert!(!file_status.changed_on_disk());
+
+        fs::remove_file(&path).unwrap();
+        assert!(file_status.changed_on_disk());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_errors_say_what_went_wrong() {
+        let path = Path::new("/notes.txt");
+        let describe =
+            |code| FileStatus::describe_write_error(path, &io::Error::from_raw_os_error(code));
+        assert_eq!(describe(13), "permission denied writing /notes.txt");
+        assert_eq!(describe(30), "/notes.txt is on a read-only file system");
+        assert_eq!(
+            describe(28),
+            "the disk is full, /notes.txt was left as it was"
+        );
+    }
+}
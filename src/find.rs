@@ -1,31 +1,27 @@
+use crate::text_buf::TextBuf;
+use crate::text_pos::TextPos;
+use regex::{Regex, RegexBuilder};
+
 #[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
 #[cfg_attr(feature = "persistence", serde(default))]
+#[derive(Default)]
 pub struct FindTools {
     pub query_buf: String,
-    pub match_locations: Vec<usize>,
+    pub match_locations: Vec<(usize, usize)>,
     selected_loc: usize,
     pub initial_click_made: bool,
     pub replace_mode: bool,
     pub replace_buf: String,
-}
-
-impl Default for FindTools {
-    fn default() -> Self {
-        FindTools {
-            query_buf: String::new(),
-            match_locations: Vec::new(),
-            selected_loc: 0,
-            initial_click_made: false,
-            replace_mode: false,
-            replace_buf: String::new(),
-        }
-    }
+    pub regex_mode: bool,
+    pub case_insensitive: bool,
+    pub whole_word: bool,
+    /// Limit "Replace All" to the editor's selection.
+    pub in_selection: bool,
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    pub error: Option<String>,
 }
 
 impl FindTools {
-    pub fn add_match(&mut self, loc: usize) {
-        self.match_locations.push(loc);
-    }
     pub fn full_reset(&mut self) {
         *self = FindTools::default();
     }
@@ -36,50 +32,131 @@ impl FindTools {
         self.initial_click_made = false;
     }
 
-    pub fn update_matches(&mut self) {
-        //call after using replace on a match4
-        let find_len = self.query_buf.chars().count();
-        let rep_len = self.replace_buf.chars().count();
-        if rep_len > find_len {
-            for i in self.selected_loc..self.match_locations.len() {
-                self.match_locations[i] += rep_len - find_len;
-            }
-        } else if rep_len < find_len {
-            for i in self.selected_loc+1..self.match_locations.len() {
-                self.match_locations[i] -= find_len - rep_len;
-            }
+    /// Find every match of the query in `text`.
+    /// A pattern that doesn't compile is reported through `error`.
+    pub fn search(&mut self, text: &str) {
+        self.reset_matches();
+        self.error = None;
+        if self.query_buf.is_empty() {
+            return;
         }
-        //selected location shoud stay the same index, unless it should wrap
-        //around to the start
-        match self.selected_loc == self.match_locations.len() - 1 {
-            true => {
-                self.match_locations.remove(self.selected_loc);
-                self.selected_loc = 0
-            },
-            false => {
-                self.match_locations.remove(self.selected_loc);
-            ()
+        match self.build_regex() {
+            Ok(regex) => {
+                self.match_locations = regex
+                    .find_iter(text)
+                    .filter(|m| {
+                        !m.as_str().is_empty() && self.is_whole_word(text, m.start(), m.end())
+                    })
+                    .map(|m| (m.start(), m.end()))
+                    .collect();
             }
+            Err(e) => self.error = Some(e.to_string()),
         }
     }
 
-    pub fn get_query(&self) -> String {
-        self.query_buf.clone()
+    /// Search again after the text changed, keeping the selected match index
+    /// so "Replace" moves on to the following match.
+    pub fn refresh(&mut self, text: &str) {
+        let selected_loc = self.selected_loc;
+        let initial_click_made = self.initial_click_made;
+        self.search(text);
+        if selected_loc < self.match_locations.len() {
+            self.selected_loc = selected_loc;
+        }
+        self.initial_click_made = initial_click_made;
+    }
+
+    fn build_regex(&self) -> Result<Regex, regex::Error> {
+        let pattern = match self.regex_mode {
+            true => self.query_buf.clone(),
+            false => regex::escape(&self.query_buf),
+        };
+        RegexBuilder::new(&pattern)
+            .case_insensitive(self.case_insensitive)
+            .multi_line(true)
+            .build()
+    }
+
+    /// Whether the match at `start..end` in `text` passes the whole word option:
+    /// no word char right before or after it. Checked around the match rather
+    /// than with `\b` in the pattern, which would never match a query that
+    /// starts or ends with punctuation, such as `-x` or `.foo`.
+    fn is_whole_word(&self, text: &str, start: usize, end: usize) -> bool {
+        let is_word = |ch: char| ch.is_alphanumeric() || ch == '_';
+        let before = text[..start].chars().next_back();
+        let after = text[end..].chars().next();
+        !self.whole_word || !(before.is_some_and(is_word) || after.is_some_and(is_word))
+    }
+
+    /// Text that should replace the match at `start..end` in `text`, or `None`
+    /// if the query no longer matches there because the text has changed.
+    /// In regex mode `$1` and `${name}` in the replacement refer to capture groups.
+    pub fn replacement_for(&self, text: &str, start: usize, end: usize) -> Option<String> {
+        let regex = self.build_regex().ok()?;
+        let caps = regex
+            .captures_iter(text)
+            .find(|caps| caps.get(0).map(|m| (m.start(), m.end())) == Some((start, end)))?;
+        let mut replacement = String::new();
+        match self.regex_mode {
+            true => caps.expand(&self.replace_buf, &mut replacement),
+            false => replacement.push_str(&self.replace_buf),
+        }
+        Some(replacement)
+    }
+
+    /// `text` with every match replaced in a single pass, and the number of
+    /// replacements made. With `within` set, only matches lying entirely
+    /// inside that byte range are replaced.
+    pub fn replace_all(
+        &self,
+        text: &str,
+        within: Option<(usize, usize)>,
+    ) -> Result<(String, usize), regex::Error> {
+        let regex = self.build_regex()?;
+        let (lower, upper) = within.unwrap_or((0, text.len()));
+        let mut replaced = String::with_capacity(text.len());
+        let mut count = 0;
+        let mut last_end = 0;
+        for caps in regex.captures_iter(text) {
+            let found = match caps.get(0) {
+                Some(found) => found,
+                None => continue,
+            };
+            if found.as_str().is_empty()
+                || found.start() < lower
+                || found.end() > upper
+                || !self.is_whole_word(text, found.start(), found.end())
+            {
+                continue;
+            }
+            replaced.push_str(&text[last_end..found.start()]);
+            match self.regex_mode {
+                true => caps.expand(&self.replace_buf, &mut replaced),
+                false => replaced.push_str(&self.replace_buf),
+            }
+            last_end = found.end();
+            count += 1;
+        }
+        replaced.push_str(&text[last_end..]);
+        Ok((replaced, count))
     }
 
     pub fn get_current_match(&mut self) -> Option<(usize, usize)> {
-        //Returns Some(current match starting index, current query len)
+        //Returns Some(current match starting byte, current match end byte)
         if !self.match_locations.is_empty() {
             self.initial_click_made = true;
-            Some((
-                self.match_locations[self.selected_loc],
-                self.query_buf.chars().count(),
-            ))
+            Some(self.match_locations[self.selected_loc])
         } else {
             None
         }
     }
 
+    /// Start and end of the current match, for placing the editor's cursor.
+    pub fn get_current_match_pos(&mut self, text: &TextBuf) -> Option<(TextPos, TextPos)> {
+        let (start, end) = self.get_current_match()?;
+        Some((text.pos_at_byte(start), text.pos_at_byte(end)))
+    }
+
     pub fn number_of_matches(&self) -> usize {
         self.match_locations.len()
     }
@@ -100,3 +177,81 @@ impl FindTools {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_after_multibyte_text_select_the_right_chars() {
+        let text = "naïve café, café au lait";
+        let mut finder = FindTools {
+            query_buf: "café".to_string(),
+            ..Default::default()
+        };
+        finder.search(text);
+        assert_eq!(finder.number_of_matches(), 2);
+        finder.selected_loc_inc();
+        let (start, end) = finder
+            .get_current_match_pos(&TextBuf::from(text.to_string()))
+            .unwrap();
+        assert_eq!(&text[start.byte..end.byte], "café");
+        assert_eq!((start.char, end.char), (12, 16));
+    }
+
+    #[test]
+    fn replace_keeps_later_multibyte_matches_valid() {
+        let mut text = String::from("ö-ö-ö");
+        let mut finder = FindTools {
+            query_buf: "ö".to_string(),
+            replace_buf: "oe".to_string(),
+            ..Default::default()
+        };
+        finder.search(&text);
+        while let Some((start, end)) = finder.get_current_match() {
+            let replacement = finder.replacement_for(&text, start, end).unwrap();
+            text.replace_range(start..end, &replacement);
+            finder.refresh(&text);
+        }
+        assert_eq!(text, "oe-oe-oe");
+        //  A match made stale by an edit isn't replaced
+        finder.search("ö-ö");
+        assert_eq!(finder.replacement_for("-ö", 0, 2), None);
+    }
+
+    #[test]
+    fn replace_all_with_longer_text_and_selection() {
+        let finder = FindTools {
+            query_buf: "a".to_string(),
+            replace_buf: "äää".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            finder.replace_all("a-a-a", None).unwrap(),
+            ("äää-äää-äää".to_string(), 3)
+        );
+        assert_eq!(
+            finder.replace_all("a-a-a", Some((1, 5))).unwrap(),
+            ("a-äää-äää".to_string(), 2)
+        );
+    }
+
+    #[test]
+    fn whole_words_may_start_or_end_with_punctuation() {
+        let mut finder = FindTools {
+            query_buf: "-x".to_string(),
+            replace_buf: "-y".to_string(),
+            whole_word: true,
+            ..Default::default()
+        };
+        let text = "ls -x; ls -xa; a-x";
+        finder.search(text);
+        assert_eq!(finder.match_locations, vec![(3, 5)]);
+        assert_eq!(
+            finder.replace_all(text, None).unwrap(),
+            ("ls -y; ls -xa; a-x".to_string(), 1)
+        );
+        finder.query_buf = "café".to_string();
+        finder.search("café cafés");
+        assert_eq!(finder.match_locations, vec![(0, 5)]);
+    }
+}
@@ -0,0 +1,114 @@
+//! Fonts picked from disk for the editor and for the rest of the UI.
+//! egui's built-in fonts, compiled into every build, stay behind them as
+//! fallbacks for glyphs a picked font lacks. The web build can't pick fonts,
+//! so it has a fallback set of its own compiled in behind egui's.
+//! Fonts with ligatures can be used, but egui draws glyph by glyph so the
+//! ligatures show as their separate characters.
+
+use crate::app_config::AppConfig;
+use eframe::egui::{FontDefinitions, FontFamily, TextStyle};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// Fonts for the web build, covering more scripts and symbols than egui's.
+#[cfg(target_arch = "wasm32")]
+const FALLBACK_FONTS: [(&str, FontFamily, &[u8]); 2] = [
+    (
+        "DejaVuSansMono",
+        FontFamily::Monospace,
+        include_bytes!("../fonts/DejaVuSansMono.ttf"),
+    ),
+    (
+        "DejaVuSans",
+        FontFamily::Proportional,
+        include_bytes!("../fonts/DejaVuSans.ttf"),
+    ),
+];
+
+/// Read a TTF or OTF file, checking it parses first as egui panics on fonts it can't read.
+pub fn read_font(path: &Path) -> Result<Vec<u8>, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if ab_glyph::FontRef::try_from_slice(&bytes).is_err() {
+        return Err(format!("{} is not a TTF or OTF font", path.display()));
+    }
+    Ok(bytes)
+}
+
+/// Font definitions for the fonts and size in `config`. Fonts that can't
+/// be read are reported and dropped from `config`, so the error shows once.
+pub fn load(config: &mut AppConfig) -> (FontDefinitions, Vec<String>) {
+    let mut fonts = FontDefinitions::default();
+    let mut errors = Vec::new();
+    let size = config.get_font_size();
+    let picked: [(FontFamily, &mut Option<PathBuf>); 2] = [
+        (FontFamily::Proportional, &mut config.ui_font),
+        (FontFamily::Monospace, &mut config.editor_font),
+    ];
+    for (family, path) in picked {
+        let font_path = match path.as_deref() {
+            Some(font_path) => font_path,
+            None => continue,
+        };
+        match read_font(font_path) {
+            Ok(bytes) => {
+                let name = format!("{:?}: {}", family, font_path.display());
+                fonts.font_data.insert(name.clone(), Cow::Owned(bytes));
+                //  First in the list, so egui's fonts are only used for what it lacks
+                fonts
+                    .fonts_for_family
+                    .entry(family)
+                    .or_default()
+                    .insert(0, name);
+            }
+            Err(e) => {
+                errors.push(e);
+                *path = None;
+            }
+        }
+    }
+    #[cfg(target_arch = "wasm32")]
+    for (name, family, bytes) in FALLBACK_FONTS {
+        fonts
+            .font_data
+            .insert(name.to_string(), Cow::Borrowed(bytes));
+        fonts
+            .fonts_for_family
+            .entry(family)
+            .or_default()
+            .push(name.to_string());
+    }
+    fonts
+        .family_and_size
+        .insert(TextStyle::Monospace, (FontFamily::Monospace, size));
+    (fonts, errors)
+}
+
+/// File name of a picked font, for menus.
+pub fn font_name(path: &Option<PathBuf>) -> String {
+    match path.as_deref().and_then(Path::file_name) {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => "Default".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn bad_fonts_fall_back_to_the_defaults() {
+        let dir = TestDir::new("font");
+        let path = dir.join("bad.ttf");
+        std::fs::write(&path, "not a font").unwrap();
+        let mut config = AppConfig::default();
+        config.editor_font = Some(path.clone());
+        let (fonts, errors) = load(&mut config);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(config.editor_font, None);
+        assert_eq!(
+            fonts.font_data.len(),
+            FontDefinitions::default().font_data.len()
+        );
+    }
+}
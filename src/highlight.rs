@@ -0,0 +1,538 @@
+use crate::theme::SyntaxColors;
+use eframe::egui;
+use eframe::egui::text::{LayoutJob, TextFormat};
+use eframe::egui::Color32;
+use std::ops::Range;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+pub enum Language {
+    PlainText,
+    Rust,
+    Toml,
+    Json,
+    Markdown,
+    Python,
+    Shell,
+}
+
+impl Language {
+    pub const ALL: [Language; 7] = [
+        Language::PlainText,
+        Language::Rust,
+        Language::Toml,
+        Language::Json,
+        Language::Markdown,
+        Language::Python,
+        Language::Shell,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Language::PlainText => "Plain Text",
+            Language::Rust => "Rust",
+            Language::Toml => "TOML",
+            Language::Json => "JSON",
+            Language::Markdown => "Markdown",
+            Language::Python => "Python",
+            Language::Shell => "Shell",
+        }
+    }
+
+    /// Short stable name, used when a language has to be written down.
+    pub fn id(&self) -> &'static str {
+        match self {
+            Language::PlainText => "text",
+            Language::Rust => "rust",
+            Language::Toml => "toml",
+            Language::Json => "json",
+            Language::Markdown => "markdown",
+            Language::Python => "python",
+            Language::Shell => "shell",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Language> {
+        Language::ALL
+            .iter()
+            .copied()
+            .find(|language| language.id() == id)
+    }
+
+    /// Guess the language from a file extension, falling back to plain text.
+    pub fn from_path(path: Option<&Path>) -> Language {
+        let extension = path
+            .and_then(|path| path.extension())
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("rs") => Language::Rust,
+            Some("toml") => Language::Toml,
+            Some("json") => Language::Json,
+            Some("md") | Some("markdown") => Language::Markdown,
+            Some("py") | Some("pyw") => Language::Python,
+            Some("sh") | Some("bash") | Some("zsh") => Language::Shell,
+            _ => Language::PlainText,
+        }
+    }
+
+    fn syntax(&self) -> Option<&'static Syntax> {
+        match self {
+            Language::Rust => Some(&RUST),
+            Language::Toml => Some(&TOML),
+            Language::Json => Some(&JSON),
+            Language::Python => Some(&PYTHON),
+            Language::Shell => Some(&SHELL),
+            Language::PlainText | Language::Markdown => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenKind {
+    Plain,
+    Comment,
+    String,
+    Number,
+    Keyword,
+    Literal,
+    Punctuation,
+    Heading,
+    Key,
+}
+
+impl TokenKind {
+    fn color(&self, colors: &SyntaxColors) -> Color32 {
+        match self {
+            TokenKind::Plain => colors.plain,
+            TokenKind::Comment => colors.comment,
+            TokenKind::String => colors.string,
+            TokenKind::Number => colors.number,
+            TokenKind::Keyword => colors.keyword,
+            TokenKind::Literal => colors.literal,
+            TokenKind::Punctuation => colors.punctuation,
+            TokenKind::Heading => colors.heading,
+            TokenKind::Key => colors.key,
+        }
+    }
+}
+
+/// Lexical rules for the C-like languages, which all share one tokenizer.
+struct Syntax {
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    /// Longest delimiters first, so `"""` wins over `"`.
+    quotes: &'static [&'static str],
+    multiline_strings: bool,
+    keywords: &'static [&'static str],
+    literals: &'static [&'static str],
+    /// Marks the token right before this character as a key, e.g. `"name":` in JSON.
+    key_separator: Option<char>,
+    /// Highlights `[table]` lines as headings.
+    section_headers: bool,
+    /// Highlights `$NAME` variables as keys.
+    variable_sigil: Option<char>,
+    /// Treats `'a` as a lifetime rather than the start of a char literal.
+    lifetimes: bool,
+}
+
+const RUST: Syntax = Syntax {
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &["\"", "'"],
+    multiline_strings: true,
+    keywords: &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+        "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut",
+        "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait", "type",
+        "unsafe", "use", "where", "while",
+    ],
+    literals: &["true", "false", "None", "Some", "Ok", "Err"],
+    key_separator: None,
+    section_headers: false,
+    variable_sigil: None,
+    lifetimes: true,
+};
+
+const TOML: Syntax = Syntax {
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &["\"\"\"", "'''", "\"", "'"],
+    multiline_strings: false,
+    keywords: &[],
+    literals: &["true", "false", "inf", "nan"],
+    key_separator: Some('='),
+    section_headers: true,
+    variable_sigil: None,
+    lifetimes: false,
+};
+
+const JSON: Syntax = Syntax {
+    line_comments: &[],
+    block_comment: None,
+    quotes: &["\""],
+    multiline_strings: false,
+    keywords: &[],
+    literals: &["true", "false", "null"],
+    key_separator: Some(':'),
+    section_headers: false,
+    variable_sigil: None,
+    lifetimes: false,
+};
+
+const PYTHON: Syntax = Syntax {
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &["\"\"\"", "'''", "\"", "'"],
+    multiline_strings: false,
+    keywords: &[
+        "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+        "elif", "else", "except", "finally", "for", "from", "global", "if", "import", "in", "is",
+        "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while", "with",
+        "yield",
+    ],
+    literals: &["True", "False", "None", "self"],
+    key_separator: None,
+    section_headers: false,
+    variable_sigil: None,
+    lifetimes: false,
+};
+
+const SHELL: Syntax = Syntax {
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &["\"", "'"],
+    multiline_strings: true,
+    keywords: &[
+        "if", "then", "else", "elif", "fi", "case", "esac", "for", "while", "until", "do", "done",
+        "in", "function", "return", "local", "export", "readonly", "shift", "exit", "set", "unset",
+        "source",
+    ],
+    literals: &["true", "false"],
+    key_separator: None,
+    section_headers: false,
+    variable_sigil: Some('$'),
+    lifetimes: false,
+};
+
+/// Split `code` into colored spans covering the whole text.
+pub fn tokenize(language: Language, code: &str) -> Vec<(Range<usize>, TokenKind)> {
+    let mut tokens = match (language, language.syntax()) {
+        (Language::Markdown, _) => tokenize_markdown(code),
+        (_, Some(syntax)) => tokenize_with(syntax, code),
+        (_, None) => vec![(0..code.len(), TokenKind::Plain)],
+    };
+    //  Merge neighbours of the same kind so the layout job stays small
+    tokens.retain(|(range, _)| !range.is_empty());
+    tokens.dedup_by(|next, prev| {
+        if prev.1 == next.1 && prev.0.end == next.0.start {
+            prev.0.end = next.0.end;
+            true
+        } else {
+            false
+        }
+    });
+    tokens
+}
+
+/// Build a colored layout job for the editor, cached while the text is unchanged.
+/// The editor only passes in the lines it lays out, so tokenizing starts
+/// afresh a little above what's in view.
+pub fn highlight(
+    ctx: &egui::Context,
+    language: Language,
+    code: &str,
+    colors: SyntaxColors,
+) -> LayoutJob {
+    type HighlightCache<'a> = egui::util::cache::FrameCache<LayoutJob, Highlighter>;
+
+    let mut memory = ctx.memory();
+    let highlight_cache = memory.caches.cache::<HighlightCache<'_>>();
+    highlight_cache.get((language, code, colors))
+}
+
+#[derive(Default)]
+struct Highlighter {}
+
+impl egui::util::cache::ComputerMut<(Language, &str, SyntaxColors), LayoutJob> for Highlighter {
+    fn compute(&mut self, (language, code, colors): (Language, &str, SyntaxColors)) -> LayoutJob {
+        self.highlight(language, code, &colors)
+    }
+}
+
+impl Highlighter {
+    fn highlight(&self, language: Language, code: &str, colors: &SyntaxColors) -> LayoutJob {
+        let mut job = LayoutJob::default();
+        for (range, kind) in tokenize(language, code) {
+            job.append(
+                &code[range],
+                0.0,
+                TextFormat {
+                    style: egui::TextStyle::Monospace,
+                    color: kind.color(colors),
+                    ..Default::default()
+                },
+            );
+        }
+        job
+    }
+}
+
+fn is_ident_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+/// Byte length of the run of characters at the start of `text` matching `pred`.
+fn run_len(text: &str, pred: impl Fn(char) -> bool) -> usize {
+    text.find(|ch: char| !pred(ch)).unwrap_or(text.len())
+}
+
+fn tokenize_with(syntax: &Syntax, code: &str) -> Vec<(Range<usize>, TokenKind)> {
+    let mut tokens: Vec<(Range<usize>, TokenKind)> = Vec::new();
+    let mut at_line_start = true;
+    let mut i = 0;
+    while i < code.len() {
+        let rest = &code[i..];
+        let ch = rest.chars().next().unwrap_or_default();
+        let (len, kind) = if ch == '\n' {
+            (1, TokenKind::Plain)
+        } else if ch.is_whitespace() {
+            (
+                run_len(rest, |c| c.is_whitespace() && c != '\n'),
+                TokenKind::Plain,
+            )
+        } else if syntax.line_comments.iter().any(|c| rest.starts_with(c)) {
+            (run_len(rest, |c| c != '\n'), TokenKind::Comment)
+        } else if let Some((open, close)) = syntax
+            .block_comment
+            .filter(|(open, _)| rest.starts_with(open))
+        {
+            let end = rest[open.len()..]
+                .find(close)
+                .map(|pos| open.len() + pos + close.len())
+                .unwrap_or(rest.len());
+            (end, TokenKind::Comment)
+        } else if syntax.section_headers && at_line_start && ch == '[' {
+            (run_len(rest, |c| c != '\n'), TokenKind::Heading)
+        } else if let Some(len) = scan_string(syntax, rest) {
+            (len, TokenKind::String)
+        } else if ch.is_ascii_digit() {
+            (
+                run_len(rest, |c| is_ident_char(c) || c == '.'),
+                TokenKind::Number,
+            )
+        } else if syntax.variable_sigil == Some(ch) {
+            let name_len = run_len(&rest[1..], |c| is_ident_char(c) || c == '{' || c == '}');
+            (1 + name_len, TokenKind::Key)
+        } else if is_ident_char(ch) {
+            let len = run_len(rest, is_ident_char);
+            let word = &rest[..len];
+            let kind = if syntax.keywords.contains(&word) {
+                TokenKind::Keyword
+            } else if syntax.literals.contains(&word) {
+                TokenKind::Literal
+            } else {
+                TokenKind::Plain
+            };
+            (len, kind)
+        } else {
+            if Some(ch) == syntax.key_separator {
+                mark_previous_as_key(&mut tokens, code);
+            }
+            (ch.len_utf8(), TokenKind::Punctuation)
+        };
+        at_line_start = ch == '\n' || (at_line_start && ch.is_whitespace());
+        tokens.push((i..i + len, kind));
+        i += len;
+    }
+    tokens
+}
+
+/// Length of the string literal at the start of `rest`, if there is one.
+fn scan_string(syntax: &Syntax, rest: &str) -> Option<usize> {
+    let quote = syntax.quotes.iter().find(|q| rest.starts_with(*q))?;
+    let multiline = syntax.multiline_strings || quote.len() > 1;
+    let mut escaped = false;
+    for (pos, ch) in rest.char_indices().skip(quote.chars().count()) {
+        if escaped {
+            escaped = false;
+        } else if ch == '\\' {
+            escaped = true;
+        } else if rest[pos..].starts_with(quote) {
+            return Some(pos + quote.len());
+        } else if ch == '\n' && !multiline {
+            return Some(pos);
+        }
+        //  A quote that doesn't close straight away is a lifetime, e.g. `'a`
+        if syntax.lifetimes && *quote == "'" && pos > 4 {
+            return None;
+        }
+    }
+    match syntax.lifetimes && *quote == "'" {
+        true => None,
+        false => Some(rest.len()),
+    }
+}
+
+/// Turn the last non-whitespace token into a key.
+fn mark_previous_as_key(tokens: &mut [(Range<usize>, TokenKind)], code: &str) {
+    if let Some(token) = tokens
+        .iter_mut()
+        .rev()
+        .find(|(range, _)| !code[range.clone()].trim().is_empty())
+    {
+        if token.1 == TokenKind::String || token.1 == TokenKind::Plain {
+            token.1 = TokenKind::Key;
+        }
+    }
+}
+
+fn tokenize_markdown(code: &str) -> Vec<(Range<usize>, TokenKind)> {
+    let mut tokens = Vec::new();
+    let mut in_fence = false;
+    let mut line_start = 0;
+    for line in code.split_inclusive('\n') {
+        let range = line_start..line_start + line.len();
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+            tokens.push((range, TokenKind::Punctuation));
+        } else if in_fence {
+            tokens.push((range, TokenKind::String));
+        } else if trimmed.starts_with('#') {
+            tokens.push((range, TokenKind::Heading));
+        } else if trimmed.starts_with('>') {
+            tokens.push((range, TokenKind::Comment));
+        } else {
+            let indent = line.len() - trimmed.len();
+            let marker = list_marker_len(trimmed);
+            tokens.push((line_start..line_start + indent, TokenKind::Plain));
+            tokens.push((
+                line_start + indent..line_start + indent + marker,
+                TokenKind::Punctuation,
+            ));
+            tokenize_markdown_inline(&mut tokens, line, line_start, indent + marker);
+        }
+        line_start += line.len();
+    }
+    tokens
+}
+
+fn list_marker_len(trimmed: &str) -> usize {
+    for bullet in &["- ", "* ", "+ "] {
+        if trimmed.starts_with(bullet) {
+            return bullet.len();
+        }
+    }
+    let digits = run_len(trimmed, |c| c.is_ascii_digit());
+    if digits > 0 && trimmed[digits..].starts_with(". ") {
+        return digits + 2;
+    }
+    0
+}
+
+/// Inline code, emphasis and links within one Markdown line.
+fn tokenize_markdown_inline(
+    tokens: &mut Vec<(Range<usize>, TokenKind)>,
+    line: &str,
+    line_start: usize,
+    mut i: usize,
+) {
+    while i < line.len() {
+        let rest = &line[i..];
+        let ch = rest.chars().next().unwrap_or_default();
+        //  Length up to the closing `delim`, if `rest` opens with one
+        let closing = |delim: &str| {
+            rest.strip_prefix(delim)?
+                .find(delim)
+                .map(|pos| pos + 2 * delim.len())
+        };
+        //  Links are split into their text and target parts
+        if ch == '[' {
+            if let Some((mid, close)) = rest
+                .find("](")
+                .and_then(|mid| Some((mid, mid + rest[mid..].find(')')?)))
+            {
+                let start = line_start + i;
+                tokens.push((start..start + mid + 1, TokenKind::Key));
+                tokens.push((start + mid + 1..start + close + 1, TokenKind::Literal));
+                i += close + 1;
+                continue;
+            }
+        }
+        let (len, kind) = if let Some(len) = closing("`") {
+            (len, TokenKind::String)
+        } else if let Some(len) = closing("**").or_else(|| closing("__")) {
+            (len, TokenKind::Keyword)
+        } else if let Some(len) = closing("*") {
+            (len, TokenKind::Keyword)
+        } else {
+            let len = run_len(&rest[ch.len_utf8()..], |c| !"`*_[".contains(c));
+            (ch.len_utf8() + len, TokenKind::Plain)
+        };
+        tokens.push((line_start + i..line_start + i + len, kind));
+        i += len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tokens of `code`, which should cover it in order.
+    fn tokens(language: Language, code: &str) -> Vec<(&str, TokenKind)> {
+        let tokens = tokenize(language, code);
+        let mut end = 0;
+        for (range, _) in &tokens {
+            assert_eq!(range.start, end);
+            end = range.end;
+        }
+        assert_eq!(end, code.len());
+        tokens
+            .into_iter()
+            .map(|(range, kind)| (&code[range], kind))
+            .collect()
+    }
+
+    #[test]
+    fn non_ascii_text_is_tokenized_in_every_language() {
+        let code = "été 日本 \"ü\" # ü\n";
+        for language in Language::ALL {
+            tokens(language, code);
+        }
+
+        let rust = tokens(Language::Rust, "let é = \"ß\"; // ñ\n'ü' 'a");
+        assert!(rust.contains(&("\"ß\"", TokenKind::String)));
+        assert!(rust.contains(&("// ñ", TokenKind::Comment)));
+        assert!(rust.contains(&("'ü'", TokenKind::String)));
+
+        let toml = tokens(Language::Toml, "[tablé]\nnäme = \"ö\" # ü");
+        assert!(toml.contains(&("[tablé]", TokenKind::Heading)));
+        assert!(toml.contains(&("näme", TokenKind::Key)));
+        assert!(toml.contains(&("# ü", TokenKind::Comment)));
+
+        let json = tokens(Language::Json, "{\"ключ\": \"значение\"}");
+        assert!(json.contains(&("\"ключ\"", TokenKind::Key)));
+        assert!(json.contains(&("\"значение\"", TokenKind::String)));
+
+        let python = tokens(Language::Python, "def naïve(): return 'ü' # é");
+        assert!(python.contains(&("def", TokenKind::Keyword)));
+        assert!(python.contains(&("'ü'", TokenKind::String)));
+        assert!(python.contains(&("# é", TokenKind::Comment)));
+
+        let shell = tokens(Language::Shell, "echo \"$ÿ\" ü # ñ");
+        assert!(shell.contains(&("\"$ÿ\"", TokenKind::String)));
+        assert!(shell.contains(&("# ñ", TokenKind::Comment)));
+
+        let markdown = tokens(
+            Language::Markdown,
+            "été `ç` **ø**\n# Überschrift\n[a](b)é *ñ*\n- ü\n",
+        );
+        assert!(markdown.contains(&("`ç`", TokenKind::String)));
+        assert!(markdown.contains(&("**ø**", TokenKind::Keyword)));
+        assert!(markdown.contains(&("# Überschrift\n", TokenKind::Heading)));
+        assert!(markdown.contains(&("(b)", TokenKind::Literal)));
+        assert!(markdown.contains(&("*ñ*", TokenKind::Keyword)));
+    }
+}
@@ -0,0 +1,420 @@
+use crate::text_buf::TextBuf;
+use std::collections::VecDeque;
+
+/// Number of undo groups kept per document before the oldest are dropped.
+const HISTORY_LIMIT: usize = 200;
+/// Typing within this many seconds of the last edit is undone together.
+const GROUP_TIMEOUT: f64 = 1.0;
+
+/// A single replacement of `removed` by `inserted` at byte offset `start`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edit {
+    pub start: usize,
+    pub removed: String,
+    pub inserted: String,
+}
+
+impl Edit {
+    /// Smallest edit turning `old` into `new`, or None if they are equal.
+    pub fn diff(old: &str, new: &str) -> Option<Edit> {
+        if old == new {
+            return None;
+        }
+        let prefix = old
+            .char_indices()
+            .zip(new.chars())
+            .find(|((_, old_ch), new_ch)| old_ch != new_ch)
+            .map(|((i, _), _)| i)
+            .unwrap_or_else(|| old.len().min(new.len()));
+        let suffix: usize = old[prefix..]
+            .chars()
+            .rev()
+            .zip(new[prefix..].chars().rev())
+            .take_while(|(old_ch, new_ch)| old_ch == new_ch)
+            .map(|(ch, _)| ch.len_utf8())
+            .sum();
+        Some(Edit {
+            start: prefix,
+            removed: old[prefix..old.len() - suffix].to_string(),
+            inserted: new[prefix..new.len() - suffix].to_string(),
+        })
+    }
+
+    fn apply(&self, buf: &mut TextBuf) {
+        buf.replace_range(self.start..self.start + self.removed.len(), &self.inserted);
+    }
+
+    fn revert(&self, buf: &mut TextBuf) {
+        buf.replace_range(self.start..self.start + self.inserted.len(), &self.removed);
+    }
+
+    /// Shift by what `other` adds or removes before this edit.
+    fn shift(&mut self, other: &Edit) {
+        self.start = self.start + other.inserted.len() - other.removed.len();
+    }
+
+    /// For an edit on the undo stack: move it past `remote`, made on the text
+    /// this edit led to, and move `remote` back to the text before this edit.
+    /// False if the two overlap.
+    fn undo_past(&mut self, remote: &mut Edit) -> bool {
+        if remote.start + remote.removed.len() <= self.start {
+            self.shift(remote);
+        } else if remote.start >= self.start + self.inserted.len() {
+            remote.start = remote.start + self.removed.len() - self.inserted.len();
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// For an edit on the redo stack: move it past `remote`, made on the text
+    /// this edit applies to, and move `remote` on to the text after this edit.
+    /// False if the two overlap.
+    fn redo_past(&mut self, remote: &mut Edit) -> bool {
+        if remote.start + remote.removed.len() <= self.start {
+            self.shift(remote);
+        } else if remote.start >= self.start + self.removed.len() {
+            remote.start = remote.start + self.inserted.len() - self.removed.len();
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+/// Lines that differ between `old` and `new`, marked `-` and `+`,
+/// with a couple of unchanged lines around them.
+pub fn diff_lines(old: &str, new: &str) -> String {
+    const CONTEXT: usize = 2;
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let prefix = old_lines
+        .iter()
+        .zip(new_lines.iter())
+        .take_while(|(old_line, new_line)| old_line == new_line)
+        .count();
+    let suffix = old_lines[prefix..]
+        .iter()
+        .rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|(old_line, new_line)| old_line == new_line)
+        .count();
+    if prefix == old_lines.len() && prefix == new_lines.len() {
+        return String::new();
+    }
+    let mut diff = String::new();
+    for line in old_lines[prefix.saturating_sub(CONTEXT)..prefix].iter() {
+        diff.push_str(&format!("  {}\n", line));
+    }
+    for line in old_lines[prefix..old_lines.len() - suffix].iter() {
+        diff.push_str(&format!("- {}\n", line));
+    }
+    for line in new_lines[prefix..new_lines.len() - suffix].iter() {
+        diff.push_str(&format!("+ {}\n", line));
+    }
+    let after = &old_lines[old_lines.len() - suffix..];
+    for line in after[..after.len().min(CONTEXT)].iter() {
+        diff.push_str(&format!("  {}\n", line));
+    }
+    diff
+}
+
+/// Undo/redo stacks for one document.
+/// Edits are taken from the buffer's edit log as they are made, so every
+/// code path that changes a buffer only has to call `record` or one of its
+/// siblings, and nothing is compared or copied but the edits themselves.
+#[derive(Default)]
+pub struct History {
+    undo_stack: VecDeque<Vec<Edit>>,
+    redo_stack: Vec<Vec<Edit>>,
+    /// Revision of the buffer up to which its edits have been taken in.
+    last_revision: u64,
+    last_edit_time: Option<f64>,
+}
+
+impl History {
+    /// Forget all edits and start tracking from `buf`.
+    pub fn reset(&mut self, buf: &TextBuf) {
+        *self = History::default();
+        self.last_revision = buf.revision();
+    }
+
+    /// Record a change made by typing. Changes made in quick succession
+    /// are grouped and undone together.
+    pub fn record(&mut self, buf: &TextBuf, time: f64) {
+        let merge = match self.last_edit_time {
+            Some(last) => time - last < GROUP_TIMEOUT,
+            None => false,
+        };
+        self.push(buf, merge);
+        self.last_edit_time = Some(time);
+    }
+
+    /// Record a change as its own undo step, e.g. a replace from the Find popup.
+    pub fn record_group(&mut self, buf: &TextBuf) {
+        self.push(buf, false);
+        self.last_edit_time = None;
+    }
+
+    /// Take in a change made by someone else, e.g. a collaborator in a live
+    /// session, without making it undoable. Undo and redo move the user's own
+    /// edits around it instead. An edit it overlaps can't be undone any more,
+    /// and neither can anything before it, or redone after it.
+    pub fn record_remote(&mut self, buf: &TextBuf) {
+        let edits = self.take_edits(buf);
+        if edits.is_empty() {
+            return;
+        }
+        self.last_edit_time = None;
+
+        for remote in edits {
+            let mut moving = remote.clone();
+            let overlapped = self.undo_stack.iter_mut().rposition(|group| {
+                !group
+                    .iter_mut()
+                    .rev()
+                    .all(|edit| edit.undo_past(&mut moving))
+            });
+            if let Some(index) = overlapped {
+                self.undo_stack.drain(..=index);
+            }
+            let mut moving = remote;
+            let overlapped = self
+                .redo_stack
+                .iter_mut()
+                .rposition(|group| !group.iter_mut().all(|edit| edit.redo_past(&mut moving)));
+            if let Some(index) = overlapped {
+                self.redo_stack.drain(..=index);
+            }
+        }
+    }
+
+    fn push(&mut self, buf: &TextBuf, merge: bool) {
+        let edits = self.take_edits(buf);
+        if edits.is_empty() {
+            return;
+        }
+        self.redo_stack.clear();
+        match (merge, self.undo_stack.back_mut()) {
+            (true, Some(group)) => group.extend(edits),
+            _ => {
+                self.undo_stack.push_back(edits);
+                if self.undo_stack.len() > HISTORY_LIMIT {
+                    self.undo_stack.pop_front();
+                }
+            }
+        }
+    }
+
+    /// The edits made to `buf` since they were last taken in.
+    fn take_edits(&mut self, buf: &TextBuf) -> Vec<Edit> {
+        let edits = buf.edits_since(self.last_revision);
+        self.last_revision = buf.revision();
+        edits.unwrap_or_else(|| {
+            //  Too much changed at once to follow, or the buffer was
+            //  replaced, so the stacks no longer fit the text
+            self.undo_stack.clear();
+            self.redo_stack.clear();
+            Vec::new()
+        })
+    }
+
+    /// Revert the edits made to `buf` since they were last taken in, e.g.
+    /// the editor's own undo. False if they can't be, and the stacks are cleared.
+    fn drop_pending(&mut self, buf: &mut TextBuf) -> bool {
+        match buf.edits_since(self.last_revision) {
+            Some(edits) => {
+                for edit in edits.iter().rev() {
+                    edit.revert(buf);
+                }
+                self.last_revision = buf.revision();
+                true
+            }
+            None => {
+                self.take_edits(buf);
+                false
+            }
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
+    }
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    /// Undo the last group of edits on `buf`.
+    /// Returns the byte offset where the cursor should be placed.
+    pub fn undo(&mut self, buf: &mut TextBuf) -> Option<usize> {
+        let group = self.undo_stack.pop_back()?;
+        if !self.drop_pending(buf) {
+            return None;
+        }
+        for edit in group.iter().rev() {
+            edit.revert(buf);
+        }
+        let cursor = group.first().map(|edit| edit.start + edit.removed.len());
+        self.last_revision = buf.revision();
+        self.last_edit_time = None;
+        self.redo_stack.push(group);
+        cursor
+    }
+
+    /// Redo the last undone group of edits on `buf`.
+    /// Returns the byte offset where the cursor should be placed.
+    pub fn redo(&mut self, buf: &mut TextBuf) -> Option<usize> {
+        let group = self.redo_stack.pop()?;
+        if !self.drop_pending(buf) {
+            return None;
+        }
+        for edit in group.iter() {
+            edit.apply(buf);
+        }
+        let cursor = group.last().map(|edit| edit.start + edit.inserted.len());
+        self.last_revision = buf.revision();
+        self.last_edit_time = None;
+        self.undo_stack.push_back(group);
+        cursor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_shows_changed_lines() {
+        let old = "a\nb\nc\nd\ne\nf\n";
+        let new = "a\nb\nc\nD\nE\ne\nf\n";
+        assert_eq!(diff_lines(old, new), "  b\n  c\n- d\n+ D\n+ E\n  e\n  f\n");
+        assert_eq!(diff_lines(old, old), "");
+    }
+
+    /// Append `text` to `buf` as if typed at `time`.
+    fn type_at(history: &mut History, buf: &mut TextBuf, text: &str, time: f64) {
+        buf.replace_range(buf.len()..buf.len(), text);
+        history.record(buf, time);
+    }
+
+    #[test]
+    fn undo_and_redo_round_trip() {
+        let mut buf = TextBuf::from("fn".to_string());
+        let mut history = History::default();
+        history.reset(&buf);
+        assert!(!history.can_undo());
+        type_at(&mut history, &mut buf, " main", 0.0);
+        buf.replace_range(0..2, "pub fn");
+        history.record_group(&buf);
+
+        //  The cursor goes where the edit was
+        assert_eq!(history.undo(&mut buf), Some(2));
+        assert_eq!(buf.to_string(), "fn main");
+        assert_eq!(history.undo(&mut buf), Some(2));
+        assert_eq!(buf.to_string(), "fn");
+        assert!(!history.can_undo());
+        assert_eq!(history.undo(&mut buf), None);
+
+        assert_eq!(history.redo(&mut buf), Some(7));
+        assert_eq!(buf.to_string(), "fn main");
+        assert_eq!(history.redo(&mut buf), Some(6));
+        assert_eq!(buf.to_string(), "pub fn main");
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn typing_in_quick_succession_is_undone_together() {
+        let mut buf = TextBuf::default();
+        let mut history = History::default();
+        history.reset(&buf);
+        type_at(&mut history, &mut buf, "a", 0.0);
+        type_at(&mut history, &mut buf, "b", 0.5);
+        type_at(&mut history, &mut buf, "c", 0.5 + GROUP_TIMEOUT * 0.9);
+        //  A pause starts a new group
+        type_at(&mut history, &mut buf, "d", 5.0);
+        history.undo(&mut buf);
+        assert_eq!(buf.to_string(), "abc");
+        history.undo(&mut buf);
+        assert_eq!(buf.to_string(), "");
+    }
+
+    #[test]
+    fn grouped_edits_break_up_typing() {
+        let mut buf = TextBuf::default();
+        let mut history = History::default();
+        history.reset(&buf);
+        type_at(&mut history, &mut buf, "a", 0.0);
+        buf.replace_range(0..1, "A");
+        history.record_group(&buf);
+        //  Typing right after doesn't join the replace
+        type_at(&mut history, &mut buf, "b", 0.1);
+        type_at(&mut history, &mut buf, "c", 0.2);
+        history.undo(&mut buf);
+        assert_eq!(buf.to_string(), "A");
+        history.undo(&mut buf);
+        assert_eq!(buf.to_string(), "a");
+        history.undo(&mut buf);
+        assert_eq!(buf.to_string(), "");
+    }
+
+    #[test]
+    fn new_edits_clear_redo() {
+        let mut buf = TextBuf::default();
+        let mut history = History::default();
+        history.reset(&buf);
+        type_at(&mut history, &mut buf, "one", 0.0);
+        history.undo(&mut buf);
+        assert!(history.can_redo());
+        type_at(&mut history, &mut buf, "two", 5.0);
+        assert!(!history.can_redo());
+        assert_eq!(history.redo(&mut buf), None);
+        assert_eq!(buf.to_string(), "two");
+    }
+
+    #[test]
+    fn oldest_groups_are_dropped_past_the_limit() {
+        let mut buf = TextBuf::default();
+        let mut history = History::default();
+        history.reset(&buf);
+        for index in 0..HISTORY_LIMIT + 5 {
+            buf.replace_range(buf.len()..buf.len(), "x");
+            history.record_group(&buf);
+            assert_eq!(buf.len(), index + 1);
+        }
+        let mut undone = 0;
+        while history.undo(&mut buf).is_some() {
+            undone += 1;
+        }
+        assert_eq!(undone, HISTORY_LIMIT);
+        assert_eq!(buf.to_string(), "xxxxx");
+    }
+
+    #[test]
+    fn undo_skips_remote_edits() {
+        let mut buf = TextBuf::from("one two".to_string());
+        let mut history = History::default();
+        history.reset(&buf);
+        buf.replace_range(3..3, " and");
+        history.record_group(&buf);
+        buf.replace_range(0..0, "¡");
+        history.record_remote(&buf);
+        buf.replace_range(buf.len()..buf.len(), "!");
+        history.record_remote(&buf);
+        history.undo(&mut buf);
+        assert_eq!(buf.to_string(), "¡one two!");
+        history.redo(&mut buf);
+        assert_eq!(buf.to_string(), "¡one and two!");
+        assert!(!history.can_redo());
+
+        history.undo(&mut buf);
+        buf.replace_range(5..5, "x");
+        history.record_remote(&buf);
+        history.redo(&mut buf);
+        assert_eq!(buf.to_string(), "¡onex and two!");
+
+        //  An edit someone else changed can't be undone
+        buf.replace_range(7..10, "&");
+        history.record_remote(&buf);
+        assert!(!history.can_undo());
+    }
+}
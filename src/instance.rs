@@ -0,0 +1,255 @@
+//! Lets `code_share FILE` open a tab in an editor that is already running,
+//! instead of starting another window. The running editor listens on a
+//! localhost port, written along with a random token to a file only the user
+//! can read; a new launch sends the token and its arguments, and exits once
+//! the editor answers `ok`. The port file is removed when the editor quits,
+//! and an editor started while another is listening leaves it alone.
+
+use crate::app_config::config_dir;
+use crate::cli::LaunchOptions;
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const GREETING: &str = "code_share";
+const PORT_FILE: &str = "port";
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Where the port file goes: the user's runtime dir, or else a private dir
+/// next to the settings.
+fn instance_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("code_share")),
+        _ => config_dir().map(|dir| dir.join("instance")),
+    }
+}
+
+fn no_instance_dir() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "No place for the port file")
+}
+
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+fn new_token() -> String {
+    let state = RandomState::new();
+    (0..2u8)
+        .map(|half| {
+            let mut hasher = state.build_hasher();
+            half.hash(&mut hasher);
+            SystemTime::now().hash(&mut hasher);
+            std::process::id().hash(&mut hasher);
+            format!("{:016x}", hasher.finish())
+        })
+        .collect()
+}
+
+/// The request for `args`: greeting and token lines, then each argument as
+/// its length in bytes on a line of its own followed by the argument, so
+/// arguments may hold any character, newlines included.
+fn encode_request(token: &str, args: &[String]) -> String {
+    let mut request = format!("{}\n{}\n", GREETING, token);
+    for arg in args {
+        request.push_str(&format!("{}\n{}", arg.len(), arg));
+    }
+    request
+}
+
+/// The token and arguments of a request, None if it isn't one.
+fn decode_request(request: &str) -> Option<(&str, Vec<String>)> {
+    let rest = request.strip_prefix(GREETING)?.strip_prefix('\n')?;
+    let (token, mut rest) = rest.split_once('\n')?;
+    let mut args = Vec::new();
+    while !rest.is_empty() {
+        let (len, after) = rest.split_once('\n')?;
+        let len = len.parse().ok()?;
+        args.push(after.get(..len)?.to_string());
+        rest = &after[len..];
+    }
+    Some((token, args))
+}
+
+/// Send `args` to the editor whose port file is in `dir`, returning its answer.
+fn send(dir: &Path, args: &[String]) -> io::Result<String> {
+    let path = dir.join(PORT_FILE);
+    let port_file = fs::read_to_string(&path)?;
+    let (port, token) = port_file
+        .split_once(' ')
+        .and_then(|(port, token)| Some((port.parse::<u16>().ok()?, token)))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Malformed port file"))?;
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let mut stream = match TcpStream::connect_timeout(&addr, TIMEOUT) {
+        Ok(stream) => stream,
+        Err(e) => {
+            //  Left behind by an editor that didn't get to clean up
+            if e.kind() == io::ErrorKind::ConnectionRefused {
+                let _ = fs::remove_file(&path);
+            }
+            return Err(e);
+        }
+    };
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.write_all(encode_request(token, args).as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+/// Hand `options` to a running editor. Returns false if there is none,
+/// or it didn't answer in time.
+pub fn forward(options: &LaunchOptions) -> bool {
+    match instance_dir() {
+        Some(dir) => forward_in(&dir, options),
+        None => false,
+    }
+}
+
+fn forward_in(dir: &Path, options: &LaunchOptions) -> bool {
+    matches!(send(dir, &options.to_args()), Ok(reply) if reply == "ok")
+}
+
+/// This editor's port file, see `listen`.
+pub struct Listener {
+    path: PathBuf,
+    contents: String,
+}
+
+impl Listener {
+    /// Remove the port file, unless another editor has written its own since.
+    pub fn close(&self) {
+        if fs::read_to_string(&self.path).ok().as_deref() == Some(self.contents.as_str()) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Accept forwarded launches for the rest of the run, passing each one to
+/// `on_launch` from a background thread. Fails if another editor already
+/// takes them.
+pub fn listen(on_launch: impl Fn(LaunchOptions) + Send + 'static) -> io::Result<Listener> {
+    listen_in(&instance_dir().ok_or_else(no_instance_dir)?, on_launch)
+}
+
+fn listen_in(
+    dir: &Path,
+    on_launch: impl Fn(LaunchOptions) + Send + 'static,
+) -> io::Result<Listener> {
+    //  Launches without arguments are only answered, so this checks for a
+    //  running editor without opening anything in it
+    if matches!(send(dir, &[]), Ok(reply) if reply == "ok") {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "Another editor takes launches",
+        ));
+    }
+    create_private_dir(dir)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let token = new_token();
+    let path = dir.join(PORT_FILE);
+    let contents = format!("{} {}", listener.local_addr()?.port(), token);
+    write_private(&path, &contents)?;
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let _ = stream.set_read_timeout(Some(TIMEOUT));
+            let mut request = String::new();
+            if stream.read_to_string(&mut request).is_err() {
+                continue;
+            }
+            let args = match decode_request(&request) {
+                Some((sent_token, args)) if sent_token == token => args,
+                _ => continue,
+            };
+            let reply = match args.is_empty() {
+                true => "ok".to_string(),
+                false => match LaunchOptions::parse(args) {
+                    Ok(options) => {
+                        on_launch(options);
+                        "ok".to_string()
+                    }
+                    Err(e) => e,
+                },
+            };
+            let _ = stream.write_all(reply.as_bytes());
+        }
+    });
+    Ok(Listener { path, contents })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use std::sync::mpsc;
+
+    #[test]
+    fn launches_reach_the_running_editor() {
+        let dir = TestDir::new("instance");
+        let file = "odd\nname.txt".to_string();
+        let options = LaunchOptions::parse(["--readonly".to_string(), file.clone()]).unwrap();
+        assert!(!forward_in(dir.path(), &options));
+
+        let (launch_tx, launch_rx) = mpsc::channel();
+        let listener = listen_in(dir.path(), move |options| {
+            let _ = launch_tx.send(options);
+        })
+        .unwrap();
+        //  Only one editor takes launches
+        assert!(listen_in(dir.path(), |_| {}).is_err());
+        assert!(forward_in(dir.path(), &options));
+        let forwarded = launch_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(forwarded.read_only);
+        assert!(forwarded.files[0].path.ends_with(&file));
+
+        //  Nothing gets through without the token
+        let port_file = fs::read_to_string(dir.join(PORT_FILE)).unwrap();
+        let port: u16 = port_file.split(' ').next().unwrap().parse().unwrap();
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let request = encode_request("guessed", &["--".to_string(), file]);
+        stream.write_all(request.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "");
+        assert!(launch_rx.try_recv().is_err());
+
+        listener.close();
+        assert!(!dir.join(PORT_FILE).exists());
+    }
+
+    #[test]
+    fn stale_port_files_are_removed() {
+        let dir = TestDir::new("stale");
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        fs::write(dir.join(PORT_FILE), format!("{} token", port)).unwrap();
+        assert!(!forward_in(dir.path(), &LaunchOptions::default()));
+        assert!(!dir.join(PORT_FILE).exists());
+    }
+}
@@ -0,0 +1,492 @@
+//! Named commands and the keys bound to them. The defaults can be changed
+//! in Tools > Keyboard Shortcuts, which writes a `keymap.toml` like:
+//!
+//! ```toml
+//! save = "Ctrl+S"
+//! redo = ["Ctrl+Shift+Z", "Ctrl+Y"]
+//! find_next = []
+//! ```
+//!
+//! Commands left out keep their default keys, and `[]` leaves one unbound.
+
+use eframe::egui::{self, Key, Modifiers};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::PathBuf;
+
+/// Declares `Command` with, for each one, the name shown in menus, the name
+/// used in the keymap file and its default keys, so `Command::ALL` can't miss one.
+macro_rules! commands {
+    ($(
+        $(#[$attr:meta])*
+        $command:ident => ($name:literal, $id:literal, [$($key:literal),*]),
+    )*) => {
+        /// Everything the menus, shortcuts and command palette can do.
+        #[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub enum Command {
+            $($(#[$attr])* $command,)*
+        }
+
+        impl Command {
+            pub const ALL: &'static [Command] = &[$($(#[$attr])* Command::$command,)*];
+
+            /// Name shown in menus.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $($(#[$attr])* Command::$command => $name,)*
+                }
+            }
+
+            /// Name used in the keymap file.
+            pub fn id(self) -> &'static str {
+                match self {
+                    $($(#[$attr])* Command::$command => $id,)*
+                }
+            }
+
+            fn default_keys(self) -> &'static [&'static str] {
+                match self {
+                    $($(#[$attr])* Command::$command => &[$($key),*],)*
+                }
+            }
+        }
+    };
+}
+
+commands! {
+    NewFile => ("New", "new", ["Ctrl+N"]),
+    OpenFile => ("Open", "open", ["Ctrl+O"]),
+    Save => ("Save", "save", ["Ctrl+S"]),
+    SaveAs => ("Save As", "save_as", ["Ctrl+Shift+S"]),
+    Share => ("Share", "share", []),
+    #[cfg(not(target_arch = "wasm32"))]
+    PublishPaste => ("Publish to Server", "publish", []),
+    #[cfg(not(target_arch = "wasm32"))]
+    OpenPaste => ("Open from Server", "open_from_server", []),
+    #[cfg(all(feature = "persistence", not(target_arch = "wasm32")))]
+    SaveSession => ("Save Session...", "save_session", []),
+    #[cfg(all(feature = "persistence", not(target_arch = "wasm32")))]
+    OpenSession => ("Open Session...", "open_session", []),
+    CloseTab => ("Close Tab", "close_tab", ["Ctrl+W"]),
+    Preferences => ("Preferences", "preferences", []),
+    Quit => ("Quit", "quit", ["Ctrl+Q"]),
+    Undo => ("Undo", "undo", ["Ctrl+Z"]),
+    Redo => ("Redo", "redo", ["Ctrl+Shift+Z", "Ctrl+Y"]),
+    Find => ("Find", "find", ["Ctrl+F"]),
+    FindAndReplace => ("Find and Replace", "find_and_replace", ["Ctrl+H"]),
+    FindNext => ("Find Next", "find_next", []),
+    FindPrevious => ("Find Previous", "find_previous", []),
+    GoToLine => ("Go to Line", "go_to_line", ["Ctrl+G"]),
+    FontBigger => ("Bigger Font", "font_bigger", []),
+    FontSmaller => ("Smaller Font", "font_smaller", []),
+    ToggleLineNumbers => ("Line Numbers", "line_numbers", []),
+    ToggleWordWrap => ("Word Wrap", "word_wrap", ["Alt+Z"]),
+    ThemeDark => ("Dark Theme", "theme_dark", []),
+    ThemeLight => ("Light Theme", "theme_light", []),
+    ThemeHighContrast => ("High Contrast Theme", "theme_high_contrast", []),
+    #[cfg(not(target_arch = "wasm32"))]
+    OpenThemeFile => ("Open Theme File...", "open_theme_file", []),
+    #[cfg(not(target_arch = "wasm32"))]
+    ReloadThemeFile => ("Reload Theme File", "reload_theme_file", []),
+    #[cfg(not(target_arch = "wasm32"))]
+    ChooseEditorFont => ("Choose Editor Font...", "choose_editor_font", []),
+    #[cfg(not(target_arch = "wasm32"))]
+    ChooseUiFont => ("Choose UI Font...", "choose_ui_font", []),
+    DefaultEditorFont => ("Default Editor Font", "default_editor_font", []),
+    DefaultUiFont => ("Default UI Font", "default_ui_font", []),
+    SyntaxAuto => ("Syntax: Auto Detect", "syntax_auto", []),
+    SyntaxPlainText => ("Syntax: Plain Text", "syntax_plain_text", []),
+    SyntaxRust => ("Syntax: Rust", "syntax_rust", []),
+    SyntaxToml => ("Syntax: TOML", "syntax_toml", []),
+    SyntaxJson => ("Syntax: JSON", "syntax_json", []),
+    SyntaxMarkdown => ("Syntax: Markdown", "syntax_markdown", []),
+    SyntaxPython => ("Syntax: Python", "syntax_python", []),
+    SyntaxShell => ("Syntax: Shell", "syntax_shell", []),
+    EncodingUtf8 => ("Encoding: UTF-8", "encoding_utf8", []),
+    EncodingUtf8Bom => ("Encoding: UTF-8 BOM", "encoding_utf8_bom", []),
+    EncodingUtf16Le => ("Encoding: UTF-16 LE", "encoding_utf16_le", []),
+    EncodingUtf16Be => ("Encoding: UTF-16 BE", "encoding_utf16_be", []),
+    EncodingLatin1 => ("Encoding: Latin-1", "encoding_latin1", []),
+    LineEndingsLf => ("Line Endings: LF", "line_endings_lf", []),
+    LineEndingsCrLf => ("Line Endings: CRLF", "line_endings_crlf", []),
+    LineEndingsCr => ("Line Endings: CR", "line_endings_cr", []),
+    #[cfg(not(target_arch = "wasm32"))]
+    AutosaveOff => ("Autosave: Off", "autosave_off", []),
+    #[cfg(not(target_arch = "wasm32"))]
+    AutosaveTimed => ("Autosave: Timed", "autosave_timed", []),
+    #[cfg(not(target_arch = "wasm32"))]
+    AutosaveFocusLost => ("Autosave: When Leaving the Window", "autosave_focus_lost", []),
+    #[cfg(not(target_arch = "wasm32"))]
+    BackupOff => ("Backups: Never", "backup_off", []),
+    #[cfg(not(target_arch = "wasm32"))]
+    BackupFirstSave => ("Backups: On First Save", "backup_first_save", []),
+    #[cfg(not(target_arch = "wasm32"))]
+    BackupEverySave => ("Backups: On Every Save", "backup_every_save", []),
+    #[cfg(not(target_arch = "wasm32"))]
+    Collaborate => ("Collaborate", "collaborate", []),
+    KeyboardShortcuts => ("Keyboard Shortcuts", "keyboard_shortcuts", []),
+    Palette => ("Command Palette", "command_palette", ["Ctrl+Shift+P"]),
+}
+
+impl Command {
+    pub fn from_id(id: &str) -> Option<Command> {
+        Command::ALL
+            .iter()
+            .copied()
+            .find(|command| command.id() == id)
+    }
+}
+
+/// Ctrl on Windows and Linux, Cmd on a Mac, like egui's `Modifiers::command`.
+const COMMAND_KEY: &str = match cfg!(target_os = "macos") {
+    true => "Cmd",
+    false => "Ctrl",
+};
+
+const KEY_NAMES: [(Key, &str); 53] = [
+    (Key::ArrowDown, "Down"),
+    (Key::ArrowLeft, "Left"),
+    (Key::ArrowRight, "Right"),
+    (Key::ArrowUp, "Up"),
+    (Key::Escape, "Escape"),
+    (Key::Tab, "Tab"),
+    (Key::Backspace, "Backspace"),
+    (Key::Enter, "Enter"),
+    (Key::Space, "Space"),
+    (Key::Insert, "Insert"),
+    (Key::Delete, "Delete"),
+    (Key::Home, "Home"),
+    (Key::End, "End"),
+    (Key::PageUp, "PageUp"),
+    (Key::PageDown, "PageDown"),
+    (Key::Num0, "0"),
+    (Key::Num1, "1"),
+    (Key::Num2, "2"),
+    (Key::Num3, "3"),
+    (Key::Num4, "4"),
+    (Key::Num5, "5"),
+    (Key::Num6, "6"),
+    (Key::Num7, "7"),
+    (Key::Num8, "8"),
+    (Key::Num9, "9"),
+    (Key::A, "A"),
+    (Key::B, "B"),
+    (Key::C, "C"),
+    (Key::D, "D"),
+    (Key::E, "E"),
+    (Key::F, "F"),
+    (Key::G, "G"),
+    (Key::H, "H"),
+    (Key::I, "I"),
+    (Key::J, "J"),
+    (Key::K, "K"),
+    (Key::L, "L"),
+    (Key::M, "M"),
+    (Key::N, "N"),
+    (Key::O, "O"),
+    (Key::P, "P"),
+    (Key::Q, "Q"),
+    (Key::R, "R"),
+    (Key::S, "S"),
+    (Key::T, "T"),
+    (Key::U, "U"),
+    (Key::V, "V"),
+    (Key::W, "W"),
+    (Key::X, "X"),
+    (Key::Y, "Y"),
+    (Key::Z, "Z"),
+    //  Other spellings, only used when reading
+    (Key::Escape, "Esc"),
+    (Key::Delete, "Del"),
+];
+
+/// A key with the modifiers held down, e.g. `Ctrl+Shift+Z`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyCombo {
+    pub command: bool,
+    pub shift: bool,
+    pub alt: bool,
+    pub key: Key,
+}
+
+impl KeyCombo {
+    pub fn new(key: Key, modifiers: &Modifiers) -> KeyCombo {
+        KeyCombo {
+            command: modifiers.command,
+            shift: modifiers.shift,
+            alt: modifiers.alt,
+            key,
+        }
+    }
+
+    /// Parse e.g. `Ctrl+Shift+Z`, ignoring case.
+    pub fn parse(text: &str) -> Result<KeyCombo, String> {
+        let mut parts: Vec<&str> = text.split('+').map(str::trim).collect();
+        let key_name = parts.pop().unwrap_or_default();
+        let key = KEY_NAMES
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(key_name))
+            .map(|(key, _)| *key)
+            .ok_or_else(|| format!("unknown key {:?} in {:?}", key_name, text))?;
+        let mut combo = KeyCombo::new(key, &Modifiers::default());
+        for modifier in parts {
+            let held = match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "cmd" | "command" => &mut combo.command,
+                "shift" => &mut combo.shift,
+                "alt" | "option" => &mut combo.alt,
+                _ => return Err(format!("unknown modifier {:?} in {:?}", modifier, text)),
+            };
+            *held = true;
+        }
+        Ok(combo)
+    }
+
+    /// Whether this can be bound to a command. Without Ctrl or Alt held, the
+    /// key would also type into the editor or move its caret. egui reports no
+    /// function keys, the only ones that would do on their own.
+    pub fn is_shortcut(&self) -> bool {
+        self.command || self.alt
+    }
+
+    /// Why this can't be bound to a command, for error messages.
+    pub fn not_a_shortcut(&self) -> String {
+        format!("{} needs {} or Alt held", self, COMMAND_KEY)
+    }
+
+    /// The first key pressed this frame.
+    pub fn first_pressed(input: &egui::InputState) -> Option<KeyCombo> {
+        input.events.iter().find_map(|event| match event {
+            egui::Event::Key {
+                key,
+                pressed: true,
+                modifiers,
+            } => Some(KeyCombo::new(*key, modifiers)),
+            _ => None,
+        })
+    }
+}
+
+impl fmt::Display for KeyCombo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.command {
+            write!(f, "{}+", COMMAND_KEY)?;
+        }
+        if self.shift {
+            write!(f, "Shift+")?;
+        }
+        if self.alt {
+            write!(f, "Alt+")?;
+        }
+        let name = KEY_NAMES
+            .iter()
+            .find(|(key, _)| *key == self.key)
+            .map_or("?", |(_, name)| *name);
+        write!(f, "{}", name)
+    }
+}
+
+/// The keys bound to each command.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    bindings: BTreeMap<Command, Vec<KeyCombo>>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let bindings = Command::ALL
+            .iter()
+            .map(|command| {
+                let keys = command
+                    .default_keys()
+                    .iter()
+                    .map(|key| KeyCombo::parse(key).unwrap())
+                    .collect();
+                (*command, keys)
+            })
+            .collect();
+        Keymap { bindings }
+    }
+}
+
+impl Keymap {
+    /// Where the keymap is kept, see `app_config::config_dir`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn path() -> Option<PathBuf> {
+        crate::app_config::config_dir().map(|dir| dir.join("keymap.toml"))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Keymap, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Keymap::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// The defaults, changed by the TOML text of a keymap file.
+    pub fn parse(text: &str) -> Result<Keymap, String> {
+        let table: toml::Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| e.message().to_string())?;
+        let mut keymap = Keymap::default();
+        for (id, keys) in table.iter() {
+            let command = Command::from_id(id).ok_or_else(|| format!("unknown command {}", id))?;
+            let keys = match keys {
+                toml::Value::String(key) => vec![key.as_str()],
+                toml::Value::Array(keys) => keys
+                    .iter()
+                    .map(|key| key.as_str())
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| format!("{} should list keys like \"Ctrl+S\"", id))?,
+                _ => return Err(format!("{} should be a key like \"Ctrl+S\"", id)),
+            };
+            let keys = keys
+                .into_iter()
+                .map(|key| match KeyCombo::parse(key)? {
+                    combo if combo.is_shortcut() => Ok(combo),
+                    combo => Err(combo.not_a_shortcut()),
+                })
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("{}: {}", id, e))?;
+            keymap.bindings.insert(command, keys);
+        }
+        Ok(keymap)
+    }
+
+    /// Every binding as keymap file text.
+    pub fn to_toml(&self) -> String {
+        let table: toml::Table = self
+            .bindings
+            .iter()
+            .map(|(command, keys)| {
+                let keys = keys
+                    .iter()
+                    .map(|key| toml::Value::String(key.to_string()))
+                    .collect();
+                (command.id().to_string(), toml::Value::Array(keys))
+            })
+            .collect();
+        table.to_string()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        std::fs::write(path, self.to_toml()).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn keys(&self, command: Command) -> &[KeyCombo] {
+        self.bindings.get(&command).map_or(&[], Vec::as_slice)
+    }
+
+    /// The first key bound to `command`, for showing next to its name.
+    pub fn shortcut(&self, command: Command) -> Option<String> {
+        self.keys(command).first().map(KeyCombo::to_string)
+    }
+
+    pub fn bind(&mut self, command: Command, key: KeyCombo) {
+        let keys = self.bindings.entry(command).or_default();
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    pub fn unbind(&mut self, command: Command, key: KeyCombo) {
+        if let Some(keys) = self.bindings.get_mut(&command) {
+            keys.retain(|bound| *bound != key);
+        }
+    }
+
+    /// Commands bound to `key`, in menu order.
+    pub fn commands_for(&self, key: KeyCombo) -> Vec<Command> {
+        Command::ALL
+            .iter()
+            .copied()
+            .filter(|command| self.keys(*command).contains(&key))
+            .collect()
+    }
+
+    /// Keys bound to more than one command. Only the first command runs.
+    pub fn conflicts(&self) -> Vec<(KeyCombo, Vec<Command>)> {
+        let mut by_key: BTreeMap<KeyCombo, Vec<Command>> = BTreeMap::new();
+        for command in Command::ALL.iter() {
+            for key in self.keys(*command) {
+                by_key.entry(*key).or_default().push(*command);
+            }
+        }
+        by_key
+            .into_iter()
+            .filter(|(_, commands)| commands.len() > 1)
+            .collect()
+    }
+
+    /// Commands whose keys were pressed this frame.
+    pub fn pressed(&self, input: &egui::InputState) -> Vec<Command> {
+        input
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                egui::Event::Key {
+                    key,
+                    pressed: true,
+                    modifiers,
+                } => self
+                    .commands_for(KeyCombo::new(*key, modifiers))
+                    .first()
+                    .copied(),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keymap_files_rebind_and_conflict() {
+        let keymap = Keymap::parse(
+            r#"
+            find = "ctrl+shift+f"
+            find_next = ["Ctrl+S", "Alt+Down"]
+            undo = []
+            "#,
+        )
+        .unwrap();
+        let ctrl_s = KeyCombo::parse("Ctrl+S").unwrap();
+        assert_eq!(
+            keymap.shortcut(Command::Find),
+            Some(format!("{}+Shift+F", COMMAND_KEY))
+        );
+        assert!(keymap.keys(Command::Undo).is_empty());
+        assert_eq!(keymap.keys(Command::Redo).len(), 2);
+        assert_eq!(
+            keymap.conflicts(),
+            vec![(ctrl_s, vec![Command::Save, Command::FindNext])]
+        );
+        assert_eq!(Keymap::parse(&keymap.to_toml()), Ok(keymap));
+
+        assert!(Keymap::parse("sve = \"Ctrl+S\"").is_err());
+        assert!(Keymap::parse("save = \"Ctrl+F13\"").is_err());
+        assert!(Keymap::parse("save = \"Hyper+S\"").is_err());
+        assert!(Keymap::parse("save = \"Shift+S\"").is_err());
+        assert!(Keymap::parse("go_to_line = [\"Ctrl+G\", \"Home\"]").is_err());
+        assert!(Keymap::default().conflicts().is_empty());
+    }
+
+    #[test]
+    fn commands_have_their_own_ids_and_valid_default_keys() {
+        let ids: std::collections::BTreeSet<&str> =
+            Command::ALL.iter().map(|command| command.id()).collect();
+        assert_eq!(ids.len(), Command::ALL.len());
+        for command in Command::ALL {
+            assert_eq!(Command::from_id(command.id()), Some(*command));
+            for key in command.default_keys() {
+                assert!(KeyCombo::parse(key).is_ok_and(|combo| combo.is_shortcut()));
+            }
+        }
+    }
+}
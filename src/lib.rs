@@ -4,8 +4,35 @@
 
 mod app;
 mod app_config;
+pub mod cli;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod collab;
+mod document;
+mod encoding;
 mod file;
 mod find;
+mod fonts;
+mod highlight;
+mod history;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod instance;
+mod keymap;
+#[cfg(not(target_arch = "wasm32"))]
+mod ot;
+mod palette;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod paste;
+mod preview;
+mod rope;
+mod session;
+mod share;
+#[cfg(not(target_arch = "wasm32"))]
+mod swap;
+#[cfg(test)]
+mod test_dir;
+mod text_buf;
+mod text_pos;
+mod theme;
 pub use app::CodeShare;
 
 // ----------------------------------------------------------------------------
@@ -21,6 +48,10 @@ use eframe::wasm_bindgen::{self, prelude::*};
 #[cfg(target_arch = "wasm32")]
 #[wasm_bindgen]
 pub fn start(canvas_id: &str) -> Result<(), eframe::wasm_bindgen::JsValue> {
-    let app = CodeShare::default();
+    let mut app = CodeShare::default();
+    //  Open a snippet shared through the link's #fragment, if there is one
+    if let Some(href) = web_sys::window().and_then(|window| window.location().href().ok()) {
+        app.open_share_link(&href);
+    }
     eframe::start_web(canvas_id, Box::new(app))
 }
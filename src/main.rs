@@ -5,7 +5,35 @@
 // When compiling natively:
 #[cfg(not(target_arch = "wasm32"))]
 fn main() {
-    let app = code_share::CodeShare::default();
+    use code_share::cli::{self, LaunchOptions};
+
+    let mut options = match LaunchOptions::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if options.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    //  Open the files in the editor that's already running, if there is one.
+    //  Without files there's nothing for it to open, so start a new window.
+    if !options.new_window
+        && !options.stdin
+        && !options.files.is_empty()
+        && code_share::instance::forward(&options)
+    {
+        return;
+    }
+    if let Err(e) = options.read_stdin() {
+        eprintln!("Could not read stdin: {}", e);
+        std::process::exit(1);
+    }
+
+    let mut app = code_share::CodeShare::default();
+    app.open_launch_options(options);
     let native_options = eframe::NativeOptions::default();
     eframe::run_native(Box::new(app), native_options);
 }
@@ -0,0 +1,308 @@
+use crate::history::Edit;
+use crate::text_buf::TextBuf;
+use std::fmt::Write;
+
+/// One step of an `Operation`, counted in chars.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Component {
+    Retain(usize),
+    Insert(String),
+    Delete(usize),
+}
+
+/// A change to a whole text, as a run of retains, inserts and deletes covering
+/// every char of it. Concurrent operations are reconciled with `transform`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Operation {
+    components: Vec<Component>,
+    base_len: usize,
+}
+
+impl Operation {
+    /// The operation turning `old` into `new`, or None if they are equal.
+    pub fn from_diff(old: &str, new: &str) -> Option<Operation> {
+        let edit = Edit::diff(old, new)?;
+        let mut op = Operation::default();
+        op.retain(old[..edit.start].chars().count());
+        op.delete(edit.removed.chars().count());
+        op.insert(&edit.inserted);
+        op.retain(old[edit.start + edit.removed.len()..].chars().count());
+        Some(op)
+    }
+
+    /// The operation making `edit` to `old`, the text before it.
+    pub fn from_edit(old: &TextBuf, edit: &Edit) -> Operation {
+        let start = old.byte_to_char(edit.start);
+        let removed = edit.removed.chars().count();
+        let mut op = Operation::default();
+        op.retain(start);
+        op.delete(removed);
+        op.insert(&edit.inserted);
+        op.retain(old.len_chars() - start - removed);
+        op
+    }
+
+    pub fn retain(&mut self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.base_len += count;
+        match self.components.last_mut() {
+            Some(Component::Retain(last)) => *last += count,
+            _ => self.components.push(Component::Retain(count)),
+        }
+    }
+
+    pub fn insert(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        //  Keep inserts before deletes, so equal operations look the same
+        match self.components.as_mut_slice() {
+            [.., Component::Insert(last)] | [.., Component::Insert(last), Component::Delete(_)] => {
+                last.push_str(text)
+            }
+            [.., Component::Delete(_)] => {
+                let delete = self.components.pop();
+                self.components.push(Component::Insert(text.to_string()));
+                self.components.extend(delete);
+            }
+            _ => self.components.push(Component::Insert(text.to_string())),
+        }
+    }
+
+    pub fn delete(&mut self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.base_len += count;
+        match self.components.last_mut() {
+            Some(Component::Delete(last)) => *last += count,
+            _ => self.components.push(Component::Delete(count)),
+        }
+    }
+
+    /// Length in chars of the text this operation applies to.
+    pub fn base_len(&self) -> usize {
+        self.base_len
+    }
+
+    pub fn apply(&self, text: &str) -> Result<String, String> {
+        if text.chars().count() != self.base_len {
+            return Err("Operation doesn't match the text it is applied to".to_string());
+        }
+        let mut chars = text.chars();
+        let mut result = String::with_capacity(text.len());
+        for component in self.components.iter() {
+            match component {
+                Component::Retain(count) => result.extend(chars.by_ref().take(*count)),
+                Component::Insert(inserted) => result.push_str(inserted),
+                Component::Delete(count) => {
+                    chars.by_ref().take(*count).for_each(drop);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Same as `apply`, editing `text` in place.
+    pub fn apply_to(&self, text: &mut TextBuf) -> Result<(), String> {
+        if text.len_chars() != self.base_len {
+            return Err("Operation doesn't match the text it is applied to".to_string());
+        }
+        //  Char index into the text as edited so far
+        let mut pos = 0;
+        for component in self.components.iter() {
+            match component {
+                Component::Retain(count) => pos += count,
+                Component::Insert(inserted) => {
+                    let byte = text.char_to_byte(pos);
+                    text.replace_range(byte..byte, inserted);
+                    pos += inserted.chars().count();
+                }
+                Component::Delete(count) => {
+                    let start = text.char_to_byte(pos);
+                    let end = text.char_to_byte(pos + count);
+                    text.replace_range(start..end, "");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the char index `index` ends up once this operation is applied.
+    pub fn transform_index(&self, index: usize) -> usize {
+        let mut new_index = index;
+        let mut pos = 0;
+        for component in self.components.iter() {
+            if pos > index {
+                break;
+            }
+            match component {
+                Component::Retain(count) => pos += count,
+                Component::Insert(inserted) => new_index += inserted.chars().count(),
+                Component::Delete(count) => {
+                    new_index -= (*count).min(index - pos);
+                    pos += count;
+                }
+            }
+        }
+        new_index
+    }
+
+    /// Rewrite two operations made concurrently on the same text so that
+    /// `a` then `b'` gives the same result as `b` then `a'`.
+    /// Where both insert at the same spot, `a`'s text comes first.
+    pub fn transform(a: &Operation, b: &Operation) -> Result<(Operation, Operation), String> {
+        if a.base_len != b.base_len {
+            return Err("Concurrent operations don't start from the same text".to_string());
+        }
+        let mut a_prime = Operation::default();
+        let mut b_prime = Operation::default();
+        let mut a_iter = a.components.iter().cloned();
+        let mut b_iter = b.components.iter().cloned();
+        let mut a_next = a_iter.next();
+        let mut b_next = b_iter.next();
+        loop {
+            match (a_next.take(), b_next.take()) {
+                (None, None) => break,
+                (Some(Component::Insert(text)), other) => {
+                    a_prime.insert(&text);
+                    b_prime.retain(text.chars().count());
+                    a_next = a_iter.next();
+                    b_next = other;
+                }
+                (other, Some(Component::Insert(text))) => {
+                    a_prime.retain(text.chars().count());
+                    b_prime.insert(&text);
+                    a_next = other;
+                    b_next = b_iter.next();
+                }
+                (Some(a_comp), Some(b_comp)) => {
+                    let a_len = Operation::component_len(&a_comp);
+                    let b_len = Operation::component_len(&b_comp);
+                    let len = a_len.min(b_len);
+                    match (&a_comp, &b_comp) {
+                        (Component::Retain(_), Component::Retain(_)) => {
+                            a_prime.retain(len);
+                            b_prime.retain(len);
+                        }
+                        (Component::Delete(_), Component::Retain(_)) => a_prime.delete(len),
+                        (Component::Retain(_), Component::Delete(_)) => b_prime.delete(len),
+                        //  Both deleted the same chars, nothing left to do
+                        _ => (),
+                    }
+                    a_next = Operation::shortened(a_comp, len).or_else(|| a_iter.next());
+                    b_next = Operation::shortened(b_comp, len).or_else(|| b_iter.next());
+                }
+                _ => return Err("Operations cover texts of different lengths".to_string()),
+            }
+        }
+        Ok((a_prime, b_prime))
+    }
+
+    fn component_len(component: &Component) -> usize {
+        match component {
+            Component::Retain(count) | Component::Delete(count) => *count,
+            Component::Insert(text) => text.chars().count(),
+        }
+    }
+
+    //  What's left of a retain or delete after `len` chars have been dealt with
+    fn shortened(component: Component, len: usize) -> Option<Component> {
+        match component {
+            Component::Retain(count) if count > len => Some(Component::Retain(count - len)),
+            Component::Delete(count) if count > len => Some(Component::Delete(count - len)),
+            _ => None,
+        }
+    }
+
+    /// Compact text form, e.g. `r4i5:hellod2`. Inserts carry their length
+    /// in chars, so the inserted text can contain anything.
+    pub fn encode(&self) -> String {
+        let mut encoded = String::new();
+        for component in self.components.iter() {
+            let _ = match component {
+                Component::Retain(count) => write!(encoded, "r{}", count),
+                Component::Insert(text) => write!(encoded, "i{}:{}", text.chars().count(), text),
+                Component::Delete(count) => write!(encoded, "d{}", count),
+            };
+        }
+        encoded
+    }
+
+    pub fn decode(encoded: &str) -> Option<Operation> {
+        let mut op = Operation::default();
+        let mut rest = encoded;
+        while let Some(kind) = rest.chars().next() {
+            if !matches!(kind, 'r' | 'd' | 'i') {
+                return None;
+            }
+            let digits = rest[1..]
+                .find(|ch: char| !ch.is_ascii_digit())
+                .unwrap_or(rest.len() - 1);
+            let count = rest[1..1 + digits].parse().ok()?;
+            rest = &rest[1 + digits..];
+            match kind {
+                'r' => op.retain(count),
+                'd' => op.delete(count),
+                'i' => {
+                    rest = rest.strip_prefix(':')?;
+                    let end = rest
+                        .char_indices()
+                        .nth(count)
+                        .map_or(rest.len(), |(index, _)| index);
+                    op.insert(&rest[..end]);
+                    rest = &rest[end..];
+                }
+                _ => unreachable!(),
+            }
+        }
+        Some(op)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn converges(text: &str, a_text: &str, b_text: &str) -> String {
+        let a = Operation::from_diff(text, a_text).unwrap();
+        let b = Operation::from_diff(text, b_text).unwrap();
+        let (a_prime, b_prime) = Operation::transform(&a, &b).unwrap();
+        let a_then_b = b_prime.apply(&a.apply(text).unwrap()).unwrap();
+        let b_then_a = a_prime.apply(&b.apply(text).unwrap()).unwrap();
+        assert_eq!(a_then_b, b_then_a);
+        let mut in_place = TextBuf::from(text.to_string());
+        a.apply_to(&mut in_place).unwrap();
+        b_prime.apply_to(&mut in_place).unwrap();
+        assert_eq!(in_place.to_string(), a_then_b);
+        a_then_b
+    }
+
+    #[test]
+    fn concurrent_edits_converge() {
+        assert_eq!(
+            converges("héllo world", "héllo, world", "héllo world!"),
+            "héllo, world!"
+        );
+        assert_eq!(converges("abc", "aXc", "aYc"), "aXYc");
+        assert_eq!(converges("abcdef", "af", "abXYef"), "aXYf");
+        assert_eq!(converges("abcdef", "a", "abcdef🦀"), "a🦀");
+    }
+
+    #[test]
+    fn indexes_follow_edits() {
+        let op = Operation::from_diff("hello world", "hi world").unwrap();
+        assert_eq!(op.transform_index(0), 0);
+        assert_eq!(op.transform_index(3), 2);
+        assert_eq!(op.transform_index(8), 5);
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let op = Operation::from_diff("a:b\nc", "a:i3:🦀\nb\nc").unwrap();
+        assert_eq!(Operation::decode(&op.encode()), Some(op));
+        assert_eq!(Operation::decode("x1"), None);
+    }
+}
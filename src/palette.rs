@@ -0,0 +1,109 @@
+//! The command palette: every command, found by typing part of its name.
+
+use crate::keymap::Command;
+
+/// How many recently run commands are listed first.
+const RECENT_COMMANDS: usize = 5;
+
+#[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "persistence", serde(default))]
+#[derive(Default)]
+pub struct Palette {
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    pub query: String,
+    /// Index into `matches()` of the highlighted command.
+    #[cfg_attr(feature = "persistence", serde(skip))]
+    pub selected: usize,
+    /// Commands run from the palette, latest first.
+    recent: Vec<Command>,
+}
+
+impl Palette {
+    /// Start over with an empty query.
+    pub fn reset(&mut self) {
+        self.query.clear();
+        self.selected = 0;
+    }
+
+    /// Commands matching the query, best first. Recent commands come
+    /// before others that match as well.
+    pub fn matches(&self) -> Vec<Command> {
+        let mut matches: Vec<(u32, usize, Command)> = Command::ALL
+            .iter()
+            .filter_map(|command| {
+                let score = fuzzy_score(&self.query, command.name())?;
+                let recent = self
+                    .recent
+                    .iter()
+                    .position(|recent| recent == command)
+                    .unwrap_or(RECENT_COMMANDS);
+                Some((score, recent, *command))
+            })
+            .collect();
+        //  Stable, so ties keep menu order
+        matches.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        matches.into_iter().map(|(_, _, command)| command).collect()
+    }
+
+    /// Move the highlight by `step` rows, staying within `count` matches.
+    pub fn move_selection(&mut self, step: isize, count: usize) {
+        let last = count.saturating_sub(1) as isize;
+        self.selected = (self.selected as isize + step).max(0).min(last) as usize;
+    }
+
+    pub fn used(&mut self, command: Command) {
+        self.recent.retain(|recent| *recent != command);
+        self.recent.insert(0, command);
+        self.recent.truncate(RECENT_COMMANDS);
+    }
+}
+
+/// How well `query` matches `name`, or `None` if its letters don't all
+/// appear in `name` in order. Case and spaces are ignored, and letters
+/// starting a word or following the last match score higher.
+pub fn fuzzy_score(query: &str, name: &str) -> Option<u32> {
+    let name: Vec<char> = name.chars().flat_map(char::to_lowercase).collect();
+    let mut score = 0;
+    let mut next = 0;
+    for wanted in query
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+    {
+        let found = (next..name.len()).find(|i| name[*i] == wanted)?;
+        score += 1;
+        if found == 0 || !name[found - 1].is_alphanumeric() {
+            score += 3;
+        }
+        if next > 0 && found == next {
+            score += 2;
+        }
+        next = found + 1;
+    }
+    Some(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn best_and_recent_matches_first() {
+        assert!(fuzzy_score("svas", "Save As").is_some());
+        assert!(fuzzy_score("sava", "Save").is_none());
+        assert!(fuzzy_score("gtl", "Go to Line") > fuzzy_score("gtl", "Light Theme"));
+
+        let mut palette = Palette::default();
+        assert_eq!(palette.matches().len(), Command::ALL.len());
+        palette.query = "find".to_string();
+        assert_eq!(palette.matches()[0], Command::Find);
+        palette.used(Command::FindNext);
+        assert_eq!(palette.matches()[0], Command::FindNext);
+        palette.reset();
+        assert_eq!(palette.matches()[0], Command::FindNext);
+        palette.move_selection(-1, 3);
+        assert_eq!(palette.selected, 0);
+        palette.move_selection(5, 3);
+        assert_eq!(palette.selected, 2);
+    }
+}
@@ -0,0 +1,534 @@
+//! A small HTTP paste service for sharing snippets on a local network, and
+//! the client the editor uses to talk to it.
+//!
+//! Routes:
+//! - `POST /pastes?expires=<seconds>` stores the body and answers with the new id
+//! - `GET /pastes/<id>` returns the paste as a snippet payload (see `Snippet::to_payload`)
+//! - `GET /raw/<id>` returns just the contents, for browsers and `curl`
+
+use crate::share::Snippet;
+use std::collections::hash_map::RandomState;
+use std::ffi::OsStr;
+use std::fs;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:8090";
+/// Largest paste the server accepts, in bytes.
+pub const MAX_PASTE_LEN: usize = 4 * 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(5);
+const ID_ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+const ID_LEN: usize = 8;
+
+/// How long a published paste is kept.
+#[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Expiry {
+    Hour,
+    Day,
+    #[default]
+    Week,
+    Never,
+}
+
+impl Expiry {
+    pub const ALL: [Expiry; 4] = [Expiry::Hour, Expiry::Day, Expiry::Week, Expiry::Never];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Expiry::Hour => "1 hour",
+            Expiry::Day => "1 day",
+            Expiry::Week => "1 week",
+            Expiry::Never => "Never",
+        }
+    }
+
+    pub fn seconds(&self) -> Option<u64> {
+        match self {
+            Expiry::Hour => Some(60 * 60),
+            Expiry::Day => Some(24 * 60 * 60),
+            Expiry::Week => Some(7 * 24 * 60 * 60),
+            Expiry::Never => None,
+        }
+    }
+}
+
+/// Pastes kept as one file each in a directory. The first line of a file
+/// holds its expiry time (unix seconds, or `never`), the rest is the payload.
+pub struct PasteStore {
+    dir: PathBuf,
+    ids: RandomState,
+    counter: AtomicUsize,
+}
+
+impl PasteStore {
+    pub fn open(dir: &Path) -> io::Result<PasteStore> {
+        fs::create_dir_all(dir)?;
+        Ok(PasteStore {
+            dir: dir.to_path_buf(),
+            ids: RandomState::new(),
+            counter: AtomicUsize::new(0),
+        })
+    }
+
+    /// Store `payload` and return its new id.
+    pub fn store(&self, payload: &str, expires_in: Option<u64>) -> io::Result<String> {
+        let expires = match expires_in {
+            Some(seconds) => (unix_time() + seconds).to_string(),
+            None => "never".to_string(),
+        };
+        loop {
+            let id = self.new_id();
+            let path = self.path_of(&id);
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path);
+            let mut file = match file {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+            write!(file, "{}\n{}", expires, payload)?;
+            return Ok(id);
+        }
+    }
+
+    /// Payload stored under `id`, or None if there is none or it has expired.
+    pub fn fetch(&self, id: &str) -> io::Result<Option<String>> {
+        if !is_valid_id(id) {
+            return Ok(None);
+        }
+        let path = self.path_of(id);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let (expires, payload) = contents.split_at(contents.find('\n').unwrap_or(0));
+        if is_expired(expires) {
+            fs::remove_file(&path)?;
+            return Ok(None);
+        }
+        Ok(Some(payload.trim_start_matches('\n').to_string()))
+    }
+
+    /// Delete every expired paste, returning how many were removed.
+    pub fn purge_expired(&self) -> io::Result<usize> {
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new("paste")) {
+                continue;
+            }
+            let mut expires = String::new();
+            BufReader::new(fs::File::open(&path)?).read_line(&mut expires)?;
+            if is_expired(expires.trim_end()) {
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn new_id(&self) -> String {
+        let mut hasher = self.ids.build_hasher();
+        self.counter
+            .fetch_add(1, Ordering::Relaxed)
+            .hash(&mut hasher);
+        SystemTime::now().hash(&mut hasher);
+        let mut bits = hasher.finish();
+        (0..ID_LEN)
+            .map(|_| {
+                let ch = ID_ALPHABET[(bits % ID_ALPHABET.len() as u64) as usize];
+                bits /= ID_ALPHABET.len() as u64;
+                ch as char
+            })
+            .collect()
+    }
+
+    fn path_of(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.paste", id))
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+fn is_expired(expires: &str) -> bool {
+    match expires.parse::<u64>() {
+        Ok(time) => time <= unix_time(),
+        Err(_) => expires != "never",
+    }
+}
+
+//  Ids end up in file names, so only ever accept ones we could have made
+fn is_valid_id(id: &str) -> bool {
+    id.len() == ID_LEN && id.bytes().all(|ch| ID_ALPHABET.contains(&ch))
+}
+
+/// Accept connections on `listener` until it fails, one thread per request.
+pub fn serve(listener: TcpListener, store: PasteStore) -> io::Result<()> {
+    let store = Arc::new(store);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let store = Arc::clone(&store);
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, &store) {
+                eprintln!("Error handling request: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, store: &PasteStore) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let (status, body) = match read_request(&mut stream) {
+        Ok(request) => route(&request, store),
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => (413, e.to_string()),
+        Err(e) => (400, e.to_string()),
+    };
+    let reason = match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    body: Vec<u8>,
+}
+
+fn read_request(stream: &mut TcpStream) -> io::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let (request_line, content_len) = read_head(&mut reader)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Err(invalid_data("Malformed request line")),
+    };
+    let (path, query) = match target.find('?') {
+        Some(index) => (&target[..index], &target[index + 1..]),
+        None => (target, ""),
+    };
+    let content_len = content_len.unwrap_or(0);
+    if content_len > MAX_PASTE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Paste is too large",
+        ));
+    }
+    let mut body = vec![0; content_len];
+    reader.read_exact(&mut body)?;
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        body,
+    })
+}
+
+/// Read the start line and headers of a request or response,
+/// returning the start line and the Content-Length, if given.
+fn read_head(reader: &mut impl BufRead) -> io::Result<(String, Option<usize>)> {
+    let mut start_line = String::new();
+    reader.read_line(&mut start_line)?;
+    let mut content_len = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                let len = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid_data("Bad Content-Length"))?;
+                content_len = Some(len);
+            }
+        }
+    }
+    Ok((start_line.trim_end().to_string(), content_len))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn route(request: &Request, store: &PasteStore) -> (u16, String) {
+    let path = request.path.trim_end_matches('/');
+    let (kind, id) = match path.rfind('/') {
+        Some(index) => (&path[..index], &path[index + 1..]),
+        None => ("", path),
+    };
+    match (request.method.as_str(), kind, id) {
+        ("GET", "", "") => (200, "code_share paste server\n".to_string()),
+        ("POST", "", "pastes") => {
+            let payload = match String::from_utf8(request.body.clone()) {
+                Ok(payload) => payload,
+                Err(_) => return (400, "Paste is not valid UTF-8".to_string()),
+            };
+            if Snippet::from_payload(&payload).is_none() {
+                return (
+                    400,
+                    "Paste is missing its language or file name".to_string(),
+                );
+            }
+            let expires_in = request
+                .query
+                .split('&')
+                .find_map(|param| param.strip_prefix("expires="))
+                .and_then(|seconds| seconds.parse().ok());
+            //  Old pastes are only cleaned up when new ones come in
+            if let Err(e) = store.purge_expired() {
+                eprintln!("Error purging expired pastes: {}", e);
+            }
+            match store.store(&payload, expires_in) {
+                Ok(id) => (201, id),
+                Err(e) => (500, e.to_string()),
+            }
+        }
+        ("GET", "/pastes", id) | ("GET", "/raw", id) => match store.fetch(id) {
+            Ok(Some(payload)) if kind == "/raw" => match Snippet::from_payload(&payload) {
+                Some(snippet) => (200, snippet.contents),
+                None => (500, "Stored paste is damaged".to_string()),
+            },
+            Ok(Some(payload)) => (200, payload),
+            Ok(None) => (404, "No such paste, it may have expired".to_string()),
+            Err(e) => (500, e.to_string()),
+        },
+        (_, "", "pastes") | (_, "/pastes", _) | (_, "/raw", _) => {
+            (405, "Method not allowed".to_string())
+        }
+        _ => (404, "Not found".to_string()),
+    }
+}
+
+/// Upload `snippet` to the server at `server_url`, returning the link to its
+/// plain text, which `fetch` also takes.
+pub(crate) fn publish(
+    server_url: &str,
+    snippet: &Snippet,
+    expiry: Expiry,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let server_url = server_url.trim_end_matches('/');
+    let url = match expiry.seconds() {
+        Some(seconds) => format!("{}/pastes?expires={}", server_url, seconds),
+        None => format!("{}/pastes", server_url),
+    };
+    let id = request("POST", &url, snippet.to_payload().as_bytes())?;
+    Ok(format!("{}/raw/{}", server_url, id.trim()))
+}
+
+/// Download a paste, given either its id on `server_url` or a full link to it.
+pub(crate) fn fetch(
+    server_url: &str,
+    id_or_url: &str,
+) -> Result<Snippet, Box<dyn std::error::Error>> {
+    let id_or_url = id_or_url.trim().trim_end_matches('/');
+    let (server_url, id) = match id_or_url.rfind('/') {
+        Some(index) => {
+            let base = &id_or_url[..index];
+            let base = base
+                .strip_suffix("/pastes")
+                .or_else(|| base.strip_suffix("/raw"))
+                .unwrap_or(base);
+            (base, &id_or_url[index + 1..])
+        }
+        None => (server_url.trim_end_matches('/'), id_or_url),
+    };
+    if id.is_empty() {
+        return Err("No paste id given".into());
+    }
+    let payload = request("GET", &format!("{}/pastes/{}", server_url, id), &[])?;
+    Snippet::from_payload(&payload).ok_or_else(|| "Paste from server is damaged".into())
+}
+
+/// What a request made through `PendingRequest` comes back with.
+pub(crate) enum Reply {
+    /// Link to the published paste.
+    Published(String),
+    Fetched(Snippet),
+}
+
+/// A publish or fetch running on its own thread, so a slow or unreachable
+/// server doesn't freeze the editor while it times out.
+pub(crate) struct PendingRequest {
+    reply: Receiver<Result<Reply, String>>,
+}
+
+impl PendingRequest {
+    pub fn publish(
+        server_url: String,
+        snippet: Snippet,
+        expiry: Expiry,
+        wake: impl Fn() + Send + 'static,
+    ) -> PendingRequest {
+        PendingRequest::spawn(
+            move || publish(&server_url, &snippet, expiry).map(Reply::Published),
+            wake,
+        )
+    }
+
+    pub fn fetch(
+        server_url: String,
+        id_or_url: String,
+        wake: impl Fn() + Send + 'static,
+    ) -> PendingRequest {
+        PendingRequest::spawn(
+            move || fetch(&server_url, &id_or_url).map(Reply::Fetched),
+            wake,
+        )
+    }
+
+    /// Run `request`, calling `wake` once its reply is ready.
+    fn spawn(
+        request: impl FnOnce() -> Result<Reply, Box<dyn std::error::Error>> + Send + 'static,
+        wake: impl Fn() + Send + 'static,
+    ) -> PendingRequest {
+        let (reply_tx, reply) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = reply_tx.send(request().map_err(|e| e.to_string()));
+            wake();
+        });
+        PendingRequest { reply }
+    }
+
+    /// The reply, once it has come in.
+    pub fn try_reply(&self) -> Option<Result<Reply, String>> {
+        match self.reply.try_recv() {
+            Ok(reply) => Some(reply),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err("Paste request failed".to_string())),
+        }
+    }
+}
+
+/// Make a plain HTTP request and return the response body.
+/// Responses other than 2xx become errors carrying the server's message.
+fn request(method: &str, url: &str, body: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None => return Err(format!("Only http:// server URLs are supported: {}", url).into()),
+    };
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    let addr = match host.to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => return Err(format!("Could not resolve {}", host).into()),
+    };
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+        .map_err(|e| format!("Could not reach paste server at {}: {}", host, e))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        host,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let (status_line, _) = read_head(&mut reader)?;
+    let mut response = String::new();
+    reader.read_to_string(&mut response)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(response),
+        Some(status) => Err(format!("Paste server answered {}: {}", status, response).into()),
+        None => Err("Malformed response from paste server".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::highlight::Language;
+    use crate::test_dir::TestDir;
+
+    /// The store goes away with the returned dir.
+    fn start_server(name: &str) -> (TestDir, String) {
+        let dir = TestDir::new(name);
+        let store = PasteStore::open(dir.path()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || serve(listener, store));
+        (dir, url)
+    }
+
+    #[test]
+    fn publish_and_fetch_against_localhost() {
+        let (_dir, server) = start_server("publish");
+        let snippet = Snippet {
+            file_name: Some("notes.md".to_string()),
+            language: Language::Markdown,
+            contents: "# Héllo\n\nsome text\n".to_string(),
+        };
+        let link = publish(&server, &snippet, Expiry::Hour).unwrap();
+        let id = link.rsplit('/').next().unwrap();
+
+        for fetched in [fetch(&server, id).unwrap(), fetch("unused", &link).unwrap()] {
+            assert_eq!(fetched.file_name.as_deref(), Some("notes.md"));
+            assert_eq!(fetched.language, Language::Markdown);
+            assert_eq!(fetched.contents, snippet.contents);
+        }
+        assert_eq!(link, format!("{}/raw/{}", server, id));
+        assert_eq!(request("GET", &link, &[]).unwrap(), snippet.contents);
+        assert!(fetch(&server, "zzzzzzzz").is_err());
+        assert!(fetch(&server, "../../etc").is_err());
+
+        let pending = PendingRequest::fetch(server, id.to_string(), || {});
+        let reply = loop {
+            match pending.try_reply() {
+                Some(reply) => break reply,
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        assert!(
+            matches!(reply, Ok(Reply::Fetched(fetched)) if fetched.contents == snippet.contents)
+        );
+    }
+
+    #[test]
+    fn expired_pastes_are_gone() {
+        let dir = TestDir::new("expiry");
+        let store = PasteStore::open(dir.path()).unwrap();
+        let expired = store.store("text\n\nold", Some(0)).unwrap();
+        let kept = store.store("text\n\nnew", None).unwrap();
+        assert_eq!(store.fetch(&expired).unwrap(), None);
+        assert_eq!(store.fetch(&kept).unwrap().as_deref(), Some("text\n\nnew"));
+    }
+}
@@ -0,0 +1,221 @@
+//! Files that shouldn't go straight into an editable buffer: binaries are
+//! shown as a hex dump, and very large files a chunk at a time, both read-only.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Files bigger than this ask before being read into the editor whole.
+pub const LARGE_FILE_BYTES: u64 = 32 * 1024 * 1024;
+/// How much of a large file is loaded at a time.
+const TEXT_CHUNK_BYTES: u64 = 1024 * 1024;
+/// How much of a binary is dumped at a time, each byte takes four chars.
+const HEX_CHUNK_BYTES: u64 = 64 * 1024;
+/// How much of a file is looked at to tell binary from text.
+const SNIFF_BYTES: u64 = 8 * 1024;
+
+/// What a file looks like before opening it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileCheck {
+    Text,
+    Binary,
+    Large { size: u64 },
+}
+
+impl FileCheck {
+    pub fn of(path: &Path) -> io::Result<FileCheck> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut start = Vec::new();
+        file.by_ref().take(SNIFF_BYTES).read_to_end(&mut start)?;
+        //  Text has no NUL bytes, unless it's UTF-16
+        let utf16 = start.starts_with(&[0xFF, 0xFE]) || start.starts_with(&[0xFE, 0xFF]);
+        if !utf16 && start.contains(&0) {
+            Ok(FileCheck::Binary)
+        } else if size > LARGE_FILE_BYTES {
+            Ok(FileCheck::Large { size })
+        } else {
+            Ok(FileCheck::Text)
+        }
+    }
+}
+
+/// Size in the units people expect, e.g. `1.5 GB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Lines of 16 bytes: offset, bytes in hex, then printable ASCII.
+pub fn hex_dump(bytes: &[u8], offset: u64) -> String {
+    let mut dump = String::with_capacity(bytes.len() * 4 + bytes.len() / 16 * 12);
+    for (row, line) in bytes.chunks(16).enumerate() {
+        dump.push_str(&format!("{:08x}  ", offset + row as u64 * 16));
+        for column in 0..16 {
+            match line.get(column) {
+                Some(byte) => dump.push_str(&format!("{:02x} ", byte)),
+                None => dump.push_str("   "),
+            }
+            if column == 7 {
+                dump.push(' ');
+            }
+        }
+        dump.push_str(" |");
+        for byte in line.iter() {
+            match byte {
+                0x20..=0x7E => dump.push(*byte as char),
+                _ => dump.push('.'),
+            }
+        }
+        dump.push_str("|\n");
+    }
+    dump
+}
+
+#[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PreviewKind {
+    Hex,
+    Text,
+}
+
+/// A file shown a chunk at a time.
+pub struct Preview {
+    pub path: PathBuf,
+    pub kind: PreviewKind,
+    /// Bytes of the file shown so far.
+    loaded: u64,
+    size: u64,
+}
+
+impl Preview {
+    /// Start showing the file at `path`, returning the first chunk.
+    pub fn open(path: &Path, kind: PreviewKind) -> io::Result<(Preview, String)> {
+        let size = std::fs::metadata(path)?.len();
+        let mut preview = Preview {
+            path: path.to_path_buf(),
+            kind,
+            loaded: 0,
+            size,
+        };
+        let first = preview.load_more()?;
+        Ok((preview, first))
+    }
+
+    /// The next chunk, to append to what's shown.
+    pub fn load_more(&mut self) -> io::Result<String> {
+        let chunk_len = match self.kind {
+            PreviewKind::Hex => HEX_CHUNK_BYTES,
+            PreviewKind::Text => TEXT_CHUNK_BYTES,
+        };
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.loaded))?;
+        let mut chunk = Vec::new();
+        file.take(chunk_len).read_to_end(&mut chunk)?;
+        let text = match self.kind {
+            PreviewKind::Hex => hex_dump(&chunk, self.loaded),
+            PreviewKind::Text => {
+                //  Leave a char cut off by the end of the chunk for the next one
+                if let Err(e) = std::str::from_utf8(&chunk) {
+                    if e.error_len().is_none() && self.loaded + (chunk.len() as u64) < self.size {
+                        chunk.truncate(e.valid_up_to());
+                    }
+                }
+                String::from_utf8_lossy(&chunk).into_owned()
+            }
+        };
+        //  A file that shrank since opening is done
+        self.loaded = match chunk.is_empty() {
+            true => self.size,
+            false => self.loaded + chunk.len() as u64,
+        };
+        Ok(text)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.loaded >= self.size
+    }
+
+    fn file_name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    pub fn title(&self) -> String {
+        let name = self.file_name();
+        match self.kind {
+            PreviewKind::Hex => format!("{} (hex)", name),
+            PreviewKind::Text => format!("{} (partial)", name),
+        }
+    }
+
+    /// E.g. `Showing 1.0 MB of 2.4 GB of server.log`.
+    pub fn describe(&self) -> String {
+        let name = self.file_name();
+        let what = match self.kind {
+            PreviewKind::Hex => format!("{} as hex", name),
+            PreviewKind::Text => name,
+        };
+        match self.is_complete() {
+            true => format!("Showing all of {}", what),
+            false => format!(
+                "Showing {} of {} of {}",
+                format_size(self.loaded),
+                format_size(self.size),
+                what
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn binaries_and_sizes() {
+        let dir = TestDir::new("preview");
+        let path = dir.join("program");
+        std::fs::write(&path, b"\x7fELF\x02\x01\x01\x00\x00hello").unwrap();
+        assert_eq!(FileCheck::of(&path).unwrap(), FileCheck::Binary);
+        let (preview, dump) = Preview::open(&path, PreviewKind::Hex).unwrap();
+        assert_eq!(
+            dump,
+            "00000000  7f 45 4c 46 02 01 01 00  00 68 65 6c 6c 6f        |.ELF.....hello|\n"
+        );
+        assert!(preview.is_complete());
+        std::fs::write(&path, "plain text\n").unwrap();
+        assert_eq!(FileCheck::of(&path).unwrap(), FileCheck::Text);
+
+        assert_eq!(format_size(512), "512 bytes");
+        assert_eq!(format_size(3 * 1024 * 1024 / 2), "1.5 MB");
+    }
+
+    #[test]
+    fn text_chunks_keep_chars_whole() {
+        let dir = TestDir::new("chunks");
+        let path = dir.join("notes.txt");
+        //  A two byte char straddling the end of the first chunk
+        let mut text = "a".repeat(TEXT_CHUNK_BYTES as usize - 1);
+        text.push_str("é and the rest");
+        std::fs::write(&path, &text).unwrap();
+        let (mut preview, mut shown) = Preview::open(&path, PreviewKind::Text).unwrap();
+        assert!(!preview.is_complete());
+        while !preview.is_complete() {
+            shown.push_str(&preview.load_more().unwrap());
+        }
+        assert_eq!(shown, text);
+    }
+}
@@ -0,0 +1,508 @@
+//! A rope: text kept as a balanced tree of small chunks, each node knowing
+//! how many bytes, chars and line breaks are under it. Edits and going
+//! between byte, char and line offsets take O(log n) rather than a scan.
+
+use std::fmt;
+use std::ops::Range;
+
+/// Leaves are split once they grow past this many bytes.
+const MAX_LEAF_BYTES: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Counts {
+    bytes: usize,
+    chars: usize,
+    newlines: usize,
+}
+
+impl Counts {
+    fn of(text: &str) -> Counts {
+        Counts {
+            bytes: text.len(),
+            chars: text.chars().count(),
+            newlines: text.bytes().filter(|&byte| byte == b'\n').count(),
+        }
+    }
+
+    fn add(self, other: Counts) -> Counts {
+        Counts {
+            bytes: self.bytes + other.bytes,
+            chars: self.chars + other.chars,
+            newlines: self.newlines + other.newlines,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Leaf {
+        text: String,
+        counts: Counts,
+    },
+    Branch {
+        left: Box<Node>,
+        right: Box<Node>,
+        counts: Counts,
+        height: usize,
+        leaves: usize,
+    },
+}
+
+impl Node {
+    fn leaf(text: String) -> Node {
+        let counts = Counts::of(&text);
+        Node::Leaf { text, counts }
+    }
+
+    /// A balanced tree over `leaves`, which must not be empty.
+    fn build(mut leaves: Vec<Node>) -> Node {
+        if leaves.len() == 1 {
+            return leaves.pop().unwrap();
+        }
+        let right = leaves.split_off(leaves.len() / 2);
+        Node::branch(Node::build(leaves), Node::build(right))
+    }
+
+    fn branch(left: Node, right: Node) -> Node {
+        Node::Branch {
+            counts: left.counts().add(right.counts()),
+            height: left.height().max(right.height()) + 1,
+            leaves: left.leaves() + right.leaves(),
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn counts(&self) -> Counts {
+        match self {
+            Node::Leaf { counts, .. } | Node::Branch { counts, .. } => *counts,
+        }
+    }
+
+    fn height(&self) -> usize {
+        match self {
+            Node::Leaf { .. } => 0,
+            Node::Branch { height, .. } => *height,
+        }
+    }
+
+    fn leaves(&self) -> usize {
+        match self {
+            Node::Leaf { .. } => 1,
+            Node::Branch { leaves, .. } => *leaves,
+        }
+    }
+
+    /// Recount after an edit below this node.
+    fn update(&mut self) {
+        match self {
+            Node::Leaf { text, counts } => *counts = Counts::of(text),
+            Node::Branch {
+                left,
+                right,
+                counts,
+                height,
+                leaves,
+            } => {
+                *counts = left.counts().add(right.counts());
+                *height = left.height().max(right.height()) + 1;
+                *leaves = left.leaves() + right.leaves();
+            }
+        }
+    }
+
+    fn into_leaf_texts(self, texts: &mut Vec<String>) {
+        match self {
+            Node::Leaf { text, .. } => texts.push(text),
+            Node::Branch { left, right, .. } => {
+                left.into_leaf_texts(texts);
+                right.into_leaf_texts(texts);
+            }
+        }
+    }
+
+    /// Append the bytes of `range` under this node to `out`.
+    fn push_slice(&self, range: Range<usize>, out: &mut String) {
+        match self {
+            Node::Leaf { text, .. } => out.push_str(&text[range]),
+            Node::Branch { left, right, .. } => {
+                let left_bytes = left.counts().bytes;
+                if range.start < left_bytes {
+                    left.push_slice(range.start..range.end.min(left_bytes), out);
+                }
+                if range.end > left_bytes {
+                    right.push_slice(
+                        range.start.max(left_bytes) - left_bytes..range.end - left_bytes,
+                        out,
+                    );
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, byte: usize, text: &str) {
+        match self {
+            Node::Leaf { text: leaf, .. } => {
+                leaf.insert_str(byte, text);
+                if leaf.len() > MAX_LEAF_BYTES {
+                    let leaf = std::mem::take(leaf);
+                    *self = Node::build(split_leaves(&leaf));
+                    return;
+                }
+            }
+            Node::Branch { left, right, .. } => {
+                let left_bytes = left.counts().bytes;
+                match byte <= left_bytes {
+                    true => left.insert(byte, text),
+                    false => right.insert(byte - left_bytes, text),
+                }
+            }
+        }
+        self.update();
+    }
+
+    fn remove(&mut self, range: Range<usize>) {
+        match self {
+            Node::Leaf { text, .. } => text.replace_range(range, ""),
+            Node::Branch { left, right, .. } => {
+                let left_bytes = left.counts().bytes;
+                if range.start < left_bytes {
+                    left.remove(range.start..range.end.min(left_bytes));
+                }
+                if range.end > left_bytes {
+                    right.remove(range.start.max(left_bytes) - left_bytes..range.end - left_bytes);
+                }
+                //  Drop a side that has been emptied
+                if left.counts().bytes == 0 {
+                    *self = std::mem::replace(right, Node::leaf(String::new()));
+                    return;
+                }
+                if right.counts().bytes == 0 {
+                    *self = std::mem::replace(left, Node::leaf(String::new()));
+                    return;
+                }
+            }
+        }
+        self.update();
+    }
+}
+
+/// `text` cut into leaves, each at most `MAX_LEAF_BYTES` and ending on a char boundary.
+fn split_leaves(text: &str) -> Vec<Node> {
+    let mut leaves = Vec::new();
+    let mut rest = text;
+    while rest.len() > MAX_LEAF_BYTES {
+        let mut end = MAX_LEAF_BYTES;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        leaves.push(Node::leaf(rest[..end].to_string()));
+        rest = &rest[end..];
+    }
+    leaves.push(Node::leaf(rest.to_string()));
+    leaves
+}
+
+#[derive(Clone, Debug)]
+pub struct Rope {
+    root: Node,
+}
+
+impl Default for Rope {
+    fn default() -> Self {
+        Rope::from("")
+    }
+}
+
+impl From<&str> for Rope {
+    fn from(text: &str) -> Self {
+        Rope {
+            root: Node::build(split_leaves(text)),
+        }
+    }
+}
+
+impl Rope {
+    pub fn len_bytes(&self) -> usize {
+        self.root.counts().bytes
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.root.counts().chars
+    }
+
+    /// Number of line breaks, one less than the number of lines counting
+    /// the (possibly empty) one after the last break.
+    pub fn len_newlines(&self) -> usize {
+        self.root.counts().newlines
+    }
+
+    /// Insert `text` at byte offset `byte`, which must be on a char boundary.
+    pub fn insert(&mut self, byte: usize, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        self.root.insert(byte, text);
+        self.rebalance();
+    }
+
+    /// Remove the bytes in `range`, which must start and end on char boundaries.
+    pub fn remove(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        self.root.remove(range);
+        self.rebalance();
+    }
+
+    /// Rebuild the tree once edits in one spot have made it lopsided,
+    /// joining leaves that deletes left small along the way.
+    fn rebalance(&mut self) {
+        let leaf_count = self.root.leaves();
+        let balanced_height = (usize::BITS - leaf_count.leading_zeros()) as usize;
+        if self.root.height() <= balanced_height * 2 + 2 {
+            return;
+        }
+        let mut texts = Vec::with_capacity(leaf_count);
+        std::mem::replace(&mut self.root, Node::leaf(String::new())).into_leaf_texts(&mut texts);
+        let mut leaves = Vec::with_capacity(texts.len());
+        let mut joined = String::new();
+        for text in texts {
+            if joined.len() + text.len() > MAX_LEAF_BYTES {
+                leaves.push(Node::leaf(std::mem::take(&mut joined)));
+            }
+            joined.push_str(&text);
+        }
+        leaves.push(Node::leaf(joined));
+        self.root = Node::build(leaves);
+    }
+
+    /// The bytes in `range`, which must start and end on char boundaries.
+    pub fn slice(&self, range: Range<usize>) -> String {
+        let mut text = String::with_capacity(range.len());
+        if !range.is_empty() {
+            self.root.push_slice(range, &mut text);
+        }
+        text
+    }
+
+    /// `byte` moved back to the start of the char it is in, clamped to the end.
+    pub fn floor_char_boundary(&self, byte: usize) -> usize {
+        let mut node = &self.root;
+        let mut byte = byte.min(self.len_bytes());
+        let mut bytes = 0;
+        loop {
+            match node {
+                Node::Leaf { text, .. } => {
+                    while !text.is_char_boundary(byte) {
+                        byte -= 1;
+                    }
+                    return bytes + byte;
+                }
+                Node::Branch { left, right, .. } => {
+                    let left_bytes = left.counts().bytes;
+                    match byte < left_bytes {
+                        true => node = left,
+                        false => {
+                            byte -= left_bytes;
+                            bytes += left_bytes;
+                            node = right;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Char index of byte offset `byte`, clamped to the end.
+    pub fn byte_to_char(&self, byte: usize) -> usize {
+        let mut node = &self.root;
+        let mut byte = byte.min(self.len_bytes());
+        let mut chars = 0;
+        loop {
+            match node {
+                Node::Leaf { text, .. } => return chars + text[..byte].chars().count(),
+                Node::Branch { left, right, .. } => {
+                    let left_counts = left.counts();
+                    match byte < left_counts.bytes {
+                        true => node = left,
+                        false => {
+                            byte -= left_counts.bytes;
+                            chars += left_counts.chars;
+                            node = right;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Byte offset of the `char`-th char, clamped to the end.
+    pub fn char_to_byte(&self, char: usize) -> usize {
+        let mut node = &self.root;
+        let mut char = char.min(self.len_chars());
+        let mut bytes = 0;
+        loop {
+            match node {
+                Node::Leaf { text, .. } => {
+                    return bytes
+                        + text
+                            .char_indices()
+                            .nth(char)
+                            .map_or(text.len(), |(byte, _)| byte)
+                }
+                Node::Branch { left, right, .. } => {
+                    let left_counts = left.counts();
+                    match char < left_counts.chars {
+                        true => node = left,
+                        false => {
+                            char -= left_counts.chars;
+                            bytes += left_counts.bytes;
+                            node = right;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// 0-based line that byte offset `byte` is on.
+    pub fn byte_to_line(&self, byte: usize) -> usize {
+        let mut node = &self.root;
+        let mut byte = byte.min(self.len_bytes());
+        let mut lines = 0;
+        loop {
+            match node {
+                Node::Leaf { text, .. } => {
+                    return lines + text[..byte].bytes().filter(|&b| b == b'\n').count()
+                }
+                Node::Branch { left, right, .. } => {
+                    let left_counts = left.counts();
+                    match byte < left_counts.bytes {
+                        true => node = left,
+                        false => {
+                            byte -= left_counts.bytes;
+                            lines += left_counts.newlines;
+                            node = right;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Byte offset where 0-based `line` starts, the end for lines past the last.
+    pub fn line_to_byte(&self, line: usize) -> usize {
+        if line == 0 {
+            return 0;
+        }
+        if line > self.len_newlines() {
+            return self.len_bytes();
+        }
+        //  Find the line break ending the previous line
+        let mut node = &self.root;
+        let mut newlines = line;
+        let mut bytes = 0;
+        loop {
+            match node {
+                Node::Leaf { text, .. } => {
+                    let newline = text
+                        .bytes()
+                        .enumerate()
+                        .filter(|&(_, b)| b == b'\n')
+                        .nth(newlines - 1)
+                        .map_or(text.len(), |(byte, _)| byte);
+                    return bytes + newline + 1;
+                }
+                Node::Branch { left, right, .. } => {
+                    let left_counts = left.counts();
+                    match newlines <= left_counts.newlines {
+                        true => node = left,
+                        false => {
+                            newlines -= left_counts.newlines;
+                            bytes += left_counts.bytes;
+                            node = right;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Rope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut stack = vec![&self.root];
+        while let Some(node) = stack.pop() {
+            match node {
+                Node::Leaf { text, .. } => f.write_str(text)?,
+                Node::Branch { left, right, .. } => {
+                    stack.push(right);
+                    stack.push(left);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_match_a_string() {
+        let mut rope = Rope::default();
+        let mut string = String::new();
+        //  Typing in one spot, then deleting across many leaves
+        for i in 0..5000 {
+            let text = match i % 7 {
+                0 => "\n",
+                1 => "é",
+                _ => "ab",
+            };
+            let byte = string.len() / 3;
+            let byte = (0..=byte)
+                .rev()
+                .find(|&b| string.is_char_boundary(b))
+                .unwrap();
+            rope.insert(byte, text);
+            string.insert_str(byte, text);
+        }
+        assert_eq!(rope.to_string(), string);
+        let start = (100..).find(|&b| string.is_char_boundary(b)).unwrap();
+        let end = (start + 3000..)
+            .find(|&b| string.is_char_boundary(b))
+            .unwrap();
+        rope.remove(start..end);
+        string.replace_range(start..end, "");
+        assert_eq!(rope.to_string(), string);
+        assert_eq!(rope.len_chars(), string.chars().count());
+        assert!(rope.root.height() < 16);
+    }
+
+    #[test]
+    fn offsets_and_lines() {
+        let text = "héllo\nwörld 🦀\n\nend".repeat(200);
+        let rope = Rope::from(text.as_str());
+        assert_eq!(rope.len_newlines(), text.matches('\n').count());
+        for (char, (byte, _)) in text.char_indices().enumerate().step_by(7) {
+            assert_eq!(rope.byte_to_char(byte), char);
+            assert_eq!(rope.char_to_byte(char), byte);
+            assert_eq!(rope.byte_to_line(byte), text[..byte].matches('\n').count());
+        }
+        let mut line_start = 0;
+        for (line, line_text) in text.split('\n').enumerate() {
+            assert_eq!(rope.line_to_byte(line), line_start);
+            line_start += line_text.len() + 1;
+        }
+        for (start, end) in [(0, 0), (3, 40), (1000, 3000), (0, text.len())].iter() {
+            let start = rope.floor_char_boundary(*start);
+            let end = rope.floor_char_boundary(*end);
+            assert_eq!(rope.slice(start..end), text[start..end]);
+        }
+        assert_eq!(rope.floor_char_boundary(2), 1);
+        assert_eq!(rope.line_to_byte(usize::MAX), text.len());
+        assert_eq!(rope.char_to_byte(usize::MAX), text.len());
+    }
+}
@@ -0,0 +1,280 @@
+//! Hot exit: the open tabs are kept with the rest of the app state, so the
+//! next run brings back the same files, cursors and unsaved changes.
+//! File > Save Session writes the same to a TOML file, to open again later.
+
+use crate::document::Document;
+use crate::encoding::{Encoding, LineEnding};
+use crate::highlight::Language;
+use crate::preview::{FileCheck, Preview, PreviewKind};
+#[cfg(all(feature = "persistence", not(target_arch = "wasm32")))]
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// What's needed to reopen one tab.
+#[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "persistence", serde(default))]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DocSnapshot {
+    path: Option<PathBuf>,
+    /// The buffer, when it isn't simply what's on disk.
+    unsaved_text: Option<String>,
+    /// Modification time of the file the unsaved buffer is based on.
+    modified: Option<SystemTime>,
+    /// Char index of the cursor.
+    cursor: usize,
+    scroll_offset: f32,
+    language_override: Option<Language>,
+    shared_name: Option<String>,
+    /// How the unsaved buffer is to be saved.
+    encoding: Encoding,
+    line_ending: LineEnding,
+    /// How a read-only preview tab showed the file, which is reread on restore.
+    preview: Option<PreviewKind>,
+}
+
+impl DocSnapshot {
+    /// None for an untouched untitled tab, there's nothing worth keeping.
+    pub fn capture(doc: &Document) -> Option<DocSnapshot> {
+        if doc.is_pristine() {
+            return None;
+        }
+        if let Some(preview) = &doc.preview {
+            return Some(DocSnapshot {
+                path: Some(preview.path.clone()),
+                cursor: doc.cursor,
+                scroll_offset: doc.scroll_offset,
+                language_override: doc.language_override,
+                preview: Some(preview.kind),
+                ..DocSnapshot::default()
+            });
+        }
+        let path = doc.file_status.get_path().map(|path| path.to_path_buf());
+        let unsaved_text = match path.is_none() || doc.file_status.is_unsaved() {
+            true => Some(doc.text_buf.to_string()),
+            false => None,
+        };
+        Some(DocSnapshot {
+            path,
+            unsaved_text,
+            modified: doc.file_status.get_modified(),
+            cursor: doc.cursor,
+            scroll_offset: doc.scroll_offset,
+            language_override: doc.language_override,
+            shared_name: doc.shared_name.clone(),
+            encoding: doc.file_status.encoding,
+            line_ending: doc.file_status.line_ending,
+            preview: None,
+        })
+    }
+
+    /// Reopen the tab. The flag is set when the file changed on disk after
+    /// its unsaved buffer was kept, so the user can pick which one to keep.
+    pub fn restore(self) -> Result<(Document, bool), String> {
+        let mut doc = Document::default();
+        let mut changed_on_disk = false;
+        match (self.path, self.unsaved_text) {
+            //  Checked again, the file may have changed into one that opens whole
+            (Some(path), _) if self.preview.is_some() => {
+                let describe = |e: std::io::Error| format!("{}: {}", path.display(), e);
+                let kind = match FileCheck::of(&path).map_err(describe)? {
+                    FileCheck::Text => None,
+                    FileCheck::Binary => Some(PreviewKind::Hex),
+                    FileCheck::Large { .. } => self.preview,
+                };
+                doc = match kind {
+                    Some(kind) => {
+                        let (preview, first_chunk) =
+                            Preview::open(&path, kind).map_err(describe)?;
+                        Document::from_preview(preview, first_chunk)
+                    }
+                    None => match doc.file_status.open_path(&path) {
+                        Ok(contents) => {
+                            doc.load_text(contents);
+                            doc
+                        }
+                        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+                    },
+                };
+            }
+            (Some(path), Some(text)) => {
+                doc.file_status.restore(path, self.modified);
+                doc.file_status.encoding = self.encoding;
+                doc.file_status.line_ending = self.line_ending;
+                doc.load_text(text);
+                changed_on_disk = doc.file_status.changed_on_disk();
+            }
+            (Some(path), None) => match doc.file_status.open_path(&path) {
+                Ok(contents) => doc.load_text(contents),
+                Err(e) => return Err(format!("{}: {}", path.display(), e)),
+            },
+            (None, text) => {
+                doc.load_text(text.unwrap_or_default());
+                doc.file_status.set_unsaved(!doc.text_buf.is_empty());
+            }
+        }
+        doc.cursor = self.cursor.min(doc.text_buf.len_chars());
+        doc.scroll_to = Some(self.scroll_offset);
+        doc.language_override = self.language_override;
+        if self.preview.is_none() {
+            doc.shared_name = self.shared_name;
+        }
+        Ok((doc, changed_on_disk))
+    }
+}
+
+/// The open tabs, in order.
+#[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "persistence", serde(default))]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Session {
+    docs: Vec<DocSnapshot>,
+    active_doc: usize,
+}
+
+/// A session reopened at startup.
+pub struct Restored {
+    pub docs: Vec<Document>,
+    pub active_doc: usize,
+    /// Tabs whose file changed on disk after their unsaved buffer was kept.
+    pub changed_on_disk: Vec<usize>,
+    /// Files that couldn't be reopened.
+    pub errors: Vec<String>,
+}
+
+impl Session {
+    pub fn capture(docs: &[Document], active_doc: usize) -> Session {
+        let mut session = Session::default();
+        for (index, doc) in docs.iter().enumerate() {
+            if let Some(snapshot) = DocSnapshot::capture(doc) {
+                if index <= active_doc {
+                    session.active_doc = session.docs.len();
+                }
+                session.docs.push(snapshot);
+            }
+        }
+        session
+    }
+
+    #[cfg(all(feature = "persistence", not(target_arch = "wasm32")))]
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = toml::to_string(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    #[cfg(all(feature = "persistence", not(target_arch = "wasm32")))]
+    pub fn from_file(path: &Path) -> Result<Session, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e.message()))
+    }
+
+    /// Reopen every tab that still can be. Always gives at least one tab.
+    pub fn restore(self) -> Restored {
+        let mut restored = Restored {
+            docs: Vec::new(),
+            active_doc: 0,
+            changed_on_disk: Vec::new(),
+            errors: Vec::new(),
+        };
+        for (index, snapshot) in self.docs.into_iter().enumerate() {
+            match snapshot.restore() {
+                Ok((doc, changed_on_disk)) => {
+                    if index <= self.active_doc {
+                        restored.active_doc = restored.docs.len();
+                    }
+                    if changed_on_disk {
+                        restored.changed_on_disk.push(restored.docs.len());
+                    }
+                    restored.docs.push(doc);
+                }
+                Err(e) => restored.errors.push(e),
+            }
+        }
+        if restored.docs.is_empty() {
+            restored.docs.push(Document::default());
+        }
+        restored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn unsaved_buffers_come_back() {
+        let dir = TestDir::new("session");
+        let path = dir.join("main.rs");
+        std::fs::write(&path, "fn main() {}\n").unwrap();
+
+        let mut saved = Document::default();
+        let contents = saved.file_status.open_path(&path).unwrap();
+        saved.load_text(contents);
+        let mut edited = Document::default();
+        edited.file_status.open_path(&path).unwrap();
+        edited.load_text("fn main() { todo!() }\n".to_string());
+        edited.file_status.set_unsaved(true);
+        edited.cursor = 11;
+        let mut untitled = Document::default();
+        untitled.load_text("scratch".to_string());
+        untitled.file_status.set_unsaved(true);
+        let docs = [saved, Document::default(), edited, untitled];
+
+        let session = Session::capture(&docs, 2);
+        assert_eq!(session.docs.len(), 3);
+        assert_eq!(session.active_doc, 1);
+        assert_eq!(session.docs[0].unsaved_text, None);
+
+        #[cfg(feature = "persistence")]
+        {
+            let file = dir.join("session.toml");
+            session.save(&file).unwrap();
+            assert_eq!(Session::from_file(&file), Ok(session.clone()));
+        }
+
+        let restored = session.clone().restore();
+        assert_eq!(restored.active_doc, 1);
+        assert!(restored.changed_on_disk.is_empty());
+        assert_eq!(restored.docs[0].text_buf.to_string(), "fn main() {}\n");
+        assert!(!restored.docs[0].file_status.is_unsaved());
+        assert_eq!(
+            restored.docs[1].text_buf.to_string(),
+            "fn main() { todo!() }\n"
+        );
+        assert_eq!(restored.docs[1].cursor, 11);
+        assert!(restored.docs[1].file_status.is_unsaved());
+        assert_eq!(restored.docs[2].text_buf.to_string(), "scratch");
+
+        //  As if someone else saved the file while the editor was closed
+        let mut session = session;
+        session.docs[1].modified = Some(SystemTime::UNIX_EPOCH);
+        let restored = session.restore();
+        assert_eq!(restored.changed_on_disk, vec![1]);
+    }
+
+    #[test]
+    fn previews_are_reread() {
+        let dir = TestDir::new("session-preview");
+        let path = dir.join("program");
+        std::fs::write(&path, b"\x7fELF\x00").unwrap();
+        let (preview, dump) = Preview::open(&path, PreviewKind::Hex).unwrap();
+        let session = Session::capture(&[Document::from_preview(preview, dump.clone())], 0);
+
+        let restored = session.clone().restore();
+        let doc = &restored.docs[0];
+        assert_eq!(
+            doc.preview.as_ref().map(|preview| preview.kind),
+            Some(PreviewKind::Hex)
+        );
+        assert_eq!(doc.text_buf.to_string(), dump);
+        assert!(doc.read_only);
+
+        //  No longer a binary, so it opens as text
+        std::fs::write(&path, "text\n").unwrap();
+        let restored = session.restore();
+        assert!(restored.docs[0].preview.is_none());
+        assert_eq!(restored.docs[0].text_buf.to_string(), "text\n");
+    }
+}
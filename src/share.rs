@@ -0,0 +1,109 @@
+use crate::highlight::Language;
+
+/// Where share links point when the app isn't running on a web page.
+pub const DEFAULT_BASE_URL: &str = "https://maxroyer.github.io/code_share/";
+/// Marks a URL fragment as a shared snippet, e.g. `#share=...`.
+pub const FRAGMENT_PREFIX: &str = "share=";
+/// Links longer than this get truncated by some chat apps.
+pub const LONG_LINK_LEN: usize = 8000;
+/// Largest snippet a link may unpack to, so a tiny link can't expand to gigabytes.
+pub const MAX_SNIPPET_LEN: usize = 4 * 1024 * 1024;
+
+/// Everything a share link carries.
+pub struct Snippet {
+    pub file_name: Option<String>,
+    pub language: Language,
+    pub contents: String,
+}
+
+impl Snippet {
+    /// Full link to this snippet, e.g. `https://host/page#share=...`.
+    pub fn to_url(&self, base_url: &str) -> String {
+        format!("{}#{}{}", base_url, FRAGMENT_PREFIX, self.encode())
+    }
+
+    /// The snippet compressed and base64 encoded, so it fits in a URL fragment.
+    pub fn encode(&self) -> String {
+        let compressed = miniz_oxide::deflate::compress_to_vec(self.to_payload().as_bytes(), 9);
+        base64::encode_config(compressed, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Read a snippet back from a URL fragment, with or without the leading `#`.
+    pub fn decode(fragment: &str) -> Result<Snippet, Box<dyn std::error::Error>> {
+        let fragment = fragment.trim_start_matches('#');
+        let data = match fragment.strip_prefix(FRAGMENT_PREFIX) {
+            Some(data) => data,
+            None => return Err("Not a share link".into()),
+        };
+        let compressed = base64::decode_config(data, base64::URL_SAFE_NO_PAD)
+            .map_err(|e| format!("Share link is damaged: {}", e))?;
+        let payload =
+            miniz_oxide::inflate::decompress_to_vec_with_limit(&compressed, MAX_SNIPPET_LEN)
+                .map_err(|e| match e {
+                    miniz_oxide::inflate::TINFLStatus::HasMoreOutput => {
+                        "Share link is too large to open".to_string()
+                    }
+                    e => format!("Share link is damaged: {:?}", e),
+                })?;
+        let payload = String::from_utf8(payload)?;
+        Snippet::from_payload(&payload)
+            .ok_or_else(|| "Share link is damaged: missing fields".into())
+    }
+
+    /// Plain text form: language id and file name on their own lines, then the contents.
+    pub fn to_payload(&self) -> String {
+        format!(
+            "{}\n{}\n{}",
+            self.language.id(),
+            self.file_name.as_deref().unwrap_or(""),
+            self.contents
+        )
+    }
+
+    pub fn from_payload(payload: &str) -> Option<Snippet> {
+        let mut parts = payload.splitn(3, '\n');
+        let language = parts.next().and_then(Language::from_id)?;
+        let file_name = parts.next().filter(|name| !name.is_empty());
+        let contents = parts.next()?;
+        Some(Snippet {
+            file_name: file_name.map(String::from),
+            language,
+            contents: contents.to_string(),
+        })
+    }
+
+    pub fn is_share_fragment(fragment: &str) -> bool {
+        fragment
+            .trim_start_matches('#')
+            .starts_with(FRAGMENT_PREFIX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippet_survives_a_round_trip() {
+        let snippet = Snippet {
+            file_name: Some("main.rs".to_string()),
+            language: Language::Rust,
+            contents: "fn main() {\n    println!(\"héllo 🦀\");\n}\n".to_string(),
+        };
+        let url = snippet.to_url(DEFAULT_BASE_URL);
+        let fragment = &url[url.find('#').unwrap()..];
+        assert!(Snippet::is_share_fragment(fragment));
+        let decoded = Snippet::decode(fragment).unwrap();
+        assert_eq!(decoded.file_name.as_deref(), Some("main.rs"));
+        assert_eq!(decoded.language, Language::Rust);
+        assert_eq!(decoded.contents, snippet.contents);
+        assert!(Snippet::decode("#share=not-base64!").is_err());
+
+        let huge = Snippet {
+            contents: " ".repeat(MAX_SNIPPET_LEN + 1),
+            ..snippet
+        };
+        assert!(huge.encode().len() < LONG_LINK_LEN);
+        assert!(Snippet::decode(&format!("share={}", huge.encode())).is_err());
+    }
+}
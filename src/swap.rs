@@ -0,0 +1,254 @@
+//! Crash recovery. Unsaved buffers are written every so often to a swap file,
+//! `.<name>.code_share.swp` next to the file (not vim's `.<name>.swp`) or in
+//! the app data dir for untitled tabs. Swap files are removed once the buffer
+//! is saved or closed, or the editor quits, but not on a panic, so one found
+//! for a file being opened, or for untitled tabs at startup, belongs to an
+//! editor that didn't get to clean up, unless that editor is still running.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const HEADER: &str = "code_share swap";
+
+/// Where swap files for untitled tabs go.
+fn untitled_dir() -> Option<PathBuf> {
+    let data_dir = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))?;
+    Some(data_dir.join("code_share").join("swap"))
+}
+
+fn swap_path_for(file_path: &Path) -> Option<PathBuf> {
+    let name = file_path.file_name()?.to_string_lossy();
+    Some(file_path.with_file_name(format!(".{}.code_share.swp", name)))
+}
+
+/// Whether the editor that wrote a swap file is still running, or None if
+/// there's no telling.
+fn process_alive(pid: u32) -> Option<bool> {
+    let proc_dir = Path::new("/proc");
+    if proc_dir.join("self").exists() {
+        return Some(proc_dir.join(pid.to_string()).exists());
+    }
+    if cfg!(windows) {
+        let filter = format!("PID eq {}", pid);
+        let output = Command::new("tasklist")
+            .args(["/NH", "/FO", "CSV", "/FI", &filter])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        //  One quoted row per process found, e.g. "code_share.exe","1234",...
+        let listed = format!("\"{}\"", pid);
+        Some(String::from_utf8_lossy(&output.stdout).contains(&listed))
+    } else {
+        //  Signal 0 only checks that the process is there
+        let status = Command::new("kill")
+            .args(["-0", &pid.to_string()])
+            .stderr(Stdio::null())
+            .status()
+            .ok()?;
+        Some(status.success())
+    }
+}
+
+fn hash_text(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The swap file kept for one tab.
+pub struct Swap {
+    untitled_name: String,
+    /// Where the buffer was last written, so it can be cleaned up.
+    path: Option<PathBuf>,
+    written_hash: Option<u64>,
+    /// Input time of the last write.
+    pub last_write: f64,
+}
+
+impl Swap {
+    /// `doc_id` tells apart the untitled tabs of this run.
+    pub fn new(doc_id: usize) -> Swap {
+        Swap {
+            untitled_name: format!("untitled-{}-{}.swp", std::process::id(), doc_id),
+            path: None,
+            written_hash: None,
+            last_write: 0.0,
+        }
+    }
+
+    /// Write `text` out for the file at `file_path`, unless it was already.
+    pub fn write(&mut self, file_path: Option<&Path>, text: &str, now: f64) -> io::Result<()> {
+        self.last_write = now;
+        let swap_path = match file_path {
+            Some(file_path) => swap_path_for(file_path),
+            None => untitled_dir().map(|dir| dir.join(&self.untitled_name)),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No place for a swap file"))?;
+        let hash = hash_text(text);
+        if self.path.as_ref() == Some(&swap_path) && self.written_hash == Some(hash) {
+            return Ok(());
+        }
+        //  Saved under a new name since the last write
+        self.remove();
+        if let Some(dir) = swap_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file_line = file_path
+            .map(|path| path.to_string_lossy())
+            .unwrap_or_default();
+        let contents = format!("{} {}\n{}\n{}", HEADER, std::process::id(), file_line, text);
+        fs::write(&swap_path, contents)?;
+        self.path = Some(swap_path);
+        self.written_hash = Some(hash);
+        Ok(())
+    }
+
+    /// Delete the swap file, the buffer is saved or gone.
+    pub fn remove(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = fs::remove_file(path);
+        }
+        self.written_hash = None;
+    }
+}
+
+/// A swap file left behind by an editor that crashed or was killed.
+#[derive(Debug, PartialEq)]
+pub struct Orphan {
+    pub swap_path: PathBuf,
+    /// The file the buffer belongs to, None for an untitled tab.
+    pub file_path: Option<PathBuf>,
+    pub contents: String,
+    /// Whether the editor that wrote it could still be running, when that
+    /// couldn't be checked. Such swap files are never deleted.
+    pub maybe_in_use: bool,
+}
+
+impl Orphan {
+    fn read(swap_path: &Path) -> Option<Orphan> {
+        let swap = fs::read_to_string(swap_path).ok()?;
+        let mut parts = swap.splitn(3, '\n');
+        let pid = parts.next()?.strip_prefix(HEADER)?.trim().parse().ok()?;
+        let file_line = parts.next()?;
+        let contents = parts.next()?.to_string();
+        let maybe_in_use = match process_alive(pid) {
+            _ if pid == std::process::id() => return None,
+            Some(true) => return None,
+            Some(false) => false,
+            None => true,
+        };
+        let file_path = match file_line.is_empty() {
+            true => None,
+            false => Some(PathBuf::from(file_line)),
+        };
+        Some(Orphan {
+            swap_path: swap_path.to_path_buf(),
+            file_path,
+            contents,
+            maybe_in_use,
+        })
+    }
+
+    /// The file's contents on disk, empty if it has none.
+    pub fn disk_contents(&self) -> String {
+        match &self.file_path {
+            Some(path) => fs::read_to_string(path).unwrap_or_default(),
+            None => String::new(),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match &self.file_path {
+            Some(path) => path.display().to_string(),
+            None => "an untitled tab".to_string(),
+        }
+    }
+
+    /// Delete the swap file, unless its editor may still be using it.
+    pub fn discard(&self) {
+        if !self.maybe_in_use {
+            let _ = fs::remove_file(&self.swap_path);
+        }
+    }
+}
+
+/// Left over swap file for the file at `file_path`, if there is one.
+/// One holding nothing that isn't on disk already is cleaned up.
+pub fn orphan_of(file_path: &Path) -> Option<Orphan> {
+    let orphan = Orphan::read(&swap_path_for(file_path)?)?;
+    if orphan.contents == orphan.disk_contents() {
+        orphan.discard();
+        return None;
+    }
+    Some(orphan)
+}
+
+/// Left over swap files of untitled tabs.
+pub fn untitled_orphans() -> Vec<Orphan> {
+    let mut swap_paths: Vec<PathBuf> = match untitled_dir().map(fs::read_dir) {
+        Some(Ok(entries)) => entries.flatten().map(|entry| entry.path()).collect(),
+        _ => Vec::new(),
+    };
+    swap_paths.sort();
+    swap_paths
+        .iter()
+        .filter_map(|path| Orphan::read(path))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn swap_files_come_and_go() {
+        let dir = TestDir::new("swap");
+        let file_path = dir.join("notes.txt");
+        fs::write(&file_path, "saved\n").unwrap();
+        //  Another editor's swap file, which must be left alone
+        let vim_swap = dir.join(".notes.txt.swp");
+        fs::write(&vim_swap, "b0VIM 9.0").unwrap();
+
+        let mut swap = Swap::new(0);
+        swap.write(Some(&file_path), "saved\nunsaved\n", 1.0)
+            .unwrap();
+        let swap_path = dir.join(".notes.txt.code_share.swp");
+        assert!(swap_path.exists());
+        //  Written by this process, so not an orphan
+        assert_eq!(orphan_of(&file_path), None);
+        swap.remove();
+        assert!(!swap_path.exists());
+        //  Kept when dropped, as happens on a panic
+        swap.write(Some(&file_path), "saved\nunsaved\n", 2.0)
+            .unwrap();
+        drop(swap);
+        assert!(swap_path.exists());
+
+        //  As if left behind by an editor that has since died
+        let dead_pid = u32::MAX;
+        let swap = format!(
+            "{} {}\n{}\nsaved\nunsaved\n",
+            HEADER,
+            dead_pid,
+            file_path.display()
+        );
+        fs::write(&swap_path, swap).unwrap();
+        let orphan = orphan_of(&file_path).unwrap();
+        assert_eq!(orphan.contents, "saved\nunsaved\n");
+        assert!(!orphan.maybe_in_use);
+        orphan.discard();
+        assert!(!swap_path.exists());
+        assert_eq!(fs::read_to_string(&vim_swap).unwrap(), "b0VIM 9.0");
+        assert_eq!(process_alive(std::process::id()), Some(true));
+    }
+}
@@ -0,0 +1,40 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A fresh directory for one test, removed again when dropped,
+/// also when the test fails.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    /// `name` only makes the directory easier to spot, it needn't be unique.
+    pub fn new(name: &str) -> TestDir {
+        let path = std::env::temp_dir().join(format!(
+            "code_share-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TestDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Path of `file_name` inside the directory.
+    pub fn join(&self, file_name: &str) -> PathBuf {
+        self.path.join(file_name)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
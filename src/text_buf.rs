@@ -0,0 +1,322 @@
+use crate::history::{Edit, History};
+use crate::rope::Rope;
+use crate::text_pos::TextPos;
+use eframe::egui;
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+//  Unique across buffers, so a buffer swapped for another never looks unchanged
+static NEXT_REVISION: AtomicU64 = AtomicU64::new(1);
+
+/// Number of edits a buffer remembers for `TextBuf::edits_since`.
+const EDIT_LOG_LEN: usize = 1024;
+
+/// The text of a tab, kept in a rope so edits and looking up lines and
+/// positions take O(log n) however long the text is. Only the lines the
+/// editor lays out are copied into a string, see `EditorView`.
+/// The latest edits are logged, so the undo history and a live session
+/// take them in as they were made instead of comparing copies of the text.
+#[derive(Clone, Debug, Default)]
+pub struct TextBuf {
+    rope: Rope,
+    revision: u64,
+    /// Each edit with the revision it was made on, oldest first.
+    edits: VecDeque<(u64, Edit)>,
+}
+
+impl From<String> for TextBuf {
+    fn from(text: String) -> Self {
+        TextBuf {
+            rope: Rope::from(text.as_str()),
+            revision: NEXT_REVISION.fetch_add(1, Ordering::Relaxed),
+            edits: VecDeque::new(),
+        }
+    }
+}
+
+impl fmt::Display for TextBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.rope.fmt(f)
+    }
+}
+
+impl TextBuf {
+    pub fn len(&self) -> usize {
+        self.rope.len_bytes()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The bytes in `range`, which must be on char boundaries.
+    pub fn slice(&self, range: Range<usize>) -> String {
+        self.rope.slice(range)
+    }
+
+    /// Replace the whole text, e.g. with a file's contents, as one edit
+    /// of the part that differs.
+    pub fn set(&mut self, text: String) {
+        if let Some(edit) = Edit::diff(&self.to_string(), &text) {
+            self.replace_range(edit.start..edit.start + edit.removed.len(), &edit.inserted);
+        }
+    }
+
+    /// Replace the bytes in `range`, which must be on char boundaries.
+    pub fn replace_range(&mut self, range: Range<usize>, with: &str) {
+        if range.is_empty() && with.is_empty() {
+            return;
+        }
+        let removed = self.rope.slice(range.clone());
+        self.rope.remove(range.clone());
+        self.rope.insert(range.start, with);
+        let edit = Edit {
+            start: range.start,
+            removed,
+            inserted: with.to_string(),
+        };
+        self.edits.push_back((self.revision, edit));
+        if self.edits.len() > EDIT_LOG_LEN {
+            self.edits.pop_front();
+        }
+        self.revision = NEXT_REVISION.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Changes on every edit, see `edits_since`.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// The edits made since `revision`, in order, or None if they are no
+    /// longer all logged, or `revision` isn't one of this buffer's.
+    pub fn edits_since(&self, revision: u64) -> Option<Vec<Edit>> {
+        if revision == self.revision {
+            return Some(Vec::new());
+        }
+        let first = self
+            .edits
+            .binary_search_by_key(&revision, |(made_on, _)| *made_on)
+            .ok()?;
+        Some(
+            self.edits
+                .range(first..)
+                .map(|(_, edit)| edit.clone())
+                .collect(),
+        )
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.rope.len_chars()
+    }
+
+    pub fn char_to_byte(&self, char: usize) -> usize {
+        self.rope.char_to_byte(char)
+    }
+
+    pub fn byte_to_char(&self, byte: usize) -> usize {
+        self.rope.byte_to_char(byte)
+    }
+
+    pub fn push_str(&mut self, text: &str) {
+        let end = self.len();
+        self.replace_range(end..end, text);
+    }
+
+    /// Number of lines, counted like `str::lines` does.
+    pub fn line_count(&self) -> usize {
+        let newlines = self.rope.len_newlines();
+        match self.is_empty() || self.rope.line_to_byte(newlines) == self.len() {
+            true => newlines,
+            false => newlines + 1,
+        }
+    }
+
+    /// 0-based `line` without its line break.
+    pub fn line(&self, line: usize) -> String {
+        self.rope.slice(self.line_range(line..line + 1))
+    }
+
+    /// Bytes of the 0-based `lines`, without the line break after the last.
+    pub fn line_range(&self, lines: Range<usize>) -> Range<usize> {
+        let start = self.rope.line_to_byte(lines.start);
+        let end = match lines.end <= self.rope.len_newlines() {
+            true => self.rope.line_to_byte(lines.end) - 1,
+            false => self.len(),
+        };
+        start..end
+    }
+
+    /// Same as `TextPos::from_byte`, without scanning the text.
+    pub fn pos_at_byte(&self, byte: usize) -> TextPos {
+        let byte = self.rope.floor_char_boundary(byte);
+        let char = self.rope.byte_to_char(byte);
+        let line = self.rope.byte_to_line(byte);
+        let line_start = self.rope.byte_to_char(self.rope.line_to_byte(line));
+        TextPos {
+            byte,
+            char,
+            line,
+            column: char - line_start,
+        }
+    }
+
+    /// Same as `TextPos::from_char`, without scanning the text.
+    pub fn pos_at_char(&self, char: usize) -> TextPos {
+        self.pos_at_byte(self.rope.char_to_byte(char))
+    }
+
+    /// Same as `TextPos::from_line_col`, only scanning the one line.
+    pub fn pos_at_line_col(&self, line: usize, column: usize) -> TextPos {
+        if line > self.rope.len_newlines() {
+            return self.pos_at_byte(self.len());
+        }
+        let start = self.rope.line_to_byte(line);
+        let line_text = self.line(line);
+        let offset = line_text
+            .char_indices()
+            .nth(column)
+            .map_or(line_text.len(), |(byte, _)| byte);
+        self.pos_at_byte(start + offset)
+    }
+}
+
+/// The lines of a `TextBuf` the editor lays out, copied out of the rope for
+/// egui's `TextEdit` to edit. Char indices count from the start of the lines,
+/// and edits go through to the whole buffer, and to the undo history if one
+/// is given. The Tab key inserts `tab` instead of a tab character.
+pub struct EditorView<'a> {
+    text_buf: &'a mut TextBuf,
+    history: Option<(&'a mut History, f64)>,
+    /// Bytes shown, moved along as they are edited.
+    range: Range<usize>,
+    shown: String,
+    tab: &'a str,
+}
+
+impl<'a> EditorView<'a> {
+    pub fn new(text_buf: &'a mut TextBuf, range: Range<usize>, tab: &'a str) -> Self {
+        EditorView {
+            shown: text_buf.slice(range.clone()),
+            text_buf,
+            history: None,
+            range,
+            tab,
+        }
+    }
+
+    /// Record edits in `history` as typing at `time` as they are made.
+    pub fn recording(mut self, history: &'a mut History, time: f64) -> Self {
+        self.history = Some((history, time));
+        self
+    }
+
+    fn byte_at(&self, char_index: usize) -> usize {
+        let first_char = self.text_buf.byte_to_char(self.range.start);
+        self.text_buf.char_to_byte(first_char + char_index)
+    }
+
+    fn replace_range(&mut self, range: Range<usize>, with: &str) {
+        self.text_buf.replace_range(range.clone(), with);
+        self.shown.replace_range(
+            range.start - self.range.start..range.end - self.range.start,
+            with,
+        );
+        self.range.end = self.range.end + with.len() - range.len();
+        if let Some((history, time)) = self.history.as_mut() {
+            history.record(self.text_buf, *time);
+        }
+    }
+}
+
+impl AsRef<str> for EditorView<'_> {
+    fn as_ref(&self) -> &str {
+        &self.shown
+    }
+}
+
+impl egui::TextBuffer for EditorView<'_> {
+    fn is_mutable(&self) -> bool {
+        true
+    }
+
+    fn insert_text(&mut self, text: &str, char_index: usize) -> usize {
+        let text = match text {
+            "\t" => self.tab,
+            _ => text,
+        };
+        let byte = self.byte_at(char_index);
+        self.replace_range(byte..byte, text);
+        text.chars().count()
+    }
+
+    fn delete_char_range(&mut self, char_range: Range<usize>) {
+        let start = self.byte_at(char_range.start);
+        let end = self.byte_at(char_range.end);
+        self.replace_range(start..end, "");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "héllo\nwörld 🦀\n\nend";
+
+    #[test]
+    fn positions_match_scanning() {
+        let mut buf = TextBuf::from(TEXT.to_string());
+        for byte in 0..=TEXT.len() + 1 {
+            assert_eq!(buf.pos_at_byte(byte), TextPos::from_byte(TEXT, byte));
+        }
+        for char in 0..=TEXT.chars().count() {
+            assert_eq!(buf.pos_at_char(char), TextPos::from_char(TEXT, char));
+        }
+        for (line, column) in [(0, 3), (1, 100), (2, 5), (3, 1), (99, 0)].iter() {
+            assert_eq!(
+                buf.pos_at_line_col(*line, *column),
+                TextPos::from_line_col(TEXT, *line, *column)
+            );
+        }
+        assert_eq!(buf.line_count(), TEXT.lines().count());
+        buf.push_str("\n");
+        assert_eq!(buf.line_count(), 4);
+        assert_eq!(buf.line(1), "wörld 🦀");
+        assert_eq!(buf.slice(1..8), "éllo\nw");
+    }
+
+    #[test]
+    fn edits_are_logged() {
+        let mut buf = TextBuf::from(TEXT.to_string());
+        let start = buf.revision();
+        buf.replace_range(0..1, "H");
+        buf.set("Héllo\nend".to_string());
+        let edits = buf.edits_since(start).unwrap();
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[1].removed, "wörld 🦀\n\n");
+        assert_eq!(buf.edits_since(buf.revision()), Some(Vec::new()));
+        //  Revisions of another buffer, or edits too long ago, aren't known
+        assert_eq!(buf.edits_since(TextBuf::default().revision()), None);
+        for _ in 0..EDIT_LOG_LEN {
+            buf.push_str("x");
+        }
+        assert_eq!(buf.edits_since(start), None);
+    }
+
+    #[test]
+    fn view_edits_reach_the_buffer() {
+        let mut buf = TextBuf::from(TEXT.to_string());
+        let mut history = History::default();
+        history.reset(&buf);
+        let start = buf.pos_at_line_col(1, 0).byte;
+        let end = buf.pos_at_line_col(2, 0).byte - 1;
+        let mut view = EditorView::new(&mut buf, start..end, "  ").recording(&mut history, 0.0);
+        egui::TextBuffer::insert_text(&mut view, "\t", 6);
+        egui::TextBuffer::delete_char_range(&mut view, 0..1);
+        assert_eq!(view.as_ref(), "örld   🦀");
+        assert_eq!(buf.to_string(), "héllo\nörld   🦀\n\nend");
+        history.undo(&mut buf);
+        assert_eq!(buf.to_string(), TEXT);
+    }
+}
@@ -0,0 +1,125 @@
+/// A location in a buffer, in every unit the editor deals with.
+/// `byte` indexes the `String` (search, `replace_range`), `char` is what
+/// egui's `CCursor` counts, and `line`/`column` (0-based, column in chars)
+/// is what gets shown to the user.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TextPos {
+    pub byte: usize,
+    pub char: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl TextPos {
+    /// Position of byte offset `byte`, snapped back to a char boundary.
+    pub fn from_byte(text: &str, byte: usize) -> TextPos {
+        let mut byte = byte.min(text.len());
+        while !text.is_char_boundary(byte) {
+            byte -= 1;
+        }
+        TextPos::scan(text, |pos, _| pos.byte >= byte)
+    }
+
+    /// Position of the `char`-th character, clamped to the end of the text.
+    pub fn from_char(text: &str, char: usize) -> TextPos {
+        TextPos::scan(text, |pos, _| pos.char >= char)
+    }
+
+    /// Position of `column` on `line`, clamped to the end of that line
+    /// (or of the text, if there are fewer lines).
+    pub fn from_line_col(text: &str, line: usize, column: usize) -> TextPos {
+        TextPos::scan(text, |pos, next| {
+            pos.line > line || (pos.line == line && (pos.column >= column || next == Some('\n')))
+        })
+    }
+
+    /// Parse `line` or `line:column` as typed into Go to Line, both 1-based.
+    /// Returns the 0-based line and column.
+    pub fn parse_line_col(input: &str) -> Option<(usize, usize)> {
+        let mut parts = input.trim().splitn(2, ':');
+        let line: usize = parts.next()?.trim().parse().ok()?;
+        let column: usize = match parts.next() {
+            Some(column) => column.trim().parse().ok()?,
+            None => 1,
+        };
+        Some((line.saturating_sub(1), column.saturating_sub(1)))
+    }
+
+    /// Walk the text from the start until `stop` says we've arrived.
+    /// `stop` also sees the character at the current position.
+    fn scan(text: &str, stop: impl Fn(&TextPos, Option<char>) -> bool) -> TextPos {
+        let mut pos = TextPos::default();
+        for ch in text.chars() {
+            if stop(&pos, Some(ch)) {
+                return pos;
+            }
+            pos.byte += ch.len_utf8();
+            pos.char += 1;
+            if ch == '\n' {
+                pos.line += 1;
+                pos.column = 0;
+            } else {
+                pos.column += 1;
+            }
+        }
+        pos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "héllo\nwörld 🦀\n\nend";
+
+    #[test]
+    fn byte_to_char_and_line() {
+        let pos = TextPos::from_byte(TEXT, 8);
+        assert_eq!(&TEXT[pos.byte..pos.byte + 2], "ö");
+        assert_eq!((pos.char, pos.line, pos.column), (7, 1, 1));
+    }
+
+    #[test]
+    fn byte_inside_char_snaps_back() {
+        // Byte 2 is the middle of 'é'
+        assert_eq!(TextPos::from_byte(TEXT, 2), TextPos::from_byte(TEXT, 1));
+        let crab = TEXT.find('🦀').unwrap();
+        assert_eq!(TextPos::from_byte(TEXT, crab + 3).byte, crab);
+    }
+
+    #[test]
+    fn char_round_trips_through_byte() {
+        for char in 0..=TEXT.chars().count() {
+            let pos = TextPos::from_char(TEXT, char);
+            assert_eq!(pos.char, char);
+            assert_eq!(TextPos::from_byte(TEXT, pos.byte), pos);
+        }
+    }
+
+    #[test]
+    fn line_col_clamps_to_line_end() {
+        let pos = TextPos::from_line_col(TEXT, 1, 100);
+        assert_eq!(&TEXT[pos.byte..pos.byte + 1], "\n");
+        assert_eq!((pos.line, pos.column), (1, 7));
+        let empty_line = TextPos::from_line_col(TEXT, 2, 5);
+        assert_eq!((empty_line.line, empty_line.column), (2, 0));
+    }
+
+    #[test]
+    fn go_to_line_input() {
+        assert_eq!(TextPos::parse_line_col("12"), Some((11, 0)));
+        assert_eq!(TextPos::parse_line_col(" 3:7 "), Some((2, 6)));
+        assert_eq!(TextPos::parse_line_col("0"), Some((0, 0)));
+        assert_eq!(TextPos::parse_line_col("x:1"), None);
+        assert_eq!(TextPos::parse_line_col("4:"), None);
+    }
+
+    #[test]
+    fn past_the_end_clamps_to_text_end() {
+        let end = TextPos::from_char(TEXT, usize::MAX);
+        assert_eq!(end.byte, TEXT.len());
+        assert_eq!((end.line, end.column), (3, 3));
+        assert_eq!(TextPos::from_byte(TEXT, usize::MAX), end);
+        assert_eq!(TextPos::from_line_col(TEXT, 99, 0), end);
+    }
+}
@@ -0,0 +1,317 @@
+//! Colors for the editor, its gutter, the info bar and syntax highlighting.
+//! Besides the built-in themes, a theme can be loaded from a TOML file:
+//!
+//! ```toml
+//! name = "Dusk"
+//! base = "dark"  # or "light", or "high-contrast"
+//!
+//! [editor]
+//! background = "#0e0f17"
+//! selection = "#264f78"
+//!
+//! [gutter]
+//! background = "#0e0f17"
+//! text = "#5c6370"
+//!
+//! [info_bar]
+//! background = "#e8bc44"
+//! text = "#000000"
+//!
+//! [syntax]
+//! plain = "#ffffff"
+//! comment = "#6a737d"
+//! string = "#98c379"
+//! number = "#d19a66"
+//! keyword = "#c678dd"
+//! literal = "#56b6c2"
+//! punctuation = "#abb2bf"
+//! heading = "#e5c07b"
+//! key = "#61afef"
+//! ```
+//!
+//! Every key is optional, anything left out comes from the base theme.
+
+use eframe::egui;
+use eframe::egui::Color32;
+use std::path::{Path, PathBuf};
+
+/// Colors of the highlighted token kinds, see `highlight::TokenKind`.
+#[derive(Clone, Copy, Debug, PartialEq, Hash)]
+pub struct SyntaxColors {
+    pub plain: Color32,
+    pub comment: Color32,
+    pub string: Color32,
+    pub number: Color32,
+    pub keyword: Color32,
+    pub literal: Color32,
+    pub punctuation: Color32,
+    pub heading: Color32,
+    pub key: Color32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Theme {
+    pub name: String,
+    /// Whether egui's own widgets use their dark or light look.
+    pub dark: bool,
+    pub editor_background: Color32,
+    pub selection: Color32,
+    pub gutter_background: Color32,
+    pub gutter_text: Color32,
+    pub info_bar_background: Color32,
+    pub info_bar_text: Color32,
+    pub syntax: SyntaxColors,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::dark()
+    }
+}
+
+impl Theme {
+    pub fn dark() -> Theme {
+        Theme {
+            name: "Dark".to_string(),
+            dark: true,
+            editor_background: Color32::from_rgb(14, 15, 23),
+            selection: Color32::from_rgb(38, 79, 120),
+            gutter_background: Color32::from_rgb(14, 15, 23),
+            gutter_text: Color32::WHITE,
+            info_bar_background: Color32::from_rgb(232, 188, 68),
+            info_bar_text: Color32::BLACK,
+            syntax: SyntaxColors {
+                plain: Color32::WHITE,
+                comment: Color32::from_rgb(106, 115, 125),
+                string: Color32::from_rgb(152, 195, 121),
+                number: Color32::from_rgb(209, 154, 102),
+                keyword: Color32::from_rgb(198, 120, 221),
+                literal: Color32::from_rgb(86, 182, 194),
+                punctuation: Color32::from_rgb(171, 178, 191),
+                heading: Color32::from_rgb(229, 192, 123),
+                key: Color32::from_rgb(97, 175, 239),
+            },
+        }
+    }
+
+    pub fn light() -> Theme {
+        Theme {
+            name: "Light".to_string(),
+            dark: false,
+            editor_background: Color32::from_rgb(250, 250, 250),
+            selection: Color32::from_rgb(173, 214, 255),
+            gutter_background: Color32::from_rgb(240, 240, 240),
+            gutter_text: Color32::from_rgb(140, 140, 140),
+            info_bar_background: Color32::from_rgb(232, 188, 68),
+            info_bar_text: Color32::BLACK,
+            syntax: SyntaxColors {
+                plain: Color32::from_rgb(36, 41, 46),
+                comment: Color32::from_rgb(106, 115, 125),
+                string: Color32::from_rgb(3, 47, 98),
+                number: Color32::from_rgb(0, 92, 197),
+                keyword: Color32::from_rgb(215, 58, 73),
+                literal: Color32::from_rgb(0, 92, 197),
+                punctuation: Color32::from_rgb(88, 96, 105),
+                heading: Color32::from_rgb(111, 66, 193),
+                key: Color32::from_rgb(0, 92, 197),
+            },
+        }
+    }
+
+    pub fn high_contrast() -> Theme {
+        Theme {
+            name: "High Contrast".to_string(),
+            dark: true,
+            editor_background: Color32::BLACK,
+            selection: Color32::from_rgb(0, 90, 200),
+            gutter_background: Color32::BLACK,
+            gutter_text: Color32::YELLOW,
+            info_bar_background: Color32::WHITE,
+            info_bar_text: Color32::BLACK,
+            syntax: SyntaxColors {
+                plain: Color32::WHITE,
+                comment: Color32::from_rgb(0, 255, 0),
+                string: Color32::from_rgb(255, 160, 0),
+                number: Color32::from_rgb(0, 255, 255),
+                keyword: Color32::from_rgb(255, 255, 0),
+                literal: Color32::from_rgb(0, 255, 255),
+                punctuation: Color32::WHITE,
+                heading: Color32::from_rgb(255, 255, 0),
+                key: Color32::from_rgb(0, 255, 255),
+            },
+        }
+    }
+
+    /// Read a theme file, see the module docs for the format.
+    pub fn from_file(path: &Path) -> Result<Theme, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut theme = Theme::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if theme.name.is_empty() {
+            theme.name = ThemeChoice::File(path.to_path_buf()).name();
+        }
+        Ok(theme)
+    }
+
+    /// A theme from the TOML text of a theme file. Unknown keys are errors,
+    /// so a typo doesn't silently leave a color unchanged.
+    pub fn parse(text: &str) -> Result<Theme, String> {
+        let table: toml::Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| e.message().to_string())?;
+        let mut theme = match table.get("base").map(|base| base.as_str()) {
+            None | Some(Some("dark")) => Theme::dark(),
+            Some(Some("light")) => Theme::light(),
+            Some(Some("high-contrast")) => Theme::high_contrast(),
+            Some(_) => {
+                return Err("base should be \"dark\", \"light\" or \"high-contrast\"".to_string())
+            }
+        };
+        theme.name = String::new();
+        for (key, value) in table.iter() {
+            match (key.as_str(), value) {
+                ("name", toml::Value::String(name)) => theme.name = name.clone(),
+                ("base", _) => {}
+                (section, toml::Value::Table(colors)) => {
+                    for (color_key, color) in colors.iter() {
+                        let slot = theme
+                            .color_slot(section, color_key)
+                            .ok_or_else(|| format!("unknown color {}.{}", section, color_key))?;
+                        *slot = color.as_str().and_then(parse_color).ok_or_else(|| {
+                            format!(
+                                "{}.{} should be a color like \"#1e1e2e\"",
+                                section, color_key
+                            )
+                        })?;
+                    }
+                }
+                (key, _) => return Err(format!("unknown setting {}", key)),
+            }
+        }
+        Ok(theme)
+    }
+
+    fn color_slot(&mut self, section: &str, key: &str) -> Option<&mut Color32> {
+        let syntax = &mut self.syntax;
+        Some(match (section, key) {
+            ("editor", "background") => &mut self.editor_background,
+            ("editor", "selection") => &mut self.selection,
+            ("gutter", "background") => &mut self.gutter_background,
+            ("gutter", "text") => &mut self.gutter_text,
+            ("info_bar", "background") => &mut self.info_bar_background,
+            ("info_bar", "text") => &mut self.info_bar_text,
+            ("syntax", "plain") => &mut syntax.plain,
+            ("syntax", "comment") => &mut syntax.comment,
+            ("syntax", "string") => &mut syntax.string,
+            ("syntax", "number") => &mut syntax.number,
+            ("syntax", "keyword") => &mut syntax.keyword,
+            ("syntax", "literal") => &mut syntax.literal,
+            ("syntax", "punctuation") => &mut syntax.punctuation,
+            ("syntax", "heading") => &mut syntax.heading,
+            ("syntax", "key") => &mut syntax.key,
+            _ => return None,
+        })
+    }
+
+    /// Set egui's own widgets to match.
+    pub fn apply(&self, ctx: &egui::CtxRef) {
+        let mut visuals = match self.dark {
+            true => egui::Visuals::dark(),
+            false => egui::Visuals::light(),
+        };
+        visuals.selection.bg_fill = self.selection;
+        ctx.set_visuals(visuals);
+    }
+}
+
+/// `#rrggbb` or `#rrggbbaa`.
+fn parse_color(hex: &str) -> Option<Color32> {
+    let hex = hex.strip_prefix('#')?;
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    let alpha = match hex.len() {
+        8 => channel(6)?,
+        _ => 255,
+    };
+    Some(Color32::from_rgba_unmultiplied(
+        channel(0)?,
+        channel(2)?,
+        channel(4)?,
+        alpha,
+    ))
+}
+
+/// The theme picked in the View menu.
+#[cfg_attr(feature = "persistence", derive(serde::Deserialize, serde::Serialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ThemeChoice {
+    #[default]
+    Dark,
+    Light,
+    HighContrast,
+    /// A theme file, see the module docs.
+    File(PathBuf),
+}
+
+impl ThemeChoice {
+    pub const BUILT_IN: [ThemeChoice; 3] = [
+        ThemeChoice::Dark,
+        ThemeChoice::Light,
+        ThemeChoice::HighContrast,
+    ];
+
+    pub fn name(&self) -> String {
+        match self {
+            ThemeChoice::Dark => "Dark".to_string(),
+            ThemeChoice::Light => "Light".to_string(),
+            ThemeChoice::HighContrast => "High Contrast".to_string(),
+            ThemeChoice::File(path) => path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn load(&self) -> Result<Theme, String> {
+        match self {
+            ThemeChoice::Dark => Ok(Theme::dark()),
+            ThemeChoice::Light => Ok(Theme::light()),
+            ThemeChoice::HighContrast => Ok(Theme::high_contrast()),
+            ThemeChoice::File(path) => Theme::from_file(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn theme_files_override_their_base() {
+        let theme = Theme::parse(
+            r##"
+            name = "Dusk"
+            base = "light"
+            [editor]
+            background = "#102030"
+            [syntax]
+            keyword = "#ff000080"
+            "##,
+        )
+        .unwrap();
+        assert_eq!(theme.name, "Dusk");
+        assert!(!theme.dark);
+        assert_eq!(theme.editor_background, Color32::from_rgb(16, 32, 48));
+        assert_eq!(
+            theme.syntax.keyword,
+            Color32::from_rgba_unmultiplied(255, 0, 0, 128)
+        );
+        assert_eq!(theme.syntax.string, Theme::light().syntax.string);
+
+        assert!(Theme::parse("[editor]\nbackgrond = \"#000000\"").is_err());
+        assert!(Theme::parse("[gutter]\ntext = \"white\"").is_err());
+        assert!(Theme::parse("base = \"sepia\"").is_err());
+    }
+}